@@ -0,0 +1,101 @@
+//! Compares the memory held by an in-memory string table with a [`StreamingStringTable`] for
+//! a 50k-line table, and how long each takes to look up every line.
+//!
+//! Run with `cargo bench --bench string_table`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use bevy_yarn_spinner::{CsvDialect, StreamingStringTable, StringTableSource};
+use yharnam::LineInfo;
+
+const LINES: usize = 50_000;
+
+/// Tracks the bytes currently allocated, so a table's footprint is the difference before and
+/// after building it.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("string_table_bench_{}.csv", std::process::id()));
+    let mut csv = String::from("id,text,file,node,lineNumber\n");
+    for index in 0..LINES {
+        csv.push_str(&format!(
+            "line:{:05},\"Line {} of the table, long enough to look like real dialogue.\",\
+             bench,Node{},{}\n",
+            index,
+            index,
+            index / 100,
+            index
+        ));
+    }
+    fs::write(&path, &csv).unwrap();
+    drop(csv);
+
+    let before = allocated();
+    let in_memory: HashMap<String, LineInfo> = csv::Reader::from_path(&path)
+        .unwrap()
+        .deserialize()
+        .map(|line: Result<LineInfo, _>| line.unwrap())
+        .map(|line| (line.id.clone(), line))
+        .collect();
+    let in_memory_bytes = allocated() - before;
+
+    let before = allocated();
+    let streaming = StreamingStringTable::open(&path, CsvDialect::default()).unwrap();
+    let streaming_bytes = allocated() - before;
+
+    let ids: Vec<String> = (0..LINES).map(|index| format!("line:{:05}", index)).collect();
+
+    let start = Instant::now();
+    for id in &ids {
+        assert!(in_memory.contains_key(id));
+    }
+    let in_memory_time = start.elapsed();
+
+    let start = Instant::now();
+    for id in &ids {
+        assert!(streaming.text(id).is_some());
+    }
+    let streaming_time = start.elapsed();
+
+    println!("{} lines", LINES);
+    println!(
+        "in-memory: {:>8} KiB resident, {:?} to look up every line",
+        in_memory_bytes / 1024,
+        in_memory_time
+    );
+    println!(
+        "streaming: {:>8} KiB resident, {:?} to look up every line",
+        streaming_bytes / 1024,
+        streaming_time
+    );
+
+    drop(streaming);
+    fs::remove_file(&path).unwrap();
+}
@@ -1,429 +1,8445 @@
-use std::{collections::VecDeque, fs, path::PathBuf};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
 use bevy::{
-    asset::{AssetLoader, LoadedAsset},
-    ecs::{schedule::ShouldRun, system::Command},
+    asset::{Asset, AssetLoader, AssetPath, HandleId, LoadState, LoadedAsset},
+    ecs::{
+        event::Events,
+        schedule::ShouldRun,
+        system::{Command, Resource},
+    },
     prelude::*,
-    reflect::TypeUuid,
+    reflect::{FromReflect, TypeUuid},
     utils::HashMap,
 };
 use derive_deref::{Deref, DerefMut};
-use nom::{
-    bytes::complete::{is_not, take_until},
-    character::complete::char,
-    error::Error,
-    sequence::{delimited, pair},
-    Err,
-};
 use prost::Message;
+use serde::{Deserialize, Serialize};
 pub use yharnam::*;
 
+#[cfg(feature = "simple_ui")]
+pub mod simple_ui;
+
 pub struct DialoguePlugin {
-    pub startup_program: PathBuf,
+    /// Program queued ahead of everything else at startup, as a path in the assets folder like
+    /// any queued dialogue. When `None` the runner starts idle and empty until dialogue is
+    /// queued.
+    pub startup_program: Option<PathBuf>,
+    /// Constructs the storage backing Yarn `$variables`. Defaults to [`HashMapVariableStorage`].
+    /// Not called when `variables` is set.
+    pub variable_storage: fn() -> Box<dyn VariableStorage>,
+    /// Keeps loaded programs and tables alive so edits are picked up while the game runs,
+    /// restarting the active node. Requires `AssetServerSettings::watch_for_changes`.
+    pub hot_reload: bool,
+    /// Keeps the assets of dialogue that has played in the [`DialogueProgramCache`], so
+    /// queuing it again starts without reloading.
+    pub cache_programs: bool,
+    /// Existing storage to back `$variables`. Takes precedence over `variable_storage`, which is
+    /// only called when this is `None`.
+    pub variables: Option<SharedVariableStorage>,
+    /// Initial [`DialogueLanguage`]. Defaults to English.
+    pub language: Option<DialogueLanguage>,
+    /// Seeds the runner's [`DialogueRng`] for deterministic `dice`/`random` results.
+    pub rng_seed: Option<u64>,
+    /// Node dialogue starts at when a queue entry doesn't name one.
+    pub default_start_node: String,
+    /// How options whose `<<if>>` condition failed are presented.
+    pub option_policy: OptionPresentationPolicy,
+    /// Whether presented lines advance on their own or wait for [`AdvanceDialogueCommand`].
+    pub line_advance_mode: LineAdvanceMode,
+    /// Checks each loaded program's line IDs against its string table, reporting missing rows
+    /// with [`EventValidationReport`].
+    pub validate_on_load: bool,
+    /// Whether string tables with malformed rows fail to load or skip those rows.
+    pub csv_strictness: CsvStrictness,
+    /// Delimiter and header layout of string tables, both loaded assets and the startup table.
+    pub csv_dialect: CsvDialect,
+    /// Countdown applied to every set of options. See [`DialogueRunner::start_option_timeout`].
+    pub option_timeout: Option<OptionTimeout>,
+    /// Splits one `<<command>>` into several, dispatched in order. Off by default.
+    pub command_separator: Option<char>,
+    /// Whether script assignments are checked against the types variables were declared with.
+    pub variable_type_checking: VariableTypeChecking,
+    /// Lets numbers and bools be assigned to each other's declared type, converting the value.
+    pub coerce_number_bool: bool,
 }
 
-impl Plugin for DialoguePlugin {
-    fn build(&self, app: &mut App) {
-        app.add_asset::<YarnProgram>()
-            .add_asset::<YarnStringTable>()
-            .init_asset_loader::<YarnProgramLoader>()
-            .init_asset_loader::<YarnStringTableLoader>()
-            .init_resource::<DialogueQueue>()
-            .add_event::<EventDialogueUpdated>()
-            .add_system_to_stage(CoreStage::PostUpdate, check_queue)
-            .add_system_to_stage(CoreStage::PreUpdate, update_runner.with_run_criteria(run_if_no_dialogue_hold))
-            .init_resource::<DialogueCommands>();
-
-        let program_bytes = fs::read(self.startup_program.as_path()).unwrap();
-        let program = Program::decode(&*program_bytes).unwrap();
-
-        let mut csv_path = self.startup_program.clone();
-        csv_path.set_extension("csv");
-        let mut csv_reader = csv::Reader::from_path(csv_path).unwrap();
-        let string_table: Vec<LineInfo> = csv_reader
-            .deserialize()
-            .map(|result| result.unwrap())
-            .collect();
-        app.insert_resource(DialogueRunner {
-            vm: VirtualMachine::new(program),
-            table: string_table,
-            state: DialogueRunnerState::Idle,
-        });
+impl DialoguePlugin {
+    /// Starts configuring the plugin. This is the preferred way to construct it.
+    pub fn builder() -> DialoguePluginBuilder {
+        DialoguePluginBuilder::default()
     }
-}
-pub trait RegisterDialogueCommandExt {
-    fn register_dialogue_command<I: Into<String>>(
-        &mut self,
-        name: I,
-        command: fn(&mut World, Vec<String>),
-    ) -> &mut Self;
-}
 
-impl RegisterDialogueCommandExt for World {
-    fn register_dialogue_command<I: Into<String>>(
-        &mut self,
-        name: I,
-        command: fn(&mut World, Vec<String>),
-    ) -> &mut Self {
-        let mut commands = self.get_resource_or_insert_with(|| DialogueCommands::default());
-        commands.insert(name.into(), command);
-        self
+    /// Creates the plugin with a startup program. Only the path is checked here: the program
+    /// and its table are loaded through the [`AssetServer`], so a missing or corrupt file is
+    /// reported by [`EventDialogueLoadFailed`] once loading fails.
+    pub fn try_new<P: Into<PathBuf>>(path: P) -> Result<Self, DialogueLoadError> {
+        let startup_program = path.into();
+        if startup_program.as_os_str().is_empty() {
+            return Err(DialogueLoadError::InvalidConfiguration(
+                "startup program path must not be empty".to_string(),
+            ));
+        }
+        Ok(Self {
+            startup_program: Some(startup_program),
+            ..Default::default()
+        })
     }
 }
 
-impl RegisterDialogueCommandExt for App {
-    fn register_dialogue_command<I: Into<String>>(
-        &mut self,
-        name: I,
-        command: fn(&mut World, Vec<String>),
-    ) -> &mut Self {
-        self.world.register_dialogue_command(name, command);
-        self
+impl Default for DialoguePlugin {
+    fn default() -> Self {
+        Self {
+            startup_program: None,
+            variable_storage: || Box::new(HashMapVariableStorage::default()),
+            hot_reload: false,
+            cache_programs: false,
+            variables: None,
+            language: None,
+            rng_seed: None,
+            default_start_node: "Start".to_string(),
+            option_policy: OptionPresentationPolicy::default(),
+            line_advance_mode: LineAdvanceMode::default(),
+            validate_on_load: false,
+            csv_strictness: CsvStrictness::default(),
+            csv_dialect: CsvDialect::default(),
+            option_timeout: None,
+            command_separator: None,
+            variable_type_checking: VariableTypeChecking::default(),
+            coerce_number_bool: false,
+        }
     }
 }
-// *****************************************************************************************
-// Events
-// *****************************************************************************************
-pub struct EventDialogueUpdated;
-// *****************************************************************************************
-// Resources
-// *****************************************************************************************
-#[derive(Default, Deref, DerefMut)]
-pub struct DialogueQueue {
-    pub queue: VecDeque<DialogueQueueEntry>,
-}
 
-pub struct DialogueQueueEntry {
-    pub path: PathBuf,
-    pub program: Handle<YarnProgram>,
-    pub table: Handle<YarnStringTable>,
-    pub start_node: Option<String>,
+#[derive(Default)]
+pub struct DialoguePluginBuilder {
+    plugin: DialoguePlugin,
 }
 
-pub struct DialogueRunner {
-    pub vm: VirtualMachine,
-    pub table: Vec<LineInfo>,
-    pub state: DialogueRunnerState,
-}
+impl DialoguePluginBuilder {
+    pub fn startup_program<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.plugin.startup_program = Some(path.into());
+        self
+    }
 
-#[derive(Debug, Clone)]
-pub enum DialogueRunnerState {
-    Idle,
-    Running(DialogueRunningCurrentEntry),
-}
+    pub fn language<I: Into<String>>(mut self, language: I) -> Self {
+        let mut dialogue_language = self.plugin.language.take().unwrap_or_default();
+        dialogue_language.current = language.into();
+        self.plugin.language = Some(dialogue_language);
+        self
+    }
 
-#[derive(Debug, Clone)]
-pub enum DialogueRunningCurrentEntry {
-    Null,
-    Text(String),
-    Options(Vec<String>),
-}
+    pub fn hot_reload(mut self, hot_reload: bool) -> Self {
+        self.plugin.hot_reload = hot_reload;
+        self
+    }
 
-impl DialogueRunner {
-    fn setup(&mut self, program: YarnProgram, table: YarnStringTable, start_node: Option<String>) {
-        let start_node = match start_node {
-            Some(s) => s.clone(),
-            None => "Start".to_string(),
-        };
-        self.vm.program = program.0;
-        self.table = table.0;
-        //println!("Nodes: {:?}", vm.program.nodes);
-        if self.vm.program.nodes.contains_key(&start_node) {
-            // Set the start node.
-            //println!("Start node set!");
-            self.vm.set_node(&start_node);
-        }
-        self.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null);
+    pub fn cache_programs(mut self, cache_programs: bool) -> Self {
+        self.plugin.cache_programs = cache_programs;
+        self
     }
-}
 
-impl PartialEq for DialogueRunnerState {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (DialogueRunnerState::Idle, DialogueRunnerState::Idle) => true,
-            (DialogueRunnerState::Idle, DialogueRunnerState::Running { .. }) => false,
-            (DialogueRunnerState::Running { .. }, DialogueRunnerState::Idle) => false,
-            (DialogueRunnerState::Running { .. }, DialogueRunnerState::Running { .. }) => true,
-        }
+    /// Backs `$variables` with `storage`. This sets [`DialoguePlugin::variables`], so it
+    /// overrides the plugin's `variable_storage` constructor.
+    pub fn variable_storage(mut self, storage: Box<dyn VariableStorage>) -> Self {
+        self.plugin.variables = Some(SharedVariableStorage::new(storage));
+        self
     }
-}
 
-#[derive(Deref, DerefMut, Default)]
-pub struct DialogueCommands(HashMap<String, fn(&mut World, Vec<String>)>);
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.plugin.rng_seed = Some(seed);
+        self
+    }
 
-pub struct DialogueHold;
-// *****************************************************************************************
-// Systems
-// *****************************************************************************************
-fn check_queue(
-    mut queue: ResMut<DialogueQueue>,
-    mut runner: ResMut<DialogueRunner>,
-    mut yarn_programs: ResMut<Assets<YarnProgram>>,
-    mut yarn_tables: ResMut<Assets<YarnStringTable>>,
-) {
-    if runner.state == DialogueRunnerState::Idle && !queue.is_empty() {
-        //println!("Setting up runner");
-
-        let temp_entry = queue.get(0).unwrap();
-        if yarn_programs.get(&temp_entry.program).is_some()
-            && yarn_tables.get(&temp_entry.table).is_some()
-        {
-            let entry = queue
-                .pop_front()
-                .expect("setup_runner: Dialogue queue empty!");
-
-            if let Some(program) = yarn_programs.remove(entry.program) {
-                //println!("Program Valid!");
-                if let Some(table) = yarn_tables.remove(entry.table) {
-                    runner.setup(program, table, entry.start_node)
-                }
-            } else {
-                //println!("Program not ready yet!");
-            }
-        } else {
-            //println!("Program not ready yet!");
-        }
+    pub fn default_start_node<I: Into<String>>(mut self, node: I) -> Self {
+        self.plugin.default_start_node = node.into();
+        self
     }
-}
 
-fn update_runner(
-    mut commands: Commands,
-    mut runner: ResMut<DialogueRunner>,
-    mut yarn_tables: ResMut<Assets<YarnStringTable>>,
-    mut queue: ResMut<DialogueQueue>,
-    mut yarn_programs: ResMut<Assets<YarnProgram>>,
-    mut event_writer: EventWriter<EventDialogueUpdated>,
-) {
-    if let DialogueRunnerState::Running(..) = runner.state.clone() {
-        let next_selection = match runner.vm.execution_state {
-            ExecutionState::WaitingOnOptionSelection => return,
-            _ => {
-                match runner.vm.continue_dialogue() {
-                    SuspendReason::Line(line) => {
-                        let new_text = runner.table.iter()
-                        .find(|line_info| line_info.id == line.id)
-                        .map(|line_info| &line_info.text)
-                        ;
-
-                        if let Some(new_text) = new_text {
-                            let subs = substitute(new_text.as_str(), &line.substitutions);
-                            event_writer.send(EventDialogueUpdated);
-                            DialogueRunningCurrentEntry::Text(subs)
-                        }
-                        else {
-                            panic!("Error! unable to find line!");
-                        }
-                    }
-                    SuspendReason::Options(new_options) => {
-                        let mut o = Vec::new();
-                        for opt in new_options.iter() {
-                            let t = runner.table.iter()
-                                .find(|line_info| line_info.id == opt.line.id)
-                                .map(|line_info| &line_info.text)
-                            ;
-                            if let Some(t) = t {
-                                o.push(t.clone());
-                            }
-                        }
-                        event_writer.send(EventDialogueUpdated);
-                        DialogueRunningCurrentEntry::Options(o)
-                    }
-                    SuspendReason::Command(command_text) => {
-                        //println!("== Command: {} ==", command_text);
-                        let mut arguments: Vec<String> = command_text.split(" ").map(|s| {s.to_string()}).collect()
-                        ;
-                        if !arguments.is_empty() {
-                            let name = arguments.remove(0);
-                            commands.add(ExecuteDialogueCommand {
-                                command: name, 
-                                args: arguments,
-                            });
-                        }
-                        DialogueRunningCurrentEntry::Null
-                    },
-                    SuspendReason::NodeChange { .. } => {
-                        DialogueRunningCurrentEntry::Null
-                        //println!("== Node end: {} ==", end);
-                        //println!("== Node start: {} ==", start);
-                    },
-                    SuspendReason::DialogueComplete(_last_node) => {
-                        //println!("== Node end: {} ==", last_node);
-                        //println!("== Dialogue complete ==");
-                        match queue.pop_front() {
-                            Some(entry) => {
-                                if yarn_programs.get(&entry.program).is_some() && yarn_tables.get(&entry.table).is_some() {
-                                    if let Some(program) = yarn_programs.remove(entry.program) {
-                                        if let Some(table) = yarn_tables.remove(entry.table) {
-                                            runner.setup(program, table, entry.start_node)
-                                        }
-                                    }
-                                } else {
-                                    runner.state = DialogueRunnerState::Idle;
-                                }
-                            }
-                            None => runner.state = DialogueRunnerState::Idle,
-                        }
-                        return
-                    }
-                }
-            }
-        };
+    pub fn option_policy(mut self, policy: OptionPresentationPolicy) -> Self {
+        self.plugin.option_policy = policy;
+        self
+    }
 
-        runner.state = DialogueRunnerState::Running(next_selection);
+    pub fn line_advance_mode(mut self, mode: LineAdvanceMode) -> Self {
+        self.plugin.line_advance_mode = mode;
+        self
     }
-}
 
-// *****************************************************************************************
-// Asset Loaders
-// *****************************************************************************************
-#[derive(Debug, TypeUuid, Deref)]
-#[uuid = "aa134e2e-a11e-4350-ae1e-b5410d0c333c"]
-pub struct YarnStringTable(pub Vec<LineInfo>);
+    pub fn validate_on_load(mut self, validate: bool) -> Self {
+        self.plugin.validate_on_load = validate;
+        self
+    }
 
-#[derive(Default)]
-pub struct YarnStringTableLoader;
+    pub fn csv_strictness(mut self, strictness: CsvStrictness) -> Self {
+        self.plugin.csv_strictness = strictness;
+        self
+    }
 
-impl AssetLoader for YarnStringTableLoader {
-    fn load<'a>(
-        &'a self,
-        bytes: &'a [u8],
-        load_context: &'a mut bevy::asset::LoadContext,
-    ) -> bevy::asset::BoxedFuture<'a, Result<(), anyhow::Error>> {
-        Box::pin(async move {
-            let mut csv_reader = csv::Reader::from_reader(bytes);
-            let string_table: Vec<LineInfo> = csv_reader
-                .deserialize()
-                .map(|result| result.unwrap())
-                .collect();
+    pub fn csv_dialect(mut self, dialect: CsvDialect) -> Self {
+        self.plugin.csv_dialect = dialect;
+        self
+    }
 
-            load_context.set_default_asset(LoadedAsset::new(YarnStringTable(string_table)));
-            Ok(())
-        })
+    pub fn option_timeout(mut self, timeout: OptionTimeout) -> Self {
+        self.plugin.option_timeout = Some(timeout);
+        self
     }
 
-    fn extensions(&self) -> &[&str] {
-        &["csv"]
+    pub fn command_separator(mut self, separator: char) -> Self {
+        self.plugin.command_separator = Some(separator);
+        self
     }
-}
 
-#[derive(Debug, TypeUuid)]
-#[uuid = "35d03e10-93b3-436e-8df4-7c7bea467dc0"]
-pub struct YarnProgram(Program);
-#[derive(Default)]
-pub struct YarnProgramLoader;
+    pub fn variable_type_checking(mut self, checking: VariableTypeChecking) -> Self {
+        self.plugin.variable_type_checking = checking;
+        self
+    }
 
-impl AssetLoader for YarnProgramLoader {
-    fn load<'a>(
-        &'a self,
-        bytes: &'a [u8],
-        load_context: &'a mut bevy::asset::LoadContext,
-    ) -> bevy::asset::BoxedFuture<'a, Result<(), anyhow::Error>> {
-        Box::pin(async move {
-            let program = Program::decode(bytes).unwrap();
-            load_context.set_default_asset(LoadedAsset::new(YarnProgram(program)));
-            Ok(())
-        })
+    pub fn coerce_number_bool(mut self, coerce: bool) -> Self {
+        self.plugin.coerce_number_bool = coerce;
+        self
     }
 
-    fn extensions(&self) -> &[&str] {
-        &["yarnc"]
+    /// Validates the configuration.
+    pub fn build(self) -> Result<DialoguePlugin, DialogueLoadError> {
+        if let Some(language) = &self.plugin.language {
+            if language.current.is_empty() {
+                return Err(DialogueLoadError::InvalidConfiguration(
+                    "language must not be empty".to_string(),
+                ));
+            }
+        }
+        if self.plugin.default_start_node.is_empty() {
+            return Err(DialogueLoadError::InvalidConfiguration(
+                "default start node must not be empty".to_string(),
+            ));
+        }
+        if let Some(startup_program) = &self.plugin.startup_program {
+            if startup_program.as_os_str().is_empty() {
+                return Err(DialogueLoadError::InvalidConfiguration(
+                    "startup program path must not be empty".to_string(),
+                ));
+            }
+        }
+        Ok(self.plugin)
     }
 }
 
-pub struct AddDialogueToQueueCommand {
-    pub path: PathBuf,
-    pub start_node: Option<String>,
-}
+impl Plugin for DialoguePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.language.clone().unwrap_or_default())
+            .insert_resource(StringTableFormat {
+                dialect: self.csv_dialect,
+                strictness: self.csv_strictness,
+            });
+        init_dialogue_world(&mut app.world);
+        app.add_asset::<YarnProgram>()
+            .add_asset::<YarnStringTable>()
+            .add_asset::<YarnLineMetadata>()
+            .init_asset_loader::<YarnProgramLoader>()
+            .add_asset_loader(YarnStringTableLoader {
+                strictness: self.csv_strictness,
+                dialect: self.csv_dialect,
+            })
+            .register_type::<DialogueRunner>()
+            .register_type::<DialogueRunnerState>()
+            .register_type::<DialogueRunningCurrentEntry>()
+            .register_type::<DialoguePhase>()
+            .register_type::<DialogueLine>()
+            .register_type::<DialogueOption>()
+            .register_type::<MarkupAttribute>()
+            .register_type::<SelectedOption>()
+            .register_type::<OptionPresentationPolicy>()
+            .register_type::<LineAdvanceMode>()
+            .register_type::<SkipSeenMode>()
+            .register_type::<PresentedLine>()
+            .register_type::<CurrentDialogueLine>()
+            .register_type::<CurrentDialogueOptions>()
+            .register_type::<SubstitutionProvider>()
+            .register_type::<DialogueHistory>()
+            .register_type::<HistoryEntry>()
+            .add_event::<EventDialogueUpdated>()
+            .add_event::<EventLinePresented>()
+            .add_event::<EventOptionsPresented>()
+            .add_event::<EventOptionSelected>()
+            .add_event::<EventCommandRun>()
+            .add_event::<EventNodeStarted>()
+            .add_event::<EventNodeCompleted>()
+            .add_event::<EventDialogueStarted>()
+            .add_event::<EventDialogueCompleted>()
+            .add_event::<EventMissingLine>()
+            .add_event::<EventMissingNode>()
+            .add_event::<EventDialogueLoadFailed>()
+            .add_event::<EventDialogueSkipped>()
+            .add_event::<EventTypeMismatch>()
+            .add_event::<EventUnsupportedProgram>()
+            .add_event::<EventLanguageFallback>()
+            .add_event::<EventValidationReport>()
+            .add_event::<EventUnknownCommand>()
+            .add_event::<EventKeyedDialogueUpdated>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                check_queue.exclusive_system().label(DialogueSystemSet::CheckQueue),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, apply_language_tables.exclusive_system())
+            .add_system_to_stage(CoreStage::PostUpdate, hot_reload_dialogue)
+            .add_system_to_stage(CoreStage::PostUpdate, release_blocking_command_hold)
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_runner
+                    .exclusive_system()
+                    .with_run_criteria(run_if_no_dialogue_hold)
+                    .label(DialogueSystemSet::Update),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_keyed_runners
+                    .exclusive_system()
+                    .with_run_criteria(run_if_no_dialogue_hold)
+                    .label(DialogueSystemSet::Update),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                tick_line_auto_advance
+                    .exclusive_system()
+                    .with_run_criteria(run_if_no_dialogue_hold)
+                    .before(DialogueSystemSet::Update),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                tick_option_timeout.exclusive_system().before(DialogueSystemSet::Update),
+            )
+            .add_system_to_stage(CoreStage::PreUpdate, tick_dialogue_wait)
+            .add_system_to_stage(CoreStage::PreUpdate, sync_current_dialogue);
 
-impl Command for AddDialogueToQueueCommand {
-    fn write(self, world: &mut World) {
-        let asset_server = world.get_resource::<AssetServer>().unwrap();
+        #[cfg(feature = "compile")]
+        app.init_asset_loader::<YarnSourceLoader>();
 
-        let program = asset_server.load(self.path.as_path());
-        let mut table_path = self.path.clone();
-        table_path.set_extension("csv");
-        let table = asset_server.load(table_path);
+        let variables = match &self.variables {
+            Some(variables) => variables.clone(),
+            None => SharedVariableStorage::new((self.variable_storage)()),
+        };
+        let mut runner = DialogueRunner::new(Program::default(), Vec::new(), variables);
+        runner.hot_reload = self.hot_reload;
+        runner.cache_programs = self.cache_programs;
+        runner.default_start_node = self.default_start_node.clone();
+        runner.option_policy = self.option_policy;
+        runner.line_advance_mode = self.line_advance_mode;
+        runner.validate_on_load = self.validate_on_load;
+        runner.option_timeout = self.option_timeout;
+        runner.command_separator = self.command_separator;
+        runner
+            .variables
+            .set_type_checking(self.variable_type_checking, self.coerce_number_bool);
+        if let Some(seed) = self.rng_seed {
+            runner.rng = DialogueRng::seeded(seed);
+        }
+        runner.install_functions(app.world.resource::<DialogueFunctions>());
+        app.insert_resource(runner);
 
-        let mut dialogue_queue = world.get_resource_mut::<DialogueQueue>().unwrap();
-        dialogue_queue.push_back(DialogueQueueEntry {
-            path: self.path.clone(),
-            program,
-            table,
-            start_node: self.start_node,
-        })
+        // The startup program goes through the asset pipeline like any other dialogue, so it
+        // loads from bundled assets on mobile and the web. Validation and unsupported opcodes
+        // are reported when it starts.
+        if let Some(path) = &self.startup_program {
+            if app.world.contains_resource::<AssetServer>() {
+                let language = app.world.resource::<DialogueLanguage>().clone();
+                let entry = load_queue_entry(&app.world, &language, path.clone(), None);
+                app.world.resource_mut::<DialogueQueue>().push_front(entry);
+            } else {
+                error!("DialoguePlugin: a startup program needs the AssetPlugin added first");
+            }
+        }
     }
 }
 
-fn substitute(input: &str, substitutions: &Vec<String>) -> String {
-    let mut parser = pair(
-        take_until("{"),
-        delimited(char('{'), is_not("}"), char('}')),
-    );
+/// Inserts the resources, event queues and builtin commands and functions the runner needs to
+/// step, shared by [`DialoguePlugin`] and [`DialogueDriver`]. Resources already present are
+/// kept.
+fn init_dialogue_world(world: &mut World) {
+    world.init_resource::<DialogueLanguage>();
+    world.init_resource::<DialogueQueue>();
+    world.init_resource::<DialoguePreloadCache>();
+    world.init_resource::<DialogueProgramCache>();
+    world.init_resource::<DialogueHistory>();
+    world.init_resource::<DialogueObservers>();
+    world.init_resource::<SubstitutionProvider>();
+    world.init_resource::<DialogueRunners>();
+    world.init_resource::<CurrentDialogueLine>();
+    world.init_resource::<CurrentDialogueOptions>();
+    world.init_resource::<DialogueCommands>();
+    world.init_resource::<DialogueBlockingCommands>();
+    world.init_resource::<BlockingDialogueCommands>();
+    world.init_resource::<DialogueHolds>();
+    world.init_resource::<InlineTriggerHandlers>();
+    world.init_resource::<DialogueFunctions>();
 
-    //let mut parser = pair(is_not("["), delimited(char('['), is_not("]"), char(']')));
-    let mut return_string = "".to_string();
-    let mut remainder = input.to_string();
-    let mut result: Result<(&str, (&str, &str)), Err<Error<&str>>> = parser(&input);
+    world.init_resource::<Events<EventDialogueUpdated>>();
+    world.init_resource::<Events<EventLinePresented>>();
+    world.init_resource::<Events<EventOptionsPresented>>();
+    world.init_resource::<Events<EventOptionSelected>>();
+    world.init_resource::<Events<EventCommandRun>>();
+    world.init_resource::<Events<EventNodeStarted>>();
+    world.init_resource::<Events<EventNodeCompleted>>();
+    world.init_resource::<Events<EventDialogueStarted>>();
+    world.init_resource::<Events<EventDialogueCompleted>>();
+    world.init_resource::<Events<EventMissingLine>>();
+    world.init_resource::<Events<EventMissingNode>>();
+    world.init_resource::<Events<EventDialogueLoadFailed>>();
+    world.init_resource::<Events<EventDialogueSkipped>>();
+    world.init_resource::<Events<EventTypeMismatch>>();
+    world.init_resource::<Events<EventUnsupportedProgram>>();
+    world.init_resource::<Events<EventLanguageFallback>>();
+    world.init_resource::<Events<EventValidationReport>>();
+    world.init_resource::<Events<EventUnknownCommand>>();
+    world.init_resource::<Events<EventKeyedDialogueUpdated>>();
 
-    if result.is_ok() {
-        let mut idx: usize = 0;
+    world
+        .register_dialogue_command("wait", builtin_wait)
+        .register_dialogue_command("yield", builtin_yield)
+        .register_dialogue_function("visited", builtin_visited)
+        .register_dialogue_function("visited_count", builtin_visited_count)
+        .register_dialogue_function("dice", builtin_dice)
+        .register_dialogue_function("random", builtin_random)
+        .register_dialogue_function("random_range", builtin_random_range)
+        .register_dialogue_function("round_places", builtin_round_places);
+}
 
-        while result.is_ok() {
-            if let Ok((remaining, (first, _sub))) = result {
-                return_string.push_str(first);
+/// Errors from configuring the plugin or opening a [`StreamingStringTable`]. Queued programs
+/// and tables, including the startup program, are read when they load, so their failures
+/// arrive as [`EventDialogueLoadFailed`] instead.
+#[derive(Debug)]
+pub enum DialogueLoadError {
+    TableRead { path: PathBuf, source: csv::Error },
+    TableParse { path: PathBuf, source: csv::Error },
+    InvalidConfiguration(String),
+}
 
-                return_string.push_str(&substitutions.get(idx).unwrap().to_string());
-                idx += 1;
-                remainder = remaining.to_string();
-                result = parser(remaining);
-                if let Err(_err) = &result {
-                    //println!("Failed to find more arguments. Error: {:?}, Remainder: {}", err, remainder);
-                    return_string.push_str(&remainder);
-                }
+impl fmt::Display for DialogueLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialogueLoadError::TableRead { path, source } => {
+                write!(f, "unable to read string table {:?}: {}", path, source)
+            }
+            DialogueLoadError::TableParse { path, source } => {
+                write!(f, "unable to parse string table {:?}: {}", path, source)
+            }
+            DialogueLoadError::InvalidConfiguration(reason) => {
+                write!(f, "invalid configuration: {}", reason)
             }
         }
-    } else {
-        return_string = remainder.to_string();
     }
-
-    return_string
 }
 
-// *****************************************************************************************
-// Run Conditions
-// *****************************************************************************************
-pub fn run_if_dialogue_queue_occupied(queue: Res<DialogueQueue>) -> ShouldRun {
-    match !queue.is_empty() {
-        true => ShouldRun::Yes,
-        false => ShouldRun::No,
+impl std::error::Error for DialogueLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DialogueLoadError::TableRead { source, .. } => Some(source),
+            DialogueLoadError::TableParse { source, .. } => Some(source),
+            DialogueLoadError::InvalidConfiguration(_) => None,
+        }
     }
 }
 
-pub fn run_if_no_dialogue_hold(hold: Option<Res<DialogueHold>>) -> ShouldRun {
-    match hold {
-        Some(_) => ShouldRun::No,
-        None => ShouldRun::Yes,
-    }
-}
+pub trait RegisterDialogueCommandExt {
+    fn register_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: fn(&mut World, Vec<String>),
+    ) -> &mut Self;
 
-pub fn run_if_dialogue_running(runner: Res<DialogueRunner>) -> ShouldRun {
-    match runner.state {
-        DialogueRunnerState::Idle => ShouldRun::No,
-        DialogueRunnerState::Running { .. } => ShouldRun::Yes,
-    }
-}
+    /// Registers a command handler that can capture state, such as a config value or a channel
+    /// sender, instead of reaching for everything through the `World`.
+    fn register_boxed_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: Box<BoxedDialogueCommand>,
+    ) -> &mut Self;
 
-pub struct ExecuteDialogueCommand {
-    pub command: String,
-    pub args: Vec<String>,
+    /// Registers a command whose arguments arrive classified by [`YarnValue::parse`], so
+    /// `<<give 5 true sword>>` is called with a number, a bool and a string.
+    fn register_typed_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: fn(&mut World, Vec<YarnValue>),
+    ) -> &mut Self;
+
+    /// Registers a command that blocks the dialogue until the game finishes it. Unlike
+    /// fire-and-forget commands, which let the runner continue on the next tick, the handler
+    /// starts work with [`BlockingDialogueCommands::begin`] and returns the token; a
+    /// [`BLOCKING_COMMAND_HOLD`] stays in [`DialogueHolds`] until
+    /// [`BlockingDialogueCommands::complete_command`] is called with it.
+    ///
+    /// A command name has one handler: registering a name again, blocking or not, replaces the
+    /// earlier handler.
+    fn register_blocking_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: BlockingDialogueCommand,
+    ) -> &mut Self;
+
+    fn register_dialogue_function<I: Into<String>>(
+        &mut self,
+        name: I,
+        function: fn(&mut World, Vec<YarnValue>) -> YarnValue,
+    ) -> &mut Self;
+
+    /// Registers a function that can capture state. See
+    /// [`RegisterDialogueCommandExt::register_boxed_dialogue_command`].
+    fn register_boxed_dialogue_function<I: Into<String>>(
+        &mut self,
+        name: I,
+        function: Box<BoxedDialogueFunction>,
+    ) -> &mut Self;
+
+    /// Handles commands that have no registered handler, receiving the command name and its
+    /// arguments.
+    fn set_dialogue_command_fallback(
+        &mut self,
+        fallback: fn(&mut World, String, Vec<String>),
+    ) -> &mut Self;
+
+    /// Consulted whenever the dialogue moves to another node, receiving the node being left and
+    /// the destination. Returning a node name sends the dialogue there instead.
+    fn set_node_transition_hook(
+        &mut self,
+        hook: fn(&mut World, &str, &str) -> Option<String>,
+    ) -> &mut Self;
+
+    /// Consulted before every command is dispatched, receiving its name and arguments, to log,
+    /// veto or rewrite it. See [`CommandAction`].
+    fn set_command_interceptor(
+        &mut self,
+        interceptor: Box<CommandInterceptorFn>,
+    ) -> &mut Self;
+
+    /// Handles inline triggers named `name`, e.g. `sfx` for `[sfx=clang/]`, as
+    /// [`fire_inline_triggers`] reveals them.
+    fn register_inline_trigger<I: Into<String>>(
+        &mut self,
+        name: I,
+        handler: fn(&mut World, &InlineTrigger),
+    ) -> &mut Self;
 }
 
-impl Command for ExecuteDialogueCommand {
-    fn write(self, world: &mut World) {
-        world.resource_scope(|world, command_registry: Mut<DialogueCommands>| {
-            if let Some(com) = command_registry.0.get(&self.command) {
-                com(world, self.args);
-            }
-        });
-    }
-}
\ No newline at end of file
+impl RegisterDialogueCommandExt for World {
+    fn register_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: fn(&mut World, Vec<String>),
+    ) -> &mut Self {
+        let name = take_command_name(self, name.into());
+        let mut commands = self.get_resource_or_insert_with(DialogueCommands::default);
+        commands.insert(name, DialogueCommandHandler::Fn(command));
+        self
+    }
+
+    fn register_boxed_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: Box<BoxedDialogueCommand>,
+    ) -> &mut Self {
+        let name = take_command_name(self, name.into());
+        let mut commands = self.get_resource_or_insert_with(DialogueCommands::default);
+        commands.insert(name, DialogueCommandHandler::Boxed(command.into()));
+        self
+    }
+
+    fn register_typed_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: fn(&mut World, Vec<YarnValue>),
+    ) -> &mut Self {
+        let name = take_command_name(self, name.into());
+        let mut commands = self.get_resource_or_insert_with(DialogueCommands::default);
+        commands.insert(name, DialogueCommandHandler::Typed(command));
+        self
+    }
+
+    fn register_blocking_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: BlockingDialogueCommand,
+    ) -> &mut Self {
+        let name = take_command_name(self, name.into());
+        let mut commands = self.get_resource_or_insert_with(DialogueBlockingCommands::default);
+        commands.insert(name, command);
+        self
+    }
+
+    fn register_dialogue_function<I: Into<String>>(
+        &mut self,
+        name: I,
+        function: fn(&mut World, Vec<YarnValue>) -> YarnValue,
+    ) -> &mut Self {
+        insert_dialogue_function(self, name.into(), DialogueFunctionHandler::Fn(function));
+        self
+    }
+
+    fn register_boxed_dialogue_function<I: Into<String>>(
+        &mut self,
+        name: I,
+        function: Box<BoxedDialogueFunction>,
+    ) -> &mut Self {
+        let handler = DialogueFunctionHandler::Boxed(function.into());
+        insert_dialogue_function(self, name.into(), handler);
+        self
+    }
+
+    fn set_dialogue_command_fallback(
+        &mut self,
+        fallback: fn(&mut World, String, Vec<String>),
+    ) -> &mut Self {
+        self.insert_resource(DialogueCommandFallback(fallback));
+        self
+    }
+
+    fn set_node_transition_hook(
+        &mut self,
+        hook: fn(&mut World, &str, &str) -> Option<String>,
+    ) -> &mut Self {
+        self.insert_resource(NodeTransitionHook(hook));
+        self
+    }
+
+    fn set_command_interceptor(
+        &mut self,
+        interceptor: Box<CommandInterceptorFn>,
+    ) -> &mut Self {
+        self.insert_resource(DialogueCommandInterceptor(interceptor));
+        self
+    }
+
+    fn register_inline_trigger<I: Into<String>>(
+        &mut self,
+        name: I,
+        handler: fn(&mut World, &InlineTrigger),
+    ) -> &mut Self {
+        let mut handlers = self.get_resource_or_insert_with(InlineTriggerHandlers::default);
+        handlers.insert(name.into(), handler);
+        self
+    }
+}
+
+/// Unregisters any command already called `name`, blocking or not, so the registration about
+/// to be made is the only one.
+fn take_command_name(world: &mut World, name: String) -> String {
+    let replaced = world
+        .get_resource_mut::<DialogueCommands>()
+        .and_then(|mut commands| commands.remove(&name))
+        .is_some()
+        | world
+            .get_resource_mut::<DialogueBlockingCommands>()
+            .and_then(|mut commands| commands.remove(&name))
+            .is_some();
+    if replaced {
+        warn!("Dialogue command {} was already registered, replacing it", name);
+    }
+    name
+}
+
+/// Stores `handler` and makes it callable from every existing runner's VM.
+fn insert_dialogue_function(world: &mut World, name: String, handler: DialogueFunctionHandler) {
+    let mut functions = world.get_resource_or_insert_with(DialogueFunctions::default);
+    functions.insert(name.clone(), handler);
+    if let Some(mut runner) = world.get_resource_mut::<DialogueRunner>() {
+        runner.install_function(name.clone());
+    }
+    if let Some(mut runners) = world.get_resource_mut::<DialogueRunners>() {
+        for keyed in runners.values_mut() {
+            keyed.runner.install_function(name.clone());
+        }
+    }
+}
+
+impl RegisterDialogueCommandExt for App {
+    fn register_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: fn(&mut World, Vec<String>),
+    ) -> &mut Self {
+        self.world.register_dialogue_command(name, command);
+        self
+    }
+
+    fn register_boxed_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: Box<BoxedDialogueCommand>,
+    ) -> &mut Self {
+        self.world.register_boxed_dialogue_command(name, command);
+        self
+    }
+
+    fn register_typed_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: fn(&mut World, Vec<YarnValue>),
+    ) -> &mut Self {
+        self.world.register_typed_dialogue_command(name, command);
+        self
+    }
+
+    fn register_blocking_dialogue_command<I: Into<String>>(
+        &mut self,
+        name: I,
+        command: BlockingDialogueCommand,
+    ) -> &mut Self {
+        self.world.register_blocking_dialogue_command(name, command);
+        self
+    }
+
+    fn register_dialogue_function<I: Into<String>>(
+        &mut self,
+        name: I,
+        function: fn(&mut World, Vec<YarnValue>) -> YarnValue,
+    ) -> &mut Self {
+        self.world.register_dialogue_function(name, function);
+        self
+    }
+
+    fn register_boxed_dialogue_function<I: Into<String>>(
+        &mut self,
+        name: I,
+        function: Box<BoxedDialogueFunction>,
+    ) -> &mut Self {
+        self.world.register_boxed_dialogue_function(name, function);
+        self
+    }
+
+    fn set_dialogue_command_fallback(
+        &mut self,
+        fallback: fn(&mut World, String, Vec<String>),
+    ) -> &mut Self {
+        self.world.set_dialogue_command_fallback(fallback);
+        self
+    }
+
+    fn set_node_transition_hook(
+        &mut self,
+        hook: fn(&mut World, &str, &str) -> Option<String>,
+    ) -> &mut Self {
+        self.world.set_node_transition_hook(hook);
+        self
+    }
+
+    fn set_command_interceptor(
+        &mut self,
+        interceptor: Box<CommandInterceptorFn>,
+    ) -> &mut Self {
+        self.world.set_command_interceptor(interceptor);
+        self
+    }
+
+    fn register_inline_trigger<I: Into<String>>(
+        &mut self,
+        name: I,
+        handler: fn(&mut World, &InlineTrigger),
+    ) -> &mut Self {
+        self.world.register_inline_trigger(name, handler);
+        self
+    }
+}
+// *****************************************************************************************
+// Events
+// *****************************************************************************************
+pub struct EventDialogueUpdated;
+
+/// A line was presented. Carries the line's source text after substitution and formatting,
+/// the line ID, the line's metadata tags, its [`InlineTrigger`]s, its voice-over clip from the
+/// [`VoiceOverTable`], if any, and the parsed line to display.
+///
+/// The source text still has its speaker prefix, markup tags and escape backslashes; show the
+/// [`DialogueLine`] instead unless the game parses lines itself.
+pub struct EventLinePresented(
+    pub String,
+    pub String,
+    pub Vec<String>,
+    pub Vec<InlineTrigger>,
+    pub Option<HandleUntyped>,
+    pub DialogueLine,
+);
+
+/// Options are waiting on a selection. Sent once when the VM starts waiting, not again while
+/// the player deliberates; rewinding to a choice presents it anew.
+pub struct EventOptionsPresented {
+    /// The presented set, each with its text, line ID and VM index.
+    pub options: Vec<DialogueOption>,
+    /// The countdown when the choice is timed, so UIs can render a clock.
+    pub timeout: Option<OptionTimeout>,
+}
+
+/// The player picked an option.
+pub struct EventOptionSelected(pub SelectedOption);
+
+/// A command is about to run, after the [`DialogueCommandInterceptor`] had its say. Skipped
+/// commands don't send one, and replaced commands carry the replacement.
+pub struct EventCommandRun {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// A node started running. Carries the node name and its `#tags`.
+pub struct EventNodeStarted(pub String, pub Vec<String>);
+
+pub struct EventNodeCompleted(pub String);
+
+/// Dialogue began playing from a queue entry. Carries the program path and the resolved start
+/// node.
+pub struct EventDialogueStarted {
+    pub path: PathBuf,
+    pub start_node: String,
+}
+
+/// The runner went idle. Carries the name of the last node, empty if none was running, and why
+/// it stopped.
+pub struct EventDialogueCompleted(pub String, pub IdleReason);
+
+/// A line or option referenced by the program has no row in the string table. A
+/// `[missing: <id>]` placeholder is presented in its place.
+pub struct EventMissingLine {
+    pub line_id: String,
+}
+
+/// The dialogue ran a command with no registered handler and no fallback.
+pub struct EventUnknownCommand {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// A loaded program references lines its string table has no rows for. Sent when
+/// [`DialoguePlugin::validate_on_load`] is set.
+pub struct EventValidationReport {
+    pub path: Option<PathBuf>,
+    pub missing_line_ids: Vec<String>,
+}
+
+/// A program uses opcodes this version of the VM can't run, usually because it was compiled by
+/// a newer Yarn Spinner. The program isn't started.
+pub struct EventUnsupportedProgram {
+    pub path: Option<PathBuf>,
+    pub program_name: String,
+    /// The raw values of the unknown opcodes, without duplicates.
+    pub opcodes: Vec<i32>,
+}
+
+/// The requested language's string table was missing or failed to parse, so dialogue is
+/// presented from the default-language table instead.
+pub struct EventLanguageFallback {
+    pub path: PathBuf,
+    pub requested: String,
+    pub used: String,
+}
+
+/// A node was requested that the loaded program doesn't contain.
+pub struct EventMissingNode {
+    pub requested: String,
+    pub available: Vec<String>,
+}
+
+/// The script assigned a value of the wrong type to a declared variable. See
+/// [`VariableTypeChecking`].
+pub struct EventTypeMismatch {
+    pub variable: String,
+    pub expected: YarnValueType,
+    pub got: YarnValueType,
+}
+
+/// A queued dialogue's condition no longer held when its turn came, so it was dropped.
+pub struct EventDialogueSkipped {
+    pub path: PathBuf,
+}
+
+/// A queued dialogue's program or string table couldn't be loaded, so the entry was dropped
+/// and the queue moved on.
+pub struct EventDialogueLoadFailed {
+    pub path: PathBuf,
+}
+// *****************************************************************************************
+// Resources
+// *****************************************************************************************
+/// Dialogue waiting to run, front first. Unlike the runner this isn't reflected, since
+/// `bevy_reflect` can't reflect `VecDeque` or `PathBuf`.
+#[derive(Default, Deref, DerefMut)]
+pub struct DialogueQueue {
+    pub queue: VecDeque<DialogueQueueEntry>,
+}
+
+/// Only entries that haven't started are queued; the running dialogue lives on the
+/// [`DialogueRunner`], so none of these affect it.
+impl DialogueQueue {
+    /// Drops every pending entry, e.g. when the scene they belong to unloads.
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    pub fn contains_path(&self, path: &Path) -> bool {
+        self.queue.iter().any(|entry| entry.path == path)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Drops every pending entry for the program at `path`.
+    pub fn remove_by_path(&mut self, path: &Path) {
+        self.queue.retain(|entry| entry.path != path);
+    }
+
+    /// The start node of each pending entry, front first. `None` means the runner's default
+    /// start node will be used.
+    pub fn start_nodes(&self) -> impl Iterator<Item = Option<&str>> {
+        self.queue.iter().map(|entry| entry.start_node.as_deref())
+    }
+}
+
+pub struct DialogueQueueEntry {
+    pub path: PathBuf,
+    pub program: Handle<YarnProgram>,
+    pub table: Handle<YarnStringTable>,
+    /// Default-language table consulted for lines missing from `table`.
+    pub fallback_table: Option<Handle<YarnStringTable>>,
+    /// Line metadata tags, `None` when the program's directory has no metadata file. The entry
+    /// doesn't wait on it if the file fails to load.
+    pub metadata: Option<Handle<YarnLineMetadata>>,
+    pub start_node: Option<String>,
+    /// Language the tables were requested in. `None` for dialogue that isn't loaded from disk.
+    pub language: Option<DialogueLanguage>,
+    /// How many times failed loads have been retried. See [`DIALOGUE_LOAD_RETRIES`].
+    pub load_retries: u32,
+    /// Commands only available while this entry runs, shadowing global ones of the same name.
+    pub extra_commands: Option<DialogueCommands>,
+    /// Checked when the entry reaches the front of the queue; if it returns `false` the entry
+    /// is dropped with [`EventDialogueSkipped`] instead of starting.
+    pub condition: Option<DialogueQueueCondition>,
+}
+
+pub type DialogueQueueCondition = Box<dyn Fn(&World) -> bool + Send + Sync>;
+
+/// How many times a queue entry's failed assets are reloaded before the entry is dropped with
+/// [`EventDialogueLoadFailed`].
+pub const DIALOGUE_LOAD_RETRIES: u32 = 3;
+
+/// Selects which string table dialogue is presented from. The default language uses the plain
+/// `.csv` next to the program (or the table compiled from a `.yarn` source), other languages use
+/// `<stem>.<language>.csv`.
+#[derive(Debug, Clone)]
+pub struct DialogueLanguage {
+    pub current: String,
+    pub default: String,
+}
+
+impl Default for DialogueLanguage {
+    fn default() -> Self {
+        Self {
+            current: "en".to_string(),
+            default: "en".to_string(),
+        }
+    }
+}
+
+impl DialogueLanguage {
+    pub fn is_default(&self) -> bool {
+        self.current == self.default
+    }
+
+    /// The string table for `program` in the current language.
+    pub fn table_path(&self, program: &Path) -> AssetPath<'static> {
+        match self.is_default() {
+            true => default_table_path(program),
+            false => string_table_path(program, Some(&self.current)).into(),
+        }
+    }
+
+    /// The default-language table for `program`, when the current language differs from it.
+    pub fn fallback_table_path(&self, program: &Path) -> Option<AssetPath<'static>> {
+        match self.is_default() {
+            true => None,
+            false => Some(default_table_path(program)),
+        }
+    }
+}
+
+/// Label of the string table sub-asset produced when a `.yarn` source is compiled on load.
+pub const COMPILED_TABLE_LABEL: &str = "lines";
+
+/// Default-language table for `program`. Compiled `.yarn` sources carry theirs as a labeled
+/// sub-asset instead of a separate `.csv`.
+fn default_table_path(program: &Path) -> AssetPath<'static> {
+    match program.extension().is_some_and(|ext| ext == "yarn") {
+        true => AssetPath::new(program.to_path_buf(), Some(COMPILED_TABLE_LABEL.to_string())),
+        false => string_table_path(program, None).into(),
+    }
+}
+
+/// The `<stem>-Metadata.csv` Yarn exports line metadata to, next to `program`.
+pub fn line_metadata_path(program: &Path) -> PathBuf {
+    let stem = program.file_stem().unwrap_or_default().to_string_lossy();
+    program.with_file_name(format!("{}-Metadata.csv", stem))
+}
+
+pub fn string_table_path(program: &Path, language: Option<&str>) -> PathBuf {
+    let mut table_path = program.to_path_buf();
+    match language {
+        Some(language) => table_path.set_extension(format!("{}.csv", language)),
+        None => table_path.set_extension("csv"),
+    };
+    table_path
+}
+
+/// Drives the yharnam VM and presents its output. Derives [`Reflect`] for inspector tooling;
+/// the VM, string tables, variable storage, asset handles and other fields whose types can't
+/// be reflected are skipped.
+#[derive(Reflect)]
+pub struct DialogueRunner {
+    #[reflect(ignore)]
+    pub vm: VirtualMachine,
+    /// Path of the running program, used to find its string tables when the language changes.
+    #[reflect(ignore)]
+    pub program_path: Option<PathBuf>,
+    /// Defaults of the variables the running program declares. See
+    /// [`YarnProgram::initial_values`].
+    #[reflect(ignore)]
+    pub initial_values: HashMap<String, YarnValue>,
+    #[reflect(ignore)]
+    pub table: Arc<Vec<LineInfo>>,
+    pub index: HashMap<String, usize>,
+    #[reflect(ignore)]
+    pub fallback_table: Arc<Vec<LineInfo>>,
+    pub fallback_index: HashMap<String, usize>,
+    /// Metadata tags per line ID, e.g. `lastline` or `character:Alice`.
+    pub line_metadata: HashMap<String, Vec<String>>,
+    /// Tables requested by [`SetDialogueLanguageCommand`] that are still loading.
+    #[reflect(ignore)]
+    pub pending_tables: Option<PendingLanguageTables>,
+    /// The line currently presented, kept so its text can be re-resolved after a language change.
+    #[reflect(ignore)]
+    pub current_line: Option<Line>,
+    pub hot_reload: bool,
+    /// See [`DialoguePlugin::cache_programs`].
+    pub cache_programs: bool,
+    /// Handles of the running program's assets, kept alive while hot reloading.
+    #[reflect(ignore)]
+    pub asset_handles: Option<DialogueAssetHandles>,
+    #[reflect(ignore)]
+    pub variables: SharedVariableStorage,
+    /// How many times each node has been started. Persists across queued dialogue until
+    /// [`DialogueRunner::reset_visited`] is called.
+    pub visited_counts: HashMap<String, u32>,
+    #[reflect(ignore)]
+    pub rng: DialogueRng,
+    pub current_node: Option<String>,
+    /// Node the most recently started dialogue began at, kept after it completes so
+    /// [`RestartDialogueCommand`] can replay it.
+    pub start_node: Option<String>,
+    /// Cleared when the next set of options is presented.
+    pub last_selected_option: Option<SelectedOption>,
+    /// Snapshots taken each time options are presented, newest last, for
+    /// [`RewindToLastChoiceCommand`]. Holds at most `max_choice_checkpoints`.
+    #[reflect(ignore)]
+    pub choice_checkpoints: VecDeque<ChoiceCheckpoint>,
+    pub max_choice_checkpoints: usize,
+    /// Node started when a queue entry doesn't name one.
+    pub default_start_node: String,
+    pub option_policy: OptionPresentationPolicy,
+    pub line_advance_mode: LineAdvanceMode,
+    /// Whether lines in already visited nodes advance on their own. See
+    /// [`DialogueRunner::is_skipping_seen`].
+    pub skip_seen: SkipSeenMode,
+    /// Counts down the presented line's auto-advance delay. See
+    /// [`DialogueRunner::auto_advance_delay`].
+    #[reflect(ignore)]
+    pub auto_advance: Option<Timer>,
+    /// Applied to every set of options without a `timeout:<seconds>` tag on one of its lines.
+    #[reflect(ignore)]
+    pub option_timeout: Option<OptionTimeout>,
+    /// The countdown for the options being presented, if they're timed.
+    #[reflect(ignore)]
+    pub option_timer: Option<(OptionTimeout, Timer)>,
+    /// When set, command text is split on this character (outside quotes) and each part runs
+    /// as its own command, so `<<fade_out; wait 1; fade_in>>` dispatches three commands.
+    #[reflect(ignore)]
+    pub command_separator: Option<char>,
+    /// Applied in order to every line and option after substitution. See
+    /// [`DialogueRunner::add_line_formatter`].
+    #[reflect(ignore)]
+    pub line_formatters: Vec<LineFormatter>,
+    /// Dialogue functions installed on the VM, stubbed out by [`DialogueRunner::peek_next`].
+    #[reflect(ignore)]
+    pub function_names: Vec<String>,
+    /// Values for named `{token}` placeholders, copied from the [`SubstitutionProvider`] each
+    /// time the runner steps.
+    pub named_substitutions: HashMap<String, String>,
+    /// Replacement text by line ID, taking precedence over both string tables. See
+    /// [`DialogueRunner::override_line`].
+    pub line_overrides: HashMap<String, String>,
+    /// Voice-over clip of the presented line. See [`VoiceOverTable`].
+    #[reflect(ignore)]
+    pub current_voice: Option<HandleUntyped>,
+    /// The running entry's [`DialogueQueueEntry::extra_commands`], dropped when it finishes.
+    #[reflect(ignore)]
+    pub scoped_commands: Option<DialogueCommands>,
+    /// Serves line text ahead of the loaded string tables, e.g. a [`StreamingStringTable`] for
+    /// tables too large to keep in memory.
+    #[reflect(ignore)]
+    pub string_source: Option<Box<dyn StringTableSource>>,
+    /// See [`DialoguePlugin::validate_on_load`].
+    pub validate_on_load: bool,
+    /// Why the runner last went idle. Only meaningful while `state` is idle.
+    #[reflect(ignore)]
+    pub idle_reason: IdleReason,
+    /// Set by `<<yield>>`. The VM doesn't step again until a [`ResumeDialogueCommand`].
+    pub paused: bool,
+    /// Set by `<<wait>>`. The VM doesn't step again until the timer finishes. Other runners
+    /// keep going.
+    #[reflect(ignore)]
+    pub wait: Option<Timer>,
+    pub state: DialogueRunnerState,
+}
+
+#[derive(Clone)]
+pub struct DialogueAssetHandles {
+    pub program: Handle<YarnProgram>,
+    pub table: Handle<YarnStringTable>,
+    pub fallback_table: Option<Handle<YarnStringTable>>,
+    pub metadata: Option<Handle<YarnLineMetadata>>,
+}
+
+/// A queue entry whose assets have finished loading, ready for [`DialogueRunner::setup`].
+struct ResolvedQueueEntry {
+    path: PathBuf,
+    program: YarnProgram,
+    table: YarnStringTable,
+    fallback_table: Option<YarnStringTable>,
+    metadata: YarnLineMetadata,
+    start_node: Option<String>,
+    handles: Option<DialogueAssetHandles>,
+    /// Set when the localized table was unavailable and the default table replaced it.
+    language_fallback: Option<EventLanguageFallback>,
+    extra_commands: Option<DialogueCommands>,
+}
+
+/// Takes a loaded entry's assets out of their collections. With `keep_assets` the assets are
+/// cloned instead so their handles stay valid for hot reloading.
+fn resolve_queue_entry(
+    entry: DialogueQueueEntry,
+    keep_assets: bool,
+    asset_server: &AssetServer,
+    yarn_programs: &mut Assets<YarnProgram>,
+    yarn_tables: &mut Assets<YarnStringTable>,
+    yarn_metadata: &mut Assets<YarnLineMetadata>,
+) -> Option<ResolvedQueueEntry> {
+    if !is_queue_entry_ready(&entry, asset_server, yarn_programs, yarn_tables, yarn_metadata) {
+        return None;
+    }
+    let handles = DialogueAssetHandles {
+        program: entry.program,
+        table: entry.table,
+        fallback_table: entry.fallback_table,
+        metadata: entry.metadata,
+    };
+    let program = take_dialogue_asset(yarn_programs, &handles.program, keep_assets)?;
+    let mut fallback_table = handles
+        .fallback_table
+        .as_ref()
+        .and_then(|fallback| take_dialogue_asset(yarn_tables, fallback, keep_assets));
+    let mut language_fallback = None;
+    let table = match take_dialogue_asset(yarn_tables, &handles.table, keep_assets) {
+        Some(table) => table,
+        // The localized table is missing, present the default language instead.
+        None => {
+            let table = fallback_table.take()?;
+            if let Some(language) = &entry.language {
+                warn!(
+                    "No {:?} string table for {:?}, using {:?}",
+                    language.current, entry.path, language.default
+                );
+                language_fallback = Some(EventLanguageFallback {
+                    path: entry.path.clone(),
+                    requested: language.current.clone(),
+                    used: language.default.clone(),
+                });
+            }
+            table
+        }
+    };
+    let metadata = handles
+        .metadata
+        .as_ref()
+        .and_then(|metadata| take_dialogue_asset(yarn_metadata, metadata, keep_assets))
+        .unwrap_or_default();
+    Some(ResolvedQueueEntry {
+        path: entry.path,
+        program,
+        table,
+        fallback_table,
+        metadata,
+        start_node: entry.start_node,
+        handles: keep_assets.then_some(handles),
+        language_fallback,
+        extra_commands: entry.extra_commands,
+    })
+}
+
+fn is_queue_entry_ready(
+    entry: &DialogueQueueEntry,
+    asset_server: &AssetServer,
+    yarn_programs: &Assets<YarnProgram>,
+    yarn_tables: &Assets<YarnStringTable>,
+    yarn_metadata: &Assets<YarnLineMetadata>,
+) -> bool {
+    let table_ready = yarn_tables.get(&entry.table).is_some()
+        || (entry.fallback_table.is_some()
+            && asset_server.get_load_state(&entry.table) == LoadState::Failed);
+    yarn_programs.get(&entry.program).is_some()
+        && table_ready
+        && entry
+            .fallback_table
+            .as_ref()
+            .is_none_or(|fallback| yarn_tables.get(fallback).is_some())
+        && entry.metadata.as_ref().is_none_or(|metadata| {
+            // Metadata is optional, a missing file counts as empty.
+            yarn_metadata.get(metadata).is_some()
+                || asset_server.get_load_state(metadata) == LoadState::Failed
+        })
+}
+
+/// The entry's assets that failed to load and that it can't start without. A failed table is
+/// fine when there's a fallback table, and metadata is always optional.
+fn failed_queue_assets(entry: &DialogueQueueEntry, asset_server: &AssetServer) -> Vec<HandleId> {
+    let failed = |id: HandleId| asset_server.get_load_state(id) == LoadState::Failed;
+    let mut required = vec![entry.program.id];
+    match &entry.fallback_table {
+        Some(fallback) => required.push(fallback.id),
+        None => required.push(entry.table.id),
+    }
+    required.into_iter().filter(|id| failed(*id)).collect()
+}
+
+fn take_dialogue_asset<T: Asset + Clone>(
+    assets: &mut Assets<T>,
+    handle: &Handle<T>,
+    keep_assets: bool,
+) -> Option<T> {
+    match keep_assets {
+        true => assets.get(handle).cloned(),
+        false => assets.remove(handle),
+    }
+}
+
+pub struct PendingLanguageTables {
+    pub table: Handle<YarnStringTable>,
+    pub fallback_table: Option<Handle<YarnStringTable>>,
+    /// The language being switched from, whose tables stay if neither new table loads.
+    pub previous_language: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Reflect, FromReflect)]
+#[reflect_value(PartialEq)]
+pub enum DialogueRunnerState {
+    Idle,
+    Running(DialogueRunningCurrentEntry),
+}
+
+#[derive(Debug, Clone, PartialEq, Reflect, FromReflect)]
+#[reflect_value(PartialEq)]
+pub enum DialogueRunningCurrentEntry {
+    Null,
+    Text(DialogueLine),
+    Options(Vec<DialogueOption>),
+}
+
+/// Why a [`DialogueRunner`] is idle. See [`DialogueRunner::idle_reason`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdleReason {
+    /// Nothing has been started yet.
+    QueueEmpty,
+    /// The dialogue ran to its end.
+    Completed { last_node: String },
+    /// [`StopDialogueCommand`] interrupted it.
+    Stopped,
+    /// Dialogue couldn't start or continue, e.g. its start node is missing.
+    Failed { reason: String },
+}
+
+/// What a [`DialogueRunner`] is doing, independent of yharnam's internal execution state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Reflect, FromReflect)]
+#[reflect_value(PartialEq)]
+pub enum DialoguePhase {
+    /// No dialogue has been started, or the last one failed to start or continue.
+    Idle,
+    PresentingLine,
+    AwaitingOptionSelection,
+    /// Between lines and options: the VM is stepping, running commands, or held.
+    RunningCommand,
+    /// The script yielded with `<<yield>>` and waits on a [`ResumeDialogueCommand`].
+    Paused,
+    /// The last dialogue finished or was stopped and nothing has started since.
+    Completed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Reflect, FromReflect)]
+pub struct DialogueLine {
+    /// The character name written before the first unescaped colon, e.g. `Alice` in
+    /// `Alice: Hello there`.
+    pub speaker: Option<String>,
+    /// The line body with markup tags stripped.
+    pub text: String,
+    /// Markup spans, indexed by character into `text`.
+    pub attributes: Vec<MarkupAttribute>,
+}
+
+impl DialogueLine {
+    pub fn parse(line: &str) -> Self {
+        let mut escaped = false;
+        for (idx, c) in line.char_indices() {
+            match c {
+                '\\' if !escaped => {
+                    escaped = true;
+                    continue;
+                }
+                ':' if !escaped => {
+                    let speaker = line[..idx].trim();
+                    if speaker.is_empty() {
+                        break;
+                    }
+                    let markup = parse_markup(line[idx + 1..].trim_start());
+                    return Self {
+                        speaker: Some(unescape_text(speaker)),
+                        text: markup.clean_text,
+                        attributes: markup.attributes,
+                    };
+                }
+                _ => {}
+            }
+            escaped = false;
+        }
+        let markup = parse_markup(line);
+        Self {
+            speaker: None,
+            text: markup.clean_text,
+            attributes: markup.attributes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Reflect, FromReflect)]
+pub struct DialogueOption {
+    pub text: String,
+    pub line_id: String,
+    /// The option's index in the VM, which [`SelectDialogueOptionCommand`] takes.
+    pub index: usize,
+    /// Whether the option's `<<if>>` condition passed. Options without one are always available.
+    pub is_available: bool,
+}
+
+/// What to do with options whose `<<if>>` condition failed. Either way each option keeps its
+/// original `index`, which is what [`SelectDialogueOptionCommand`] expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Reflect, FromReflect, Default)]
+#[reflect_value(PartialEq)]
+pub enum OptionPresentationPolicy {
+    /// Leave unavailable options out of the presented set.
+    HideUnavailable,
+    /// Present every option, with `is_available` false on those that failed.
+    #[default]
+    ShowDisabled,
+}
+
+pub type LineFormatter = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// How the runner moves on from a presented line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Reflect, FromReflect, Default)]
+#[reflect_value(PartialEq)]
+pub enum LineAdvanceMode {
+    /// Continue on the next `update_runner` tick.
+    #[default]
+    Auto,
+    /// Keep the line presented until an [`AdvanceDialogueCommand`] arrives, for reading-paced
+    /// dialogue.
+    Manual,
+    /// Advance after the line has been presented for `delay`, for cutscene-style playback. An
+    /// [`AdvanceDialogueCommand`] still advances early. Time spent held doesn't count.
+    AutoAdvance { delay: Duration },
+}
+
+/// Fast-forwarding through dialogue the player has already read, for replays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Reflect, FromReflect, Default)]
+#[reflect_value(PartialEq)]
+pub enum SkipSeenMode {
+    #[default]
+    Off,
+    /// Lines in nodes visited before advance immediately, whatever the [`LineAdvanceMode`].
+    /// Commands still run, and options and unvisited nodes present as usual.
+    On,
+}
+
+/// A timed choice: once `duration` passes without a selection, the option whose
+/// [`DialogueOption::index`] is `default_index` is picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OptionTimeout {
+    pub duration: Duration,
+    pub default_index: usize,
+}
+
+/// The option chosen from the most recently presented set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Reflect, FromReflect)]
+pub struct SelectedOption {
+    pub index: usize,
+    pub text: String,
+    pub line_id: String,
+    /// How many options were presented alongside it.
+    pub option_count: usize,
+}
+
+/// A read-only copy of the VM's internals for debugging overlays. See
+/// [`DialogueRunner::debug_snapshot`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialogueDebugInfo {
+    pub current_node: Option<String>,
+    pub program_counter: isize,
+    /// The VM's value stack, bottom first.
+    pub stack: Vec<YarnValue>,
+    pub variables: HashMap<String, YarnValue>,
+    pub execution_state: ExecutionState,
+}
+
+/// What the dialogue would stop at next. See [`DialogueRunner::peek_next`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeekResult {
+    Line { line_id: String, line: DialogueLine },
+    Options(Vec<DialogueOption>),
+    Command { name: String, args: Vec<String> },
+    Complete,
+}
+
+impl DialogueRunner {
+    /// Creates an idle runner around `program`.
+    pub fn new(program: Program, table: Vec<LineInfo>, variables: SharedVariableStorage) -> Self {
+        Self {
+            vm: VirtualMachine::new(program),
+            program_path: None,
+            index: build_line_index(&table),
+            table: Arc::new(table),
+            fallback_table: Arc::default(),
+            fallback_index: HashMap::default(),
+            line_metadata: HashMap::default(),
+            initial_values: HashMap::default(),
+            pending_tables: None,
+            current_line: None,
+            hot_reload: false,
+            cache_programs: false,
+            asset_handles: None,
+            variables,
+            visited_counts: HashMap::default(),
+            rng: DialogueRng::default(),
+            current_node: None,
+            start_node: None,
+            last_selected_option: None,
+            choice_checkpoints: VecDeque::new(),
+            max_choice_checkpoints: 16,
+            default_start_node: "Start".to_string(),
+            option_policy: OptionPresentationPolicy::default(),
+            line_advance_mode: LineAdvanceMode::default(),
+            skip_seen: SkipSeenMode::default(),
+            auto_advance: None,
+            option_timeout: None,
+            option_timer: None,
+            command_separator: None,
+            line_formatters: Vec::new(),
+            function_names: Vec::new(),
+            named_substitutions: HashMap::default(),
+            line_overrides: HashMap::default(),
+            current_voice: None,
+            scoped_commands: None,
+            string_source: None,
+            validate_on_load: false,
+            idle_reason: IdleReason::QueueEmpty,
+            paused: false,
+            wait: None,
+            state: DialogueRunnerState::Idle,
+        }
+    }
+
+    /// Makes every registered dialogue function callable from this runner's VM.
+    pub fn install_functions(&mut self, functions: &DialogueFunctions) {
+        for name in functions.keys() {
+            self.install_function(name.clone());
+        }
+    }
+
+    /// Gives each declared variable that has no value yet its default, so values restored from
+    /// a save take precedence, and declares its type for [`VariableTypeChecking`].
+    fn seed_initial_values(&self) {
+        self.variables.declare_initial_types(&self.initial_values);
+        for (name, value) in &self.initial_values {
+            if self.variables.get(name).is_none() {
+                self.variables.set(name, value.clone());
+            }
+        }
+    }
+
+    fn install_function(&mut self, name: String) {
+        install_dialogue_function(&mut self.vm, name.clone());
+        if !self.function_names.contains(&name) {
+            self.function_names.push(name);
+        }
+    }
+
+    /// Loads the entry and moves to its start node. If the start node doesn't exist the runner
+    /// stays idle and the error lists the nodes the program does have.
+    fn setup(&mut self, entry: ResolvedQueueEntry) -> Result<(), EventMissingNode> {
+        let start_node = match entry.start_node {
+            Some(s) => s,
+            None => self.default_start_node.clone(),
+        };
+        self.vm.program = entry.program.program;
+        self.initial_values = entry.program.initial_values;
+        self.seed_initial_values();
+        self.program_path = Some(entry.path);
+        self.asset_handles = entry.handles;
+        self.set_tables(
+            entry.table.0,
+            entry.fallback_table.map(|table| table.0).unwrap_or_default(),
+        );
+        self.line_metadata = entry.metadata.0;
+        self.scoped_commands = entry.extra_commands;
+        self.current_line = None;
+        self.paused = false;
+        trace!("Loaded program with nodes {:?}", self.node_names());
+        self.last_selected_option = None;
+        match self.resolve_node_group(&start_node) {
+            Some(entry_node) => {
+                debug!("Starting dialogue at node {}", entry_node);
+                self.vm.set_node(&entry_node);
+                self.mark_visited(&entry_node);
+                self.current_node = Some(entry_node);
+                self.start_node = Some(start_node);
+                self.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null);
+                Ok(())
+            }
+            None => {
+                self.current_node = None;
+                self.scoped_commands = None;
+                self.idle_reason = IdleReason::Failed {
+                    reason: format!("start node {} not found", start_node),
+                };
+                self.start_node = Some(start_node.clone());
+                self.state = DialogueRunnerState::Idle;
+                Err(EventMissingNode {
+                    requested: start_node,
+                    available: self.node_names(),
+                })
+            }
+        }
+    }
+
+    pub fn idle_reason(&self) -> &IdleReason {
+        &self.idle_reason
+    }
+
+    pub fn phase(&self) -> DialoguePhase {
+        match &self.state {
+            DialogueRunnerState::Idle => match self.idle_reason {
+                IdleReason::Completed { .. } | IdleReason::Stopped => DialoguePhase::Completed,
+                IdleReason::QueueEmpty | IdleReason::Failed { .. } => DialoguePhase::Idle,
+            },
+            _ if self.paused => DialoguePhase::Paused,
+            _ if matches!(self.vm.execution_state, ExecutionState::WaitingOnOptionSelection) => {
+                DialoguePhase::AwaitingOptionSelection
+            }
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(_)) => {
+                DialoguePhase::PresentingLine
+            }
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(_)) => {
+                DialoguePhase::AwaitingOptionSelection
+            }
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null) => {
+                DialoguePhase::RunningCommand
+            }
+        }
+    }
+
+    pub fn last_selected_option(&self) -> Option<&SelectedOption> {
+        self.last_selected_option.as_ref()
+    }
+
+    /// The metadata tags exported for `line_id`, if it has any.
+    pub fn line_metadata(&self, line_id: &str) -> Option<&[String]> {
+        self.line_metadata.get(line_id).map(Vec::as_slice)
+    }
+
+    /// How long `line_id` stays presented before advancing on its own. An `autoadvance:<seconds>`
+    /// metadata tag on the line takes precedence over [`LineAdvanceMode::AutoAdvance`].
+    pub fn auto_advance_delay(&self, line_id: &str) -> Option<Duration> {
+        let tagged = self
+            .line_metadata(line_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|tag| tag.trim_start_matches('#').strip_prefix("autoadvance:"))
+            .find_map(|seconds| seconds.parse::<f32>().ok())
+            .filter(|seconds| *seconds >= 0.0)
+            .map(Duration::from_secs_f32);
+        match (tagged, self.line_advance_mode) {
+            (Some(delay), _) => Some(delay),
+            (None, LineAdvanceMode::AutoAdvance { delay }) => Some(delay),
+            (None, _) => None,
+        }
+    }
+
+    /// Copies the VM's stack and program counter along with every stored variable.
+    pub fn debug_snapshot(&self) -> DialogueDebugInfo {
+        DialogueDebugInfo {
+            current_node: self.current_node.clone(),
+            program_counter: self.vm.state.program_counter,
+            stack: self.vm.state.stack.iter().cloned().map(YarnValue::from).collect(),
+            variables: self.variables.all(),
+            execution_state: self.vm.execution_state,
+        }
+    }
+
+    /// Previews what the dialogue stops at after the current line, without moving the runner.
+    /// A scratch VM runs from a copy of the position and variables, so nothing is presented,
+    /// no commands run and no variables change. Node jumps are followed.
+    ///
+    /// This is best-effort: dialogue functions evaluate to null during the preview, so branches
+    /// that depend on them may differ from what actually plays. Returns `None` while idle or
+    /// waiting on an option selection.
+    pub fn peek_next(&self) -> Option<PeekResult> {
+        let node = self.current_node.as_ref()?;
+        if matches!(self.vm.execution_state, ExecutionState::WaitingOnOptionSelection) {
+            return None;
+        }
+        let mut vm = VirtualMachine::new(self.vm.program.clone());
+        self.variables.load_into(&mut vm);
+        for name in &self.function_names {
+            let null: &'static ReturningFunction = &|_| yharnam::YarnValue::Null;
+            vm.library.insert(name.clone(), FunctionInfo::new_returning(-1, null));
+        }
+        vm.set_node(node);
+        vm.state.program_counter = self.vm.state.program_counter;
+        vm.state.stack = self.vm.state.stack.clone();
+        vm.state.current_options = self.vm.state.current_options.clone();
+
+        // Every jump moves to another node, so more than one per node means a loop with nothing
+        // to present.
+        for _ in 0..=self.node_count() {
+            let result = match vm.continue_dialogue() {
+                SuspendReason::NodeChange { .. } => continue,
+                SuspendReason::Line(line) => {
+                    let text = self
+                        .resolve_line(&line)
+                        .unwrap_or_else(|| missing_line_placeholder(&line.id));
+                    PeekResult::Line {
+                        line_id: line.id,
+                        line: DialogueLine::parse(&text),
+                    }
+                }
+                SuspendReason::Options(options) => {
+                    let available = take_option_conditions(&mut vm, &options);
+                    let mut options: Vec<_> = options
+                        .iter()
+                        .zip(available)
+                        .enumerate()
+                        .map(|(index, (option, is_available))| DialogueOption {
+                            text: unescape_text(
+                                &self
+                                    .resolve_line(&option.line)
+                                    .unwrap_or_else(|| missing_line_placeholder(&option.line.id)),
+                            ),
+                            line_id: option.line.id.clone(),
+                            index,
+                            is_available,
+                        })
+                        .collect();
+                    if self.option_policy == OptionPresentationPolicy::HideUnavailable {
+                        options.retain(|option| option.is_available);
+                    }
+                    PeekResult::Options(options)
+                }
+                SuspendReason::Command(command_text) => {
+                    let mut arguments = split_command_arguments(&command_text);
+                    let name = match arguments.is_empty() {
+                        true => String::new(),
+                        false => arguments.remove(0),
+                    };
+                    PeekResult::Command {
+                        name,
+                        args: arguments,
+                    }
+                }
+                SuspendReason::DialogueComplete(_) => PeekResult::Complete,
+            };
+            return Some(result);
+        }
+        None
+    }
+
+    /// The name the loaded program was compiled with.
+    pub fn program_name(&self) -> &str {
+        &self.vm.program.name
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.vm.program.nodes.len()
+    }
+
+    /// Names of the loaded program's nodes, sorted.
+    pub fn node_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.vm.program.nodes.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn node_exists(&self, name: &str) -> bool {
+        self.vm.program.nodes.contains_key(name)
+    }
+
+    pub fn current_node(&self) -> Option<String> {
+        self.current_node.clone()
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<YarnValue> {
+        self.variables.get(name)
+    }
+
+    pub fn set_variable<I: Into<String>>(&mut self, name: I, value: YarnValue) {
+        self.variables.set(&name.into(), value);
+    }
+
+    /// The `#tags` of the running node. yharnam's program format carries no header key/value
+    /// pairs beyond `title`, so only tags are available.
+    pub fn current_node_tags(&self) -> &[String] {
+        self.current_node
+            .as_deref()
+            .and_then(|node| self.node_tags(node))
+            .unwrap_or_default()
+    }
+
+    pub fn node_tags(&self, name: &str) -> Option<&[String]> {
+        self.vm.program.nodes.get(name).map(|node| node.tags.as_slice())
+    }
+
+    /// Picks the node to enter for `name`. Nodes tagged `group:<name>` compete: those whose
+    /// `when:` tags all hold are candidates, and the one with the highest `priority:<n>` tag
+    /// wins, then the one with the most conditions, then the first by name. A `when:` tag is
+    /// `$var` (truthy), `!$var` (falsy) or `$var=value`. Without a group `name` must match a
+    /// node exactly.
+    pub fn resolve_node_group(&self, name: &str) -> Option<String> {
+        let group_tag = format!("group:{}", name);
+        let mut candidates: Vec<(i32, usize, &str)> = self
+            .vm
+            .program
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.tags.contains(&group_tag))
+            .filter_map(|(node_name, node)| {
+                let conditions: Vec<&str> =
+                    node.tags.iter().filter_map(|tag| tag.strip_prefix("when:")).collect();
+                if !conditions.iter().all(|condition| self.node_condition_holds(condition)) {
+                    return None;
+                }
+                let priority = node
+                    .tags
+                    .iter()
+                    .filter_map(|tag| tag.strip_prefix("priority:"))
+                    .find_map(|priority| priority.parse::<i32>().ok())
+                    .unwrap_or_default();
+                Some((priority, conditions.len(), node_name.as_str()))
+            })
+            .collect();
+        let is_group = !candidates.is_empty()
+            || self.vm.program.nodes.values().any(|node| node.tags.contains(&group_tag));
+        if !is_group {
+            return self.node_exists(name).then(|| name.to_string());
+        }
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(a.2.cmp(b.2)));
+        candidates.first().map(|(_, _, node_name)| node_name.to_string())
+    }
+
+    fn node_condition_holds(&self, condition: &str) -> bool {
+        let condition = condition.trim();
+        if let Some(variable) = condition.strip_prefix('!') {
+            return !self.get_variable(variable.trim()).is_some_and(|value| value.as_bool());
+        }
+        match condition.split_once('=') {
+            Some((variable, expected)) => {
+                self.get_variable(variable.trim()) == Some(YarnValue::parse(expected.trim()))
+            }
+            None => self.get_variable(condition).is_some_and(|value| value.as_bool()),
+        }
+    }
+
+    pub fn has_visited(&self, node: &str) -> bool {
+        self.visit_count(node) > 0
+    }
+
+    pub fn visit_count(&self, node: &str) -> u32 {
+        self.visited_counts.get(node).copied().unwrap_or(0)
+    }
+
+    pub fn reset_visited(&mut self) {
+        self.visited_counts.clear();
+    }
+
+    /// Whether the presented line is being skipped because [`SkipSeenMode::On`] is set and its
+    /// node was visited before this pass.
+    pub fn is_skipping_seen(&self) -> bool {
+        self.skip_seen == SkipSeenMode::On
+            && self
+                .current_node
+                .as_ref()
+                .is_some_and(|node| self.visit_count(node) > 1)
+    }
+
+    fn mark_visited(&mut self, node: &str) {
+        *self.visited_counts.entry(node.to_string()).or_insert(0) += 1;
+    }
+
+    /// Halts the virtual machine and discards its node, pending options and stack.
+    fn stop_vm(&mut self) {
+        self.vm.execution_state = ExecutionState::Stopped;
+        self.vm.state = VmState {
+            current_node_name: String::new(),
+            program_counter: 0,
+            current_options: Vec::new(),
+            stack: Vec::new(),
+        };
+    }
+
+    fn set_tables(&mut self, table: Arc<Vec<LineInfo>>, fallback_table: Arc<Vec<LineInfo>>) {
+        self.table = table;
+        self.index = build_line_index(&self.table);
+        self.fallback_table = fallback_table;
+        self.fallback_index = build_line_index(&self.fallback_table);
+    }
+
+    /// Looks up a line in the active table, falling back to the default-language table.
+    pub fn line_info(&self, id: &str) -> Option<&LineInfo> {
+        self.index
+            .get(id)
+            .and_then(|idx| self.table.get(*idx))
+            .or_else(|| {
+                self.fallback_index
+                    .get(id)
+                    .and_then(|idx| self.fallback_table.get(*idx))
+            })
+    }
+
+    /// The raw text of `line_id`, looked up in the overrides, then the
+    /// [`DialogueRunner::string_source`], then the loaded string tables.
+    pub fn line_text(&self, line_id: &str) -> Option<Cow<'_, str>> {
+        if let Some(text) = self.line_overrides.get(line_id) {
+            return Some(Cow::Borrowed(text));
+        }
+        if let Some(text) = self.string_source.as_ref().and_then(|source| source.text(line_id)) {
+            return Some(text);
+        }
+        self.line_info(line_id)
+            .map(|line_info| Cow::Borrowed(line_info.text.as_str()))
+    }
+
+    /// The substituted text of `line`, if it is overridden or in a string table. Substituted
+    /// values are escaped, so a value like `[b]` or `Bob:` shows as written instead of being read
+    /// as markup or a speaker.
+    pub fn resolve_line(&self, line: &Line) -> Option<String> {
+        let substitutions: Vec<_> = line.substitutions.iter().map(|s| escape_text(s)).collect();
+        let named = self
+            .named_substitutions
+            .iter()
+            .map(|(name, value)| (name.clone(), escape_text(value)))
+            .collect();
+        self.line_text(&line.id)
+            .map(|text| apply_named_substitutions(&text, &substitutions, &named))
+            .map(|text| self.format_line(text))
+    }
+
+    /// Presents `text` for `line_id` instead of its string table row, e.g. for mods or A/B
+    /// tests. The text goes through substitution, formatting and markup like any other line, and
+    /// applies to options too. Takes effect the next time the line is presented.
+    pub fn override_line(&mut self, line_id: impl Into<String>, text: impl Into<String>) {
+        self.line_overrides.insert(line_id.into(), text.into());
+    }
+
+    /// Reverts `line_id` to its string table text.
+    pub fn clear_override(&mut self, line_id: &str) {
+        self.line_overrides.remove(line_id);
+    }
+
+    /// Adds a transform applied to every line and option, e.g. trimming or profanity filtering.
+    /// Formatters run after any already added, on the substituted text before markup is parsed.
+    pub fn add_line_formatter(&mut self, formatter: LineFormatter) {
+        self.line_formatters.push(formatter);
+    }
+
+    fn format_line(&self, text: String) -> String {
+        self.line_formatters
+            .iter()
+            .fold(text, |text, formatter| formatter(&text))
+    }
+
+    /// Checks that every line and option the program can present has a row in the loaded
+    /// tables, logging and returning a report when some don't.
+    pub fn validate_line_ids(&self) -> Option<EventValidationReport> {
+        use yharnam::yarn_proto::instruction::OpCode;
+
+        let mut missing: Vec<String> = self
+            .vm
+            .program
+            .nodes
+            .values()
+            .flat_map(|node| node.instructions.iter())
+            .filter(|instruction| {
+                matches!(
+                    instruction.opcode_enum(),
+                    Some(OpCode::RunLine) | Some(OpCode::AddOption)
+                )
+            })
+            .filter_map(|instruction| instruction.operands.first()?.as_string())
+            .map(str::to_string)
+            .filter(|line_id| self.line_text(line_id).is_none())
+            .collect();
+        if missing.is_empty() {
+            return None;
+        }
+        missing.sort();
+        missing.dedup();
+        warn!(
+            "Dialogue {:?} references {} line(s) missing from its string table: {}",
+            self.program_path.as_deref().unwrap_or_else(|| Path::new("")),
+            missing.len(),
+            missing.join(", ")
+        );
+        Some(EventValidationReport {
+            path: self.program_path.clone(),
+            missing_line_ids: missing,
+        })
+    }
+}
+
+fn build_line_index(table: &[LineInfo]) -> HashMap<String, usize> {
+    table
+        .iter()
+        .enumerate()
+        .map(|(idx, line_info)| (line_info.id.clone(), idx))
+        .collect()
+}
+
+#[derive(Clone, Deref, DerefMut, Default)]
+pub struct DialogueCommands(HashMap<String, DialogueCommandHandler>);
+
+/// A command closure that can capture state.
+pub type BoxedDialogueCommand = dyn Fn(&mut World, Vec<String>) + Send + Sync;
+
+/// A registered command: a plain function, a closure registered with
+/// [`RegisterDialogueCommandExt::register_boxed_dialogue_command`], or a function taking parsed
+/// arguments registered with [`RegisterDialogueCommandExt::register_typed_dialogue_command`].
+#[derive(Clone)]
+pub enum DialogueCommandHandler {
+    Fn(fn(&mut World, Vec<String>)),
+    Boxed(Arc<BoxedDialogueCommand>),
+    Typed(fn(&mut World, Vec<YarnValue>)),
+}
+
+impl DialogueCommandHandler {
+    pub fn call(&self, world: &mut World, args: Vec<String>) {
+        match self {
+            DialogueCommandHandler::Fn(command) => command(world, args),
+            DialogueCommandHandler::Boxed(command) => command(world, args),
+            DialogueCommandHandler::Typed(command) => {
+                command(world, args.iter().map(|arg| YarnValue::parse(arg)).collect())
+            }
+        }
+    }
+}
+
+/// A command that returns a token the dialogue waits on until it's completed.
+pub type BlockingDialogueCommand = fn(&mut World, Vec<String>) -> DialogueCommandToken;
+
+#[derive(Deref, DerefMut, Default)]
+pub struct DialogueBlockingCommands(HashMap<String, BlockingDialogueCommand>);
+
+pub struct DialogueCommandFallback(pub fn(&mut World, String, Vec<String>));
+
+/// See [`RegisterDialogueCommandExt::register_inline_trigger`].
+#[derive(Deref, DerefMut, Default)]
+pub struct InlineTriggerHandlers(HashMap<String, fn(&mut World, &InlineTrigger)>);
+
+/// See [`RegisterDialogueCommandExt::set_node_transition_hook`].
+pub struct NodeTransitionHook(pub fn(&mut World, &str, &str) -> Option<String>);
+
+/// Decides what happens to a command, given its name and arguments, before it runs.
+pub type CommandInterceptorFn = dyn Fn(&str, &[String]) -> CommandAction + Send + Sync;
+
+/// See [`RegisterDialogueCommandExt::set_command_interceptor`].
+pub struct DialogueCommandInterceptor(pub Box<CommandInterceptorFn>);
+
+/// What a [`DialogueCommandInterceptor`] wants done with a command.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum CommandAction {
+    #[default]
+    Run,
+    /// Drop the command without running it.
+    Skip,
+    /// Run a different command, or the same one with different arguments.
+    Replace { name: String, args: Vec<String> },
+}
+
+#[derive(Deref, DerefMut, Default)]
+pub struct DialogueFunctions(HashMap<String, DialogueFunctionHandler>);
+
+/// A dialogue function closure that can capture state.
+pub type BoxedDialogueFunction = dyn Fn(&mut World, Vec<YarnValue>) -> YarnValue + Send + Sync;
+
+/// A registered function: a plain function, or a closure registered with
+/// [`RegisterDialogueCommandExt::register_boxed_dialogue_function`].
+#[derive(Clone)]
+pub enum DialogueFunctionHandler {
+    Fn(fn(&mut World, Vec<YarnValue>) -> YarnValue),
+    Boxed(Arc<BoxedDialogueFunction>),
+}
+
+impl DialogueFunctionHandler {
+    pub fn call(&self, world: &mut World, args: Vec<YarnValue>) -> YarnValue {
+        match self {
+            DialogueFunctionHandler::Fn(function) => function(world, args),
+            DialogueFunctionHandler::Boxed(function) => function(world, args),
+        }
+    }
+}
+
+/// Named holds on the dialogue. The runner doesn't advance while any are held, so independent
+/// systems (a cutscene, a modal) can each hold it without releasing the other's.
+#[derive(Debug, Default)]
+pub struct DialogueHolds(HashSet<String>);
+
+/// The [`DialogueHolds`] key used while blocking commands are pending.
+pub const BLOCKING_COMMAND_HOLD: &str = "blocking_command";
+
+impl DialogueHolds {
+    pub fn acquire<I: Into<String>>(&mut self, key: I) {
+        self.0.insert(key.into());
+    }
+
+    /// Releases `key`, returning whether it was held.
+    pub fn release(&mut self, key: &str) -> bool {
+        self.0.remove(key)
+    }
+
+    pub fn is_held(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+/// Identifies a running blocking command. See
+/// [`RegisterDialogueCommandExt::register_blocking_dialogue_command`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DialogueCommandToken(u64);
+
+/// Blocking commands that haven't completed yet. While any are pending the dialogue is held.
+#[derive(Default)]
+pub struct BlockingDialogueCommands {
+    next_token: u64,
+    pending: HashSet<DialogueCommandToken>,
+    holding: bool,
+}
+
+impl BlockingDialogueCommands {
+    /// Starts tracking a blocking command, returning the token to complete it with.
+    pub fn begin(&mut self) -> DialogueCommandToken {
+        let token = DialogueCommandToken(self.next_token);
+        self.next_token += 1;
+        self.pending.insert(token);
+        token
+    }
+
+    /// Marks the command finished. The dialogue resumes once no blocking commands are pending.
+    pub fn complete_command(&mut self, token: DialogueCommandToken) {
+        self.pending.remove(&token);
+    }
+
+    pub fn is_pending(&self, token: DialogueCommandToken) -> bool {
+        self.pending.contains(&token)
+    }
+}
+
+/// The line the primary runner is presenting, if any. A flat view of [`DialogueRunner::state`]
+/// refreshed each frame after the runner updates.
+#[derive(Default, Deref, Reflect)]
+pub struct CurrentDialogueLine(pub Option<PresentedLine>);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Reflect, FromReflect)]
+pub struct PresentedLine {
+    pub speaker: Option<String>,
+    pub text: String,
+    pub line_id: String,
+    pub markup: Vec<MarkupAttribute>,
+    /// The line's clip from the [`VoiceOverTable`], if it has one.
+    #[serde(skip)]
+    #[reflect(ignore)]
+    pub voice: Option<HandleUntyped>,
+}
+
+/// The options the primary runner is waiting on, if any.
+#[derive(Default, Deref, Reflect)]
+pub struct CurrentDialogueOptions(pub Option<Vec<DialogueOption>>);
+
+/// Game-supplied values for named placeholders like `{player_name}` in line text. Named
+/// placeholders are resolved before numeric ones; unknown names are left as written.
+#[derive(Default, Deref, DerefMut, Reflect)]
+pub struct SubstitutionProvider(pub HashMap<String, String>);
+
+/// Maps line IDs to voice-over audio assets. When present, the clip for each presented line is
+/// loaded through the [`AssetServer`] and carried by [`EventLinePresented`] and
+/// [`CurrentDialogueLine`] as an untyped handle, so this crate doesn't need `bevy_audio`; call
+/// `.typed::<AudioSource>()` on it to play it.
+#[derive(Debug, Clone, Default)]
+pub struct VoiceOverTable {
+    /// Audio asset path by line ID.
+    pub clips: HashMap<String, String>,
+    /// Path pattern for lines missing from `clips`, with `{id}` replaced by the line ID, e.g.
+    /// `voice/{id}.ogg`. Paths built from it aren't checked before loading.
+    pub convention: Option<String>,
+}
+
+impl VoiceOverTable {
+    /// Resolves every line by `pattern`. See [`VoiceOverTable::convention`].
+    pub fn by_convention(pattern: impl Into<String>) -> Self {
+        Self {
+            clips: HashMap::default(),
+            convention: Some(pattern.into()),
+        }
+    }
+
+    /// Reads `line_id,audio_path` rows.
+    pub fn from_csv(reader: impl std::io::Read, dialect: CsvDialect) -> Result<Self, csv::Error> {
+        let clips = dialect
+            .reader_builder()
+            .from_reader(reader)
+            .deserialize::<(String, String)>()
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            clips,
+            convention: None,
+        })
+    }
+
+    pub fn clip_path(&self, line_id: &str) -> Option<String> {
+        self.clips.get(line_id).cloned().or_else(|| {
+            self.convention
+                .as_ref()
+                .map(|pattern| pattern.replace("{id}", line_id))
+        })
+    }
+}
+
+/// Transcript of presented lines, chosen options and node boundaries, for backlog UIs. Once
+/// `max_entries` is reached the oldest entries are evicted.
+#[derive(Reflect)]
+pub struct DialogueHistory {
+    pub entries: Vec<HistoryEntry>,
+    pub max_entries: usize,
+}
+
+impl Default for DialogueHistory {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            max_entries: 500,
+        }
+    }
+}
+
+impl DialogueHistory {
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.entries.push(entry);
+        if self.entries.len() > self.max_entries {
+            let excess = self.entries.len() - self.max_entries;
+            self.entries.drain(..excess);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Reflect, FromReflect)]
+#[reflect_value(PartialEq)]
+pub enum HistoryEntry {
+    Line {
+        speaker: Option<String>,
+        text: String,
+        line_id: String,
+    },
+    OptionChosen {
+        text: String,
+        index: usize,
+    },
+    NodeBoundary {
+        name: String,
+    },
+}
+// *****************************************************************************************
+// Values
+// *****************************************************************************************
+/// A Yarn value as seen by variables, dialogue functions and command arguments.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum YarnValue {
+    String(String),
+    Number(f32),
+    Bool(bool),
+    Null,
+}
+
+/// The type of a non-null [`YarnValue`], as a variable is declared with. See
+/// [`SharedVariableStorage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YarnValueType {
+    String,
+    Number,
+    Bool,
+}
+
+impl YarnValue {
+    pub fn value_type(&self) -> Option<YarnValueType> {
+        match self {
+            YarnValue::String(_) => Some(YarnValueType::String),
+            YarnValue::Number(_) => Some(YarnValueType::Number),
+            YarnValue::Bool(_) => Some(YarnValueType::Bool),
+            YarnValue::Null => None,
+        }
+    }
+
+    /// Classifies a bare argument: `true`/`false` become bools, anything that parses as a float
+    /// becomes a number, `null` is null and everything else is a string.
+    pub fn parse(text: &str) -> Self {
+        match text {
+            "true" => YarnValue::Bool(true),
+            "false" => YarnValue::Bool(false),
+            "null" => YarnValue::Null,
+            _ => match text.parse::<f32>() {
+                Ok(n) => YarnValue::Number(n),
+                Err(_) => YarnValue::String(text.to_string()),
+            },
+        }
+    }
+
+    /// Converts the value to a number the way Yarn does, treating `true` as 1 and unparsable
+    /// strings and null as 0.
+    pub fn as_number(&self) -> f32 {
+        match self {
+            YarnValue::String(s) => s.parse().unwrap_or_default(),
+            YarnValue::Number(n) => *n,
+            YarnValue::Bool(b) => *b as u8 as f32,
+            YarnValue::Null => 0.0,
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        match self {
+            YarnValue::String(s) => !s.is_empty(),
+            YarnValue::Number(n) => *n != 0.0,
+            YarnValue::Bool(b) => *b,
+            YarnValue::Null => false,
+        }
+    }
+}
+
+impl fmt::Display for YarnValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YarnValue::String(s) => write!(f, "{}", s),
+            YarnValue::Number(n) => write!(f, "{}", n),
+            YarnValue::Bool(b) => write!(f, "{}", b),
+            YarnValue::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl From<String> for YarnValue {
+    fn from(value: String) -> Self {
+        YarnValue::String(value)
+    }
+}
+
+impl From<&str> for YarnValue {
+    fn from(value: &str) -> Self {
+        YarnValue::String(value.to_string())
+    }
+}
+
+impl From<f32> for YarnValue {
+    fn from(value: f32) -> Self {
+        YarnValue::Number(value)
+    }
+}
+
+impl From<bool> for YarnValue {
+    fn from(value: bool) -> Self {
+        YarnValue::Bool(value)
+    }
+}
+
+impl TryFrom<YarnValue> for String {
+    type Error = YarnValue;
+
+    fn try_from(value: YarnValue) -> Result<Self, Self::Error> {
+        match value {
+            YarnValue::String(s) => Ok(s),
+            other => Err(other),
+        }
+    }
+}
+
+impl TryFrom<YarnValue> for f32 {
+    type Error = YarnValue;
+
+    fn try_from(value: YarnValue) -> Result<Self, Self::Error> {
+        match value {
+            YarnValue::Number(n) => Ok(n),
+            other => Err(other),
+        }
+    }
+}
+
+impl TryFrom<YarnValue> for bool {
+    type Error = YarnValue;
+
+    fn try_from(value: YarnValue) -> Result<Self, Self::Error> {
+        match value {
+            YarnValue::Bool(b) => Ok(b),
+            other => Err(other),
+        }
+    }
+}
+
+impl From<yharnam::YarnValue> for YarnValue {
+    fn from(value: yharnam::YarnValue) -> Self {
+        match value {
+            yharnam::YarnValue::Str(s) => YarnValue::String(s),
+            yharnam::YarnValue::Number(n) => YarnValue::Number(n),
+            yharnam::YarnValue::Bool(b) => YarnValue::Bool(b),
+            yharnam::YarnValue::Null => YarnValue::Null,
+        }
+    }
+}
+
+impl From<YarnValue> for yharnam::YarnValue {
+    fn from(value: YarnValue) -> Self {
+        match value {
+            YarnValue::String(s) => yharnam::YarnValue::Str(s),
+            YarnValue::Number(n) => yharnam::YarnValue::Number(n),
+            YarnValue::Bool(b) => yharnam::YarnValue::Bool(b),
+            YarnValue::Null => yharnam::YarnValue::Null,
+        }
+    }
+}
+
+/// Typed access to command arguments.
+pub trait DialogueArgumentsExt {
+    /// Classifies each argument with [`YarnValue::parse`].
+    fn parsed(&self) -> Vec<YarnValue>;
+}
+
+impl DialogueArgumentsExt for [String] {
+    fn parsed(&self) -> Vec<YarnValue> {
+        self.iter().map(|arg| YarnValue::parse(arg)).collect()
+    }
+}
+
+// *****************************************************************************************
+// Variable Storage
+// *****************************************************************************************
+/// Backing store for Yarn `$variables`. Implement this to keep dialogue variables in your own
+/// game state.
+pub trait VariableStorage: Send + Sync {
+    fn get(&self, name: &str) -> Option<YarnValue>;
+    fn set(&mut self, name: &str, value: YarnValue);
+    /// Every stored variable, used when saving dialogue state.
+    fn all(&self) -> HashMap<String, YarnValue>;
+    /// Forgets every variable. The default sets each to null; storages that can remove entries
+    /// should override it.
+    fn clear(&mut self) {
+        for name in self.all().keys().cloned().collect::<Vec<_>>() {
+            self.set(&name, YarnValue::Null);
+        }
+    }
+}
+
+#[derive(Default, Deref, DerefMut)]
+pub struct HashMapVariableStorage(HashMap<String, YarnValue>);
+
+impl VariableStorage for HashMapVariableStorage {
+    fn get(&self, name: &str) -> Option<YarnValue> {
+        self.0.get(name).cloned()
+    }
+
+    fn set(&mut self, name: &str, value: YarnValue) {
+        self.0.insert(name.to_string(), value);
+    }
+
+    fn all(&self) -> HashMap<String, YarnValue> {
+        self.0.clone()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Handle to the active [`VariableStorage`]. The virtual machine keeps its own variable map,
+/// which is loaded from this storage before each step and written back afterwards.
+///
+/// Type checking only covers variables with a declared type: those the running program
+/// declares with a default, and any given to [`SharedVariableStorage::declare_types`].
+/// Assignments to other variables are never checked.
+#[derive(Clone)]
+pub struct SharedVariableStorage {
+    storage: Arc<RwLock<Box<dyn VariableStorage>>>,
+    types: Arc<RwLock<VariableTypes>>,
+}
+
+impl SharedVariableStorage {
+    pub fn new(storage: Box<dyn VariableStorage>) -> Self {
+        Self {
+            storage: Arc::new(RwLock::new(storage)),
+            types: Arc::default(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<YarnValue> {
+        self.storage.read().unwrap().get(name)
+    }
+
+    pub fn set(&self, name: &str, value: YarnValue) {
+        self.storage.write().unwrap().set(name, value);
+    }
+
+    pub fn all(&self) -> HashMap<String, YarnValue> {
+        self.storage.read().unwrap().all()
+    }
+
+    pub fn clear(&self) {
+        self.storage.write().unwrap().clear();
+    }
+
+    /// Swaps in `storage` for every holder of this handle.
+    pub fn replace(&self, storage: Box<dyn VariableStorage>) {
+        *self.storage.write().unwrap() = storage;
+    }
+
+    pub fn set_type_checking(&self, checking: VariableTypeChecking, coerce_number_bool: bool) {
+        let mut types = self.types.write().unwrap();
+        types.checking = checking;
+        types.coerce_number_bool = coerce_number_bool;
+    }
+
+    /// The checking mode and whether numbers and bools coerce, as set by
+    /// [`SharedVariableStorage::set_type_checking`].
+    pub fn type_checking(&self) -> (VariableTypeChecking, bool) {
+        let types = self.types.read().unwrap();
+        (types.checking, types.coerce_number_bool)
+    }
+
+    /// Replaces the declared variable types. A program's own `<<declare>>`d variables are added
+    /// when it starts, so this is for variables the script assigns without declaring them.
+    pub fn declare_types(&self, declared: HashMap<String, YarnValueType>) {
+        self.types.write().unwrap().declared = declared;
+    }
+
+    /// Declares each variable in `initial_values` with the type of its default.
+    fn declare_initial_types(&self, initial_values: &HashMap<String, YarnValue>) {
+        let mut types = self.types.write().unwrap();
+        for (name, value) in initial_values {
+            if let Some(value_type) = value.value_type() {
+                types.declared.insert(name.clone(), value_type);
+            }
+        }
+    }
+
+    pub fn declared_type(&self, name: &str) -> Option<YarnValueType> {
+        self.types.read().unwrap().declared.get(name).copied()
+    }
+
+    /// Mismatches found since the last call, for sending as [`EventTypeMismatch`]es.
+    pub fn take_type_mismatches(&self) -> Vec<EventTypeMismatch> {
+        std::mem::take(&mut self.types.write().unwrap().mismatches)
+    }
+
+    /// The value to store for a script assignment, or `None` if strict checking rejects it.
+    fn check_assignment(&self, name: &str, value: YarnValue) -> Option<YarnValue> {
+        let mut types = self.types.write().unwrap();
+        if types.checking == VariableTypeChecking::Off {
+            return Some(value);
+        }
+        let (expected, got) = match (types.declared.get(name), value.value_type()) {
+            (Some(expected), Some(got)) if *expected != got => (*expected, got),
+            _ => return Some(value),
+        };
+        if types.coerce_number_bool {
+            match (expected, &value) {
+                (YarnValueType::Bool, YarnValue::Number(n)) => {
+                    return Some(YarnValue::Bool(*n != 0.0));
+                }
+                (YarnValueType::Number, YarnValue::Bool(b)) => {
+                    return Some(YarnValue::Number(if *b { 1.0 } else { 0.0 }));
+                }
+                _ => {}
+            }
+        }
+        types.mismatches.push(EventTypeMismatch {
+            variable: name.to_string(),
+            expected,
+            got,
+        });
+        match types.checking {
+            VariableTypeChecking::Strict => {
+                error!("Rejected {:?} assignment to ${} declared as {:?}", got, name, expected);
+                None
+            }
+            _ => {
+                warn!("Assigned {:?} to ${} declared as {:?}", got, name, expected);
+                Some(value)
+            }
+        }
+    }
+
+    /// Replaces the VM's variable map with the stored values and returns a copy of what was
+    /// loaded, for [`SharedVariableStorage::store_from`].
+    fn load_into(&self, vm: &mut VirtualMachine) -> VmVariables {
+        vm.variable_storage = self
+            .all()
+            .into_iter()
+            .map(|(name, value)| (name, value.into()))
+            .collect();
+        vm.variable_storage.clone()
+    }
+
+    /// Writes back the variables the script assigned since `loaded`. Values set from Rust in
+    /// the meantime, e.g. by a dialogue function, are kept unless the script overwrote them.
+    fn store_from(&self, vm: &VirtualMachine, loaded: &VmVariables) {
+        for (name, value) in vm.variable_storage.iter() {
+            if loaded.get(name) == Some(value) {
+                continue;
+            }
+            if let Some(value) = self.check_assignment(name, value.clone().into()) {
+                self.set(name, value);
+            }
+        }
+    }
+}
+
+/// The virtual machine's own variable map.
+type VmVariables = std::collections::HashMap<String, yharnam::YarnValue>;
+
+/// What happens when the script assigns a value whose type doesn't match the variable's
+/// declaration. Values set from Rust aren't checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VariableTypeChecking {
+    #[default]
+    Off,
+    /// Store the value anyway and send an [`EventTypeMismatch`].
+    Lenient,
+    /// Keep the old value and send an [`EventTypeMismatch`].
+    Strict,
+}
+
+#[derive(Default)]
+struct VariableTypes {
+    checking: VariableTypeChecking,
+    coerce_number_bool: bool,
+    declared: HashMap<String, YarnValueType>,
+    mismatches: Vec<EventTypeMismatch>,
+}
+
+// *****************************************************************************************
+// Save State
+// *****************************************************************************************
+/// Serializable snapshot of an in-progress conversation, produced by
+/// [`DialogueRunner::save_state`].
+///
+/// The snapshot does not contain the program itself; restore it onto a runner that has the
+/// same program loaded. It records the VM's position, stack and pending options, so saving
+/// while options await selection presents them again on restore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DialogueSaveState {
+    pub program_path: Option<PathBuf>,
+    pub current_node: Option<String>,
+    pub program_counter: isize,
+    pub stack: Vec<YarnValue>,
+    /// Options added by the node so far, or awaiting selection.
+    pub current_options: Vec<SavedOption>,
+    pub waiting_on_options: bool,
+    pub variables: HashMap<String, YarnValue>,
+    pub visited_counts: HashMap<String, u32>,
+    pub current_line: Option<SavedLine>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedLine {
+    pub id: String,
+    pub substitutions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedOption {
+    pub line: SavedLine,
+    pub destination_node: String,
+    /// Whether the option's `<<if>>` condition passed, for options awaiting selection.
+    #[serde(default = "option_available")]
+    pub is_available: bool,
+}
+
+fn option_available() -> bool {
+    true
+}
+
+impl DialogueRunner {
+    pub fn save_state(&self) -> DialogueSaveState {
+        // Conditions were popped off the stack when the options were presented, and hidden
+        // options are the unavailable ones.
+        let is_available = |index: usize| match &self.state {
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(options)) => options
+                .iter()
+                .any(|option| option.index == index && option.is_available),
+            _ => true,
+        };
+        DialogueSaveState {
+            program_path: self.program_path.clone(),
+            current_node: self.current_node.clone(),
+            program_counter: self.vm.state.program_counter,
+            stack: self.vm.state.stack.iter().cloned().map(Into::into).collect(),
+            current_options: self
+                .vm
+                .state
+                .current_options
+                .iter()
+                .enumerate()
+                .map(|(index, (line, destination_node))| SavedOption {
+                    line: SavedLine {
+                        id: line.id.clone(),
+                        substitutions: line.substitutions.clone(),
+                    },
+                    destination_node: destination_node.clone(),
+                    is_available: is_available(index),
+                })
+                .collect(),
+            waiting_on_options: matches!(
+                self.vm.execution_state,
+                ExecutionState::WaitingOnOptionSelection
+            ),
+            variables: self.variables.all(),
+            visited_counts: self.visited_counts.clone(),
+            current_line: self.current_line.as_ref().map(|line| SavedLine {
+                id: line.id.clone(),
+                substitutions: line.substitutions.clone(),
+            }),
+        }
+    }
+
+    /// Restores a snapshot taken by [`DialogueRunner::save_state`]. The current line or pending
+    /// options, if any, are presented again and `update_runner` resumes from the saved position.
+    pub fn restore_state(&mut self, save: DialogueSaveState) {
+        for (name, value) in save.variables {
+            self.variables.set(&name, value);
+        }
+        self.visited_counts = save.visited_counts;
+        self.current_node = None;
+        self.current_line = None;
+        self.state = DialogueRunnerState::Idle;
+
+        let node = match save.current_node {
+            Some(node) if self.vm.program.nodes.contains_key(&node) => node,
+            _ => return,
+        };
+        self.vm.set_node(&node);
+        self.vm.state.program_counter = save.program_counter;
+        self.vm.state.stack = save.stack.into_iter().map(Into::into).collect();
+        let available: Vec<bool> = save
+            .current_options
+            .iter()
+            .map(|option| option.is_available)
+            .collect();
+        self.vm.state.current_options = save
+            .current_options
+            .into_iter()
+            .map(|option| {
+                let line = Line {
+                    id: option.line.id,
+                    substitutions: option.line.substitutions,
+                };
+                (line, option.destination_node)
+            })
+            .collect();
+        self.current_node = Some(node);
+
+        if save.waiting_on_options {
+            let mut options: Vec<_> = self
+                .vm
+                .state
+                .current_options
+                .iter()
+                .zip(available)
+                .enumerate()
+                .map(|(index, ((line, _), is_available))| DialogueOption {
+                    text: unescape_text(
+                        &self
+                            .resolve_line(line)
+                            .unwrap_or_else(|| missing_line_placeholder(&line.id)),
+                    ),
+                    line_id: line.id.clone(),
+                    index,
+                    is_available,
+                })
+                .collect();
+            if self.option_policy == OptionPresentationPolicy::HideUnavailable {
+                options.retain(|option| option.is_available);
+            }
+            self.vm.execution_state = ExecutionState::WaitingOnOptionSelection;
+            let entry = DialogueRunningCurrentEntry::Options(options);
+            self.state = DialogueRunnerState::Running(entry);
+            return;
+        }
+
+        let line = save.current_line.map(|line| Line {
+            id: line.id,
+            substitutions: line.substitutions,
+        });
+        let entry = match line.as_ref().and_then(|line| self.resolve_line(line)) {
+            Some(subs) => DialogueRunningCurrentEntry::Text(DialogueLine::parse(&subs)),
+            None => DialogueRunningCurrentEntry::Null,
+        };
+        self.current_line = line;
+        self.state = DialogueRunnerState::Running(entry);
+    }
+
+    /// Snapshots the runner while `options` are awaiting selection, evicting the oldest
+    /// checkpoint once `max_choice_checkpoints` is reached. Called whenever options are
+    /// presented.
+    pub fn checkpoint_at_options(&mut self, options: Vec<DialogueOption>) {
+        if self.max_choice_checkpoints == 0 {
+            return;
+        }
+        while self.choice_checkpoints.len() >= self.max_choice_checkpoints {
+            self.choice_checkpoints.pop_front();
+        }
+        let checkpoint = ChoiceCheckpoint {
+            save: self.save_state(),
+            options,
+            vm_options: self.vm.state.current_options.clone(),
+        };
+        self.choice_checkpoints.push_back(checkpoint);
+    }
+
+    /// Starts the countdown for newly presented `options`, returning it if they're timed. An
+    /// option line tagged `timeout:<seconds>` makes the set timed with that option as the
+    /// default, taking precedence over [`DialogueRunner::option_timeout`].
+    pub fn start_option_timeout(&mut self, options: &[DialogueOption]) -> Option<OptionTimeout> {
+        let tagged = options.iter().find_map(|option| {
+            self.line_metadata(&option.line_id)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|tag| tag.trim_start_matches('#').strip_prefix("timeout:"))
+                .find_map(|seconds| seconds.parse::<f32>().ok())
+                .filter(|seconds| *seconds >= 0.0)
+                .map(|seconds| OptionTimeout {
+                    duration: Duration::from_secs_f32(seconds),
+                    default_index: option.index,
+                })
+        });
+        let timeout = tagged.or(self.option_timeout);
+        self.option_timer = timeout.map(|timeout| (timeout, Timer::new(timeout.duration, false)));
+        timeout
+    }
+
+    /// Restores the newest choice checkpoint so its options await selection again. The
+    /// checkpoint is kept, so rewinding repeatedly returns to the same choice.
+    pub fn rewind_to_last_choice(&mut self) -> Option<Vec<DialogueOption>> {
+        let checkpoint = self.choice_checkpoints.back()?.clone();
+        self.restore_state(checkpoint.save);
+        self.current_node.as_ref()?;
+        self.current_line = None;
+        self.last_selected_option = None;
+        self.vm.state.current_options = checkpoint.vm_options;
+        self.vm.execution_state = ExecutionState::WaitingOnOptionSelection;
+        self.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(
+            checkpoint.options.clone(),
+        ));
+        Some(checkpoint.options)
+    }
+}
+
+/// The runner's state at a decision point, recorded by
+/// [`DialogueRunner::checkpoint_at_options`].
+#[derive(Debug, Clone)]
+pub struct ChoiceCheckpoint {
+    pub save: DialogueSaveState,
+    pub options: Vec<DialogueOption>,
+    vm_options: Vec<(Line, String)>,
+}
+
+/// Returns the dialogue to the most recent choice and presents its options again, with
+/// variables and visit counts as they were then. For QA and writers exploring branches.
+pub struct RewindToLastChoiceCommand;
+
+impl Command for RewindToLastChoiceCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        runner.wait = None;
+        let options = runner.rewind_to_last_choice();
+        match options {
+            Some(options) => {
+                let timeout = runner.start_option_timeout(&options);
+                send_dialogue_event(world, EventOptionsPresented { options, timeout });
+                send_dialogue_event(world, EventDialogueUpdated);
+            }
+            None => warn!("RewindToLastChoiceCommand: no choice to rewind to!"),
+        }
+    }
+}
+
+// *****************************************************************************************
+// Systems
+// *****************************************************************************************
+/// Labels for ordering around the dialogue's systems.
+///
+/// `CheckQueue` starts queued dialogue in `PostUpdate`; `Update` advances the runners in
+/// `PreUpdate` and sends [`EventDialogueUpdated`] and the other presentation events. Both are
+/// exclusive systems, which run at the start of their stage ahead of every parallel system
+/// there, so parallel systems in `PreUpdate` always see this frame's events. Changes that must
+/// land before the dialogue advances, such as writes to variable storage, belong in an earlier
+/// stage or in an exclusive system ordered `.before(DialogueSystemSet::Update)`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum DialogueSystemSet {
+    CheckQueue,
+    Update,
+}
+
+fn check_queue(world: &mut World) {
+    // Entries that can't start are dropped, so move straight on to the next one.
+    loop {
+        let queued = world.resource::<DialogueQueue>().len();
+        if try_start_next(world) || world.resource::<DialogueQueue>().len() >= queued {
+            break;
+        }
+    }
+}
+
+/// Starts the front queue entry if the runner is idle and the entry's assets have loaded,
+/// returning whether it started. An entry that isn't ready stays queued so `check_queue` can
+/// retry it next frame.
+fn try_start_next(world: &mut World) -> bool {
+    if world.resource::<DialogueRunner>().state != DialogueRunnerState::Idle
+        || world.resource::<DialogueQueue>().is_empty()
+    {
+        return false;
+    }
+    let asset_server = world.resource::<AssetServer>().clone();
+    world.resource_scope(|world, mut yarn_programs: Mut<Assets<YarnProgram>>| {
+        world.resource_scope(|world, mut yarn_tables: Mut<Assets<YarnStringTable>>| {
+            world.resource_scope(|world, mut yarn_metadata: Mut<Assets<YarnLineMetadata>>| {
+                let mut queue = world.resource_mut::<DialogueQueue>();
+                let failed = failed_queue_assets(&queue[0], &asset_server);
+                if !failed.is_empty() {
+                    let entry = &mut queue[0];
+                    if entry.load_retries < DIALOGUE_LOAD_RETRIES {
+                        entry.load_retries += 1;
+                        for id in failed {
+                            // The entry's own handles keep the reloaded asset alive.
+                            if let Some(path) = asset_server.get_handle_path(id) {
+                                let _ = asset_server.load_untyped(path);
+                            }
+                        }
+                        return false;
+                    }
+                    let path = queue.pop_front().unwrap().path;
+                    warn!("Dropping {:?} from the dialogue queue, its assets failed to load", path);
+                    send_dialogue_event(world, EventDialogueLoadFailed { path });
+                    return false;
+                }
+                if !is_queue_entry_ready(
+                    &queue[0],
+                    &asset_server,
+                    &yarn_programs,
+                    &yarn_tables,
+                    &yarn_metadata,
+                ) {
+                    return false;
+                }
+                if let Some(condition) = queue[0].condition.take() {
+                    if !condition(world) {
+                        let mut queue = world.resource_mut::<DialogueQueue>();
+                        let path = queue.pop_front().unwrap().path;
+                        debug!("Skipping {:?}, its queue condition no longer holds", path);
+                        send_dialogue_event(world, EventDialogueSkipped { path });
+                        return false;
+                    }
+                }
+                let mut queue = world.resource_mut::<DialogueQueue>();
+                let entry = queue
+                    .pop_front()
+                    .expect("setup_runner: Dialogue queue empty!");
+                debug!("Dequeued {:?}, {} entries left", entry.path, queue.len());
+
+                // Preloaded assets stay resident for the next time they're queued.
+                let preloaded = world
+                    .get_resource::<DialoguePreloadCache>()
+                    .is_some_and(|cache| cache.contains(&entry.path))
+                    || world
+                        .get_resource::<DialogueProgramCache>()
+                        .is_some_and(|cache| cache.contains(&entry.path));
+                // Only dialogue loaded from disk can be found again by path.
+                let cache_language = entry
+                    .language
+                    .as_ref()
+                    .map(|language| language.current.clone());
+                let mut runner = world.resource_mut::<DialogueRunner>();
+                let cache_language = cache_language.filter(|_| runner.cache_programs);
+                let keep_assets = runner.hot_reload || preloaded || cache_language.is_some();
+                match resolve_queue_entry(
+                    entry,
+                    keep_assets,
+                    &asset_server,
+                    &mut yarn_programs,
+                    &mut yarn_tables,
+                    &mut yarn_metadata,
+                ) {
+                    Some(mut resolved) => {
+                        let unsupported = unsupported_program_event(
+                            Some(resolved.path.clone()),
+                            &resolved.program.program,
+                        );
+                        if let Some(unsupported) = unsupported {
+                            let reason = IdleReason::Failed {
+                                reason: format!("unsupported opcodes {:?}", unsupported.opcodes),
+                            };
+                            // The entry never started, so there's nothing to complete.
+                            runner.idle_reason = reason;
+                            send_dialogue_event(world, unsupported);
+                            return false;
+                        }
+                        let language_fallback = resolved.language_fallback.take();
+                        if let Err(missing) = runner.setup(resolved) {
+                            let reason = runner.idle_reason.clone();
+                            warn!(
+                                "Start node {} does not exist! Available nodes: {:?}",
+                                missing.requested, missing.available
+                            );
+                            send_dialogue_event(world, missing);
+                            let completed = EventDialogueCompleted(String::new(), reason);
+                            send_dialogue_event(world, completed);
+                            return false;
+                        }
+                        let cache_entry = cache_language.zip(runner.asset_handles.clone());
+                        let path = runner.program_path.clone().unwrap_or_default();
+                        let report = match runner.validate_on_load {
+                            true => runner.validate_line_ids(),
+                            false => None,
+                        };
+                        if let Some((language, handles)) = cache_entry {
+                            world
+                                .resource_mut::<DialogueProgramCache>()
+                                .insert(path, language, handles);
+                        }
+                        if let Some(report) = report {
+                            send_dialogue_event(world, report);
+                        }
+                        if let Some(language_fallback) = language_fallback {
+                            send_dialogue_event(world, language_fallback);
+                        }
+                        send_dialogue_started(world);
+                        true
+                    }
+                    None => false,
+                }
+            })
+        })
+    })
+}
+
+/// Checks `program` for opcodes the VM doesn't know, which it would otherwise panic on
+/// partway through the dialogue.
+fn unsupported_program_event(
+    path: Option<PathBuf>,
+    program: &Program,
+) -> Option<EventUnsupportedProgram> {
+    let mut opcodes: Vec<i32> = program
+        .nodes
+        .values()
+        .flat_map(|node| node.instructions.iter())
+        .filter(|instruction| instruction.opcode_enum().is_none())
+        .map(|instruction| instruction.opcode)
+        .collect();
+    if opcodes.is_empty() {
+        return None;
+    }
+    opcodes.sort_unstable();
+    opcodes.dedup();
+    error!(
+        "Program {:?} uses opcodes this VM doesn't support: {:?}. Was it built with a newer compiler?",
+        program.name, opcodes
+    );
+    Some(EventUnsupportedProgram {
+        path,
+        program_name: program.name.clone(),
+        opcodes,
+    })
+}
+
+/// Gives the [`NodeTransitionHook`] a chance to send a node change somewhere else, moving the
+/// VM to the new destination. Returns the node the dialogue ends up in.
+fn redirect_node_transition(world: &mut World, from: &str, to: String) -> String {
+    let hook = match world.get_resource::<NodeTransitionHook>() {
+        Some(hook) => hook.0,
+        None => return to,
+    };
+    let target = match hook(world, from, &to) {
+        Some(target) if target != to => target,
+        _ => return to,
+    };
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    if !runner.vm.program.nodes.contains_key(&target) {
+        warn!("Node transition hook redirected to missing node {}!", target);
+        let available = runner.vm.program.nodes.keys().cloned().collect();
+        send_dialogue_event(world, EventMissingNode {
+            requested: target,
+            available,
+        });
+        return to;
+    }
+    runner.vm.set_node(&target);
+    target
+}
+
+/// Fires [`EventDialogueStarted`] for the runner's freshly set up program, if its start node
+/// was found.
+fn send_dialogue_started(world: &mut World) {
+    let runner = world.resource::<DialogueRunner>();
+    if let (Some(path), Some(start_node)) = (runner.program_path.clone(), runner.current_node.clone()) {
+        notify_observers(world, |observer| observer.on_node(&start_node));
+        send_dialogue_event(world, EventDialogueStarted { path, start_node });
+    }
+}
+
+fn update_runner(world: &mut World) {
+    let node = world.resource::<DialogueRunner>().current_node.clone().unwrap_or_default();
+    let _span = debug_span!("update_runner", node = %node).entered();
+    step_dialogue(world);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DialogueStep {
+    Line,
+    Options,
+    Command,
+    NodeChange,
+    Complete,
+}
+
+/// Runs the VM until it next suspends and presents the result. Returns `None` if the runner
+/// had nothing to run.
+fn step_dialogue(world: &mut World) -> Option<DialogueStep> {
+    let named_substitutions = world
+        .get_resource::<SubstitutionProvider>()
+        .map(|provider| provider.0.clone());
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    if let Some(named_substitutions) = named_substitutions {
+        runner.named_substitutions = named_substitutions;
+    }
+    if runner.state == DialogueRunnerState::Idle
+        || runner.paused
+        || runner.wait.is_some()
+        || matches!(runner.vm.execution_state, ExecutionState::WaitingOnOptionSelection)
+    {
+        return None;
+    }
+    if (runner.line_advance_mode == LineAdvanceMode::Manual || runner.auto_advance.is_some())
+        && !runner.is_skipping_seen()
+        && matches!(runner.state, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(_)))
+    {
+        return None;
+    }
+
+    // Detach the VM while it runs so dialogue functions can reach the rest of the world,
+    // including the runner itself.
+    let mut vm = std::mem::replace(&mut runner.vm, VirtualMachine::new(Program::default()));
+    let variables = runner.variables.clone();
+    let loaded = variables.load_into(&mut vm);
+    let reason = with_dialogue_world(world, || vm.continue_dialogue());
+    variables.store_from(&vm, &loaded);
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    runner.vm = vm;
+    for mismatch in runner.variables.take_type_mismatches() {
+        send_dialogue_event(world, mismatch);
+    }
+    let mut runner = world.resource_mut::<DialogueRunner>();
+
+    let (step, next_selection) = match reason {
+        SuspendReason::Line(line) => {
+            trace!("Presenting line {}", line.id);
+            runner.auto_advance = match runner.is_skipping_seen() {
+                true => None,
+                false => runner.auto_advance_delay(&line.id).map(|delay| Timer::new(delay, false)),
+            };
+            if let Some(subs) = runner.resolve_line(&line) {
+                runner.current_line = Some(line.clone());
+                let dialogue_line = DialogueLine::parse(&subs);
+                let metadata = runner.line_metadata(&line.id).unwrap_or_default().to_vec();
+                let history_entry = HistoryEntry::Line {
+                    speaker: dialogue_line.speaker.clone(),
+                    text: dialogue_line.text.clone(),
+                    line_id: line.id.clone(),
+                };
+                world.resource_mut::<DialogueHistory>().push(history_entry);
+                let triggers = inline_triggers(&dialogue_line.attributes);
+                let voice = load_voice_over(world, &line.id);
+                let event = EventLinePresented(
+                    subs,
+                    line.id.clone(),
+                    metadata,
+                    triggers,
+                    voice,
+                    dialogue_line.clone(),
+                );
+                send_dialogue_event(world, event);
+                send_dialogue_event(world, EventDialogueUpdated);
+                notify_observers(world, |observer| observer.on_line(&line.id, &dialogue_line));
+                (DialogueStep::Line, DialogueRunningCurrentEntry::Text(dialogue_line))
+            }
+            else {
+                warn!("Unable to find line {}!", line.id);
+                runner.current_line = Some(line.clone());
+                let metadata = runner.line_metadata(&line.id).unwrap_or_default().to_vec();
+                send_dialogue_event(world, EventMissingLine { line_id: line.id.clone() });
+                let placeholder = missing_line_placeholder(&line.id);
+                let voice = load_voice_over(world, &line.id);
+                let dialogue_line = DialogueLine {
+                    speaker: None,
+                    text: placeholder.clone(),
+                    attributes: Vec::new(),
+                };
+                let event = EventLinePresented(
+                    placeholder,
+                    line.id.clone(),
+                    metadata,
+                    Vec::new(),
+                    voice,
+                    dialogue_line.clone(),
+                );
+                send_dialogue_event(world, event);
+                send_dialogue_event(world, EventDialogueUpdated);
+                notify_observers(world, |observer| observer.on_line(&line.id, &dialogue_line));
+                (DialogueStep::Line, DialogueRunningCurrentEntry::Text(dialogue_line))
+            }
+        }
+        SuspendReason::Options(new_options) => {
+            runner.last_selected_option = None;
+            let mut o = Vec::new();
+            let mut missing = Vec::new();
+            let available = take_option_conditions(&mut runner.vm, &new_options);
+            for (index, (opt, is_available)) in new_options.iter().zip(available).enumerate() {
+                let t = match runner.resolve_line(&opt.line) {
+                    Some(t) => t,
+                    None => {
+                        missing.push(opt.line.id.clone());
+                        missing_line_placeholder(&opt.line.id)
+                    }
+                };
+                o.push(DialogueOption {
+                    text: unescape_text(&t),
+                    line_id: opt.line.id.clone(),
+                    index,
+                    is_available,
+                });
+            }
+            if runner.option_policy == OptionPresentationPolicy::HideUnavailable {
+                o.retain(|option| option.is_available);
+            }
+            runner.checkpoint_at_options(o.clone());
+            let timeout = runner.start_option_timeout(&o);
+            for line_id in missing {
+                warn!("Unable to find option line {}!", line_id);
+                send_dialogue_event(world, EventMissingLine { line_id });
+            }
+            send_dialogue_event(world, EventOptionsPresented {
+                options: o.clone(),
+                timeout,
+            });
+            send_dialogue_event(world, EventDialogueUpdated);
+            (DialogueStep::Options, DialogueRunningCurrentEntry::Options(o))
+        }
+        SuspendReason::Command(command_text) => {
+            let commands = match runner.command_separator {
+                Some(separator) => split_command_batch(&command_text, separator),
+                None => vec![command_text],
+            };
+            for command_text in commands {
+                let mut arguments = split_command_arguments(&command_text);
+                if arguments.is_empty() {
+                    continue;
+                }
+                let name = arguments.remove(0);
+                debug!("Dispatching command {} {:?}", name, arguments);
+                ExecuteDialogueCommand {
+                    command: name,
+                    args: arguments,
+                }
+                .write(world);
+            }
+            (DialogueStep::Command, DialogueRunningCurrentEntry::Null)
+        },
+        SuspendReason::NodeChange { start, end } => {
+            let start = redirect_node_transition(world, &end, start);
+            debug!("Node {} jumped to {}", end, start);
+            let mut runner = world.resource_mut::<DialogueRunner>();
+            runner.mark_visited(&start);
+            runner.current_node = Some(start.clone());
+            let tags = runner.node_tags(&start).unwrap_or_default().to_vec();
+            world.resource_mut::<DialogueHistory>().push(HistoryEntry::NodeBoundary {
+                name: start.clone(),
+            });
+            send_dialogue_event(world, EventNodeCompleted(end));
+            notify_observers(world, |observer| observer.on_node(&start));
+            send_dialogue_event(world, EventNodeStarted(start, tags));
+            (DialogueStep::NodeChange, DialogueRunningCurrentEntry::Null)
+        },
+        SuspendReason::DialogueComplete(last_node) => {
+            debug!("Dialogue completed in node {}", last_node);
+            send_dialogue_event(world, EventNodeCompleted(last_node.clone()));
+            notify_observers(world, |observer| observer.on_complete(&last_node));
+            let reason = IdleReason::Completed {
+                last_node: last_node.clone(),
+            };
+            send_dialogue_event(world, EventDialogueCompleted(last_node, reason.clone()));
+            let mut runner = world.resource_mut::<DialogueRunner>();
+            runner.current_node = None;
+            runner.scoped_commands = None;
+            runner.idle_reason = reason;
+            runner.state = DialogueRunnerState::Idle;
+            try_start_next(world);
+            return Some(DialogueStep::Complete);
+        }
+    };
+
+    world.resource_mut::<DialogueRunner>().state = DialogueRunnerState::Running(next_selection);
+    Some(step)
+}
+
+type DialogueAssetEvents<'w, 's> = (
+    EventReader<'w, 's, AssetEvent<YarnProgram>>,
+    EventReader<'w, 's, AssetEvent<YarnStringTable>>,
+    EventReader<'w, 's, AssetEvent<YarnLineMetadata>>,
+);
+
+fn hot_reload_dialogue(
+    mut runner: ResMut<DialogueRunner>,
+    mut runners: ResMut<DialogueRunners>,
+    (mut program_events, mut table_events, mut metadata_events): DialogueAssetEvents,
+    yarn_programs: Res<Assets<YarnProgram>>,
+    yarn_tables: Res<Assets<YarnStringTable>>,
+    yarn_metadata: Res<Assets<YarnLineMetadata>>,
+) {
+    fn modified<T: Asset>(events: &mut EventReader<AssetEvent<T>>) -> HashSet<Handle<T>> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                AssetEvent::Modified { handle } => Some(handle.clone_weak()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    let modified = ModifiedDialogueAssets {
+        programs: modified(&mut program_events),
+        tables: modified(&mut table_events),
+        metadata: modified(&mut metadata_events),
+    };
+    let keyed = runners.values_mut().map(|keyed| &mut keyed.runner);
+    for runner in std::iter::once(&mut *runner).chain(keyed) {
+        hot_reload_runner(runner, &modified, &yarn_programs, &yarn_tables, &yarn_metadata);
+    }
+}
+
+struct ModifiedDialogueAssets {
+    programs: HashSet<Handle<YarnProgram>>,
+    tables: HashSet<Handle<YarnStringTable>>,
+    metadata: HashSet<Handle<YarnLineMetadata>>,
+}
+
+fn hot_reload_runner(
+    runner: &mut DialogueRunner,
+    modified: &ModifiedDialogueAssets,
+    yarn_programs: &Assets<YarnProgram>,
+    yarn_tables: &Assets<YarnStringTable>,
+    yarn_metadata: &Assets<YarnLineMetadata>,
+) {
+    let handles = match &runner.asset_handles {
+        Some(handles) => handles.clone(),
+        None => return,
+    };
+    // Metadata doesn't affect control flow, so it's swapped in without restarting the node.
+    if let Some(metadata) = handles
+        .metadata
+        .as_ref()
+        .filter(|handle| modified.metadata.contains(*handle))
+        .and_then(|handle| yarn_metadata.get(handle))
+    {
+        runner.line_metadata = metadata.0.clone();
+    }
+    let program_modified = modified.programs.contains(&handles.program);
+    let table_modified = modified.tables.contains(&handles.table)
+        || handles
+            .fallback_table
+            .as_ref()
+            .is_some_and(|fallback| modified.tables.contains(fallback));
+    if !program_modified && !table_modified {
+        return;
+    }
+
+    if let Some(program) = yarn_programs.get(&handles.program) {
+        runner.vm.program = program.program.clone();
+        runner.initial_values = program.initial_values.clone();
+        runner.seed_initial_values();
+    }
+    let table = yarn_tables
+        .get(&handles.table)
+        .map(|table| table.0.clone())
+        .unwrap_or_default();
+    let fallback_table = handles
+        .fallback_table
+        .as_ref()
+        .and_then(|fallback| yarn_tables.get(fallback))
+        .map(|table| table.0.clone())
+        .unwrap_or_default();
+    runner.set_tables(table, fallback_table);
+
+    // Restart the active node so the edits take effect.
+    if runner.state == DialogueRunnerState::Idle {
+        return;
+    }
+    match runner.current_node.clone() {
+        Some(node) if runner.vm.program.nodes.contains_key(&node) => {
+            info!("Hot reloaded dialogue, restarting node {}", node);
+            runner.vm.set_node(&node);
+            runner.current_line = None;
+            runner.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null);
+        }
+        _ => {
+            warn!("Hot reloaded dialogue no longer contains the running node, stopping");
+            runner.stop_vm();
+            runner.current_node = None;
+            runner.idle_reason = IdleReason::Failed {
+                reason: "hot reload removed the running node".to_string(),
+            };
+            runner.state = DialogueRunnerState::Idle;
+        }
+    }
+}
+
+fn sync_current_dialogue(
+    runner: Res<DialogueRunner>,
+    mut current_line: ResMut<CurrentDialogueLine>,
+    mut current_options: ResMut<CurrentDialogueOptions>,
+) {
+    if !runner.is_changed() {
+        return;
+    }
+    let (line, options) = match &runner.state {
+        DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(line)) => (
+            Some(PresentedLine {
+                speaker: line.speaker.clone(),
+                text: line.text.clone(),
+                line_id: runner
+                    .current_line
+                    .as_ref()
+                    .map(|line| line.id.clone())
+                    .unwrap_or_default(),
+                markup: line.attributes.clone(),
+                voice: runner.current_voice.clone(),
+            }),
+            None,
+        ),
+        DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(options)) => {
+            (None, Some(options.clone()))
+        }
+        _ => (None, None),
+    };
+    if current_line.0 != line {
+        current_line.0 = line;
+    }
+    if current_options.0 != options {
+        current_options.0 = options;
+    }
+}
+
+fn tick_dialogue_wait(
+    time: Res<Time>,
+    mut runner: ResMut<DialogueRunner>,
+    mut runners: ResMut<DialogueRunners>,
+) {
+    let keyed = runners.values_mut().map(|keyed| &mut keyed.runner);
+    for runner in std::iter::once(&mut *runner).chain(keyed) {
+        if let Some(wait) = &mut runner.wait {
+            if wait.tick(time.delta()).finished() {
+                runner.wait = None;
+            }
+        }
+    }
+}
+
+/// Advances the presented line once its auto-advance delay has elapsed. Doesn't tick while the
+/// dialogue is held.
+fn tick_line_auto_advance(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+    for_each_dialogue_runner(world, |world| advance_line_if_due(world, delta));
+}
+
+fn advance_line_if_due(world: &mut World, delta: Duration) {
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    if !matches!(runner.state, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(_))) {
+        return;
+    }
+    let finished = match runner.auto_advance.as_mut() {
+        Some(timer) => timer.tick(delta).finished(),
+        None => false,
+    };
+    if finished {
+        AdvanceDialogueCommand.write(world);
+    }
+}
+
+/// Picks the default option once a timed choice's countdown runs out.
+fn tick_option_timeout(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+    for_each_dialogue_runner(world, |world| select_default_option_if_due(world, delta));
+}
+
+fn select_default_option_if_due(world: &mut World, delta: Duration) {
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    if !matches!(runner.vm.execution_state, ExecutionState::WaitingOnOptionSelection) {
+        runner.option_timer = None;
+        return;
+    }
+    let expired = match runner.option_timer.as_mut() {
+        Some((timeout, timer)) => timer.tick(delta).finished().then_some(timeout.default_index),
+        None => None,
+    };
+    if let Some(index) = expired {
+        runner.option_timer = None;
+        SelectDialogueOptionCommand { index }.write(world);
+    }
+}
+
+/// Releases the [`BLOCKING_COMMAND_HOLD`] once every blocking command has completed.
+fn release_blocking_command_hold(
+    mut holds: ResMut<DialogueHolds>,
+    mut blocking: ResMut<BlockingDialogueCommands>,
+) {
+    if blocking.holding && blocking.pending.is_empty() {
+        blocking.holding = false;
+        holds.release(BLOCKING_COMMAND_HOLD);
+    }
+}
+
+/// `<<wait seconds>>`. A new wait replaces any wait still pending rather than adding to it.
+fn builtin_wait(world: &mut World, args: Vec<String>) {
+    let seconds = match args.first().map(|arg| arg.parse::<f32>()) {
+        Some(Ok(seconds)) if seconds >= 0.0 => seconds,
+        _ => {
+            warn!("wait: expected a duration in seconds, got {:?}", args);
+            return;
+        }
+    };
+    world.resource_mut::<DialogueRunner>().wait = Some(Timer::from_seconds(seconds, false));
+}
+
+fn builtin_yield(world: &mut World, _args: Vec<String>) {
+    world.resource_mut::<DialogueRunner>().paused = true;
+}
+
+/// Starts loading the [`VoiceOverTable`] clip for `line_id` and keeps it as the runner's
+/// current voice, which is cleared when the line has none.
+fn load_voice_over(world: &mut World, line_id: &str) -> Option<HandleUntyped> {
+    let voice = world
+        .get_resource::<VoiceOverTable>()
+        .and_then(|table| table.clip_path(line_id))
+        .and_then(|path| Some(world.get_resource::<AssetServer>()?.load_untyped(path.as_str())));
+    world.resource_mut::<DialogueRunner>().current_voice = voice.clone();
+    voice
+}
+
+fn missing_line_placeholder(line_id: &str) -> String {
+    format!("[missing: {}]", line_id)
+}
+
+/// Pops the results of the presented options' `<<if>>` conditions off the VM's stack, returning
+/// whether each option is available. yharnam ignores `AddOption`'s fourth operand, which flags
+/// a condition, so each evaluated condition is still on the stack with the last option's on top.
+fn take_option_conditions(vm: &mut VirtualMachine, options: &[YarnOption]) -> Vec<bool> {
+    use yharnam::yarn_proto::instruction::OpCode;
+
+    let node = vm.program.nodes.get(&vm.state.current_node_name);
+    let has_condition: Vec<bool> = options
+        .iter()
+        .map(|option| {
+            node.into_iter()
+                .flat_map(|node| node.instructions.iter())
+                .filter(|instruction| instruction.opcode_enum() == Some(OpCode::AddOption))
+                .find(|instruction| {
+                    let operand = |index: usize| instruction.operands.get(index);
+                    operand(0).and_then(OperandExt::as_string) == Some(option.line.id.as_str())
+                        && operand(1).and_then(OperandExt::as_string)
+                            == Some(option.destination_node.as_str())
+                })
+                .and_then(|instruction| instruction.operands.get(3)?.as_bool())
+                .unwrap_or(false)
+        })
+        .collect();
+    let mut available = vec![true; options.len()];
+    for (index, _) in has_condition.iter().enumerate().rev().filter(|(_, flagged)| **flagged) {
+        available[index] = vm.state.stack.pop().is_none_or(|value| value.as_bool());
+    }
+    available
+}
+
+fn send_dialogue_event<E: Resource>(world: &mut World, event: E) {
+    world.resource_mut::<Events<E>>().send(event);
+}
+
+/// Swaps in the tables requested by [`SetDialogueLanguageCommand`] for each runner once
+/// they've loaded or failed.
+fn apply_language_tables(world: &mut World) {
+    for_each_dialogue_runner(world, apply_pending_tables);
+}
+
+/// Swaps in the runner's pending tables if they've settled. A requested table that fails falls
+/// back to the default-language table, or failing that to the tables already in use, with an
+/// [`EventLanguageFallback`].
+fn apply_pending_tables(world: &mut World) {
+    let asset_server = world.resource::<AssetServer>().clone();
+    let language = world.resource::<DialogueLanguage>().clone();
+    let runner = world.resource::<DialogueRunner>();
+    let pending = match &runner.pending_tables {
+        Some(pending) => pending,
+        None => return,
+    };
+    let yarn_tables = world.resource::<Assets<YarnStringTable>>();
+    let settled = |table: &Handle<YarnStringTable>| {
+        yarn_tables.get(table).is_some()
+            || asset_server.get_load_state(table) == LoadState::Failed
+    };
+    if !settled(&pending.table) || !pending.fallback_table.as_ref().is_none_or(settled) {
+        return;
+    }
+
+    let pending = world.resource_mut::<DialogueRunner>().pending_tables.take().unwrap();
+    let mut yarn_tables = world.resource_mut::<Assets<YarnStringTable>>();
+    let table = yarn_tables.remove(pending.table).map(|table| table.0);
+    let fallback_table = pending
+        .fallback_table
+        .and_then(|fallback| yarn_tables.remove(fallback))
+        .map(|table| table.0);
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    let path = runner.program_path.clone().unwrap_or_default();
+    let (used, tables_changed) = match (table, fallback_table) {
+        (Some(table), fallback_table) => {
+            runner.set_tables(table, fallback_table.unwrap_or_default());
+            (None, true)
+        }
+        (None, Some(fallback_table)) => {
+            runner.set_tables(fallback_table, Arc::default());
+            (Some(language.default.clone()), true)
+        }
+        (None, None) => (Some(pending.previous_language), false),
+    };
+    if let Some(used) = used {
+        warn!("No {:?} string table for {:?}, using {:?}", language.current, path, used);
+        send_dialogue_event(world, EventLanguageFallback {
+            path,
+            requested: language.current,
+            used,
+        });
+    }
+    // With the old tables still in use the current line hasn't changed.
+    if !tables_changed {
+        return;
+    }
+
+    // Re-present the current line in the new language without restarting the node.
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    let line = match (&runner.state, &runner.current_line) {
+        (DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(_)), Some(line)) => {
+            line.clone()
+        }
+        _ => return,
+    };
+    if let Some(subs) = runner.resolve_line(&line) {
+        let metadata = runner.line_metadata(&line.id).unwrap_or_default().to_vec();
+        let dialogue_line = DialogueLine::parse(&subs);
+        let triggers = inline_triggers(&dialogue_line.attributes);
+        let voice = runner.current_voice.clone();
+        runner.state =
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(dialogue_line.clone()));
+        let event = EventLinePresented(subs, line.id, metadata, triggers, voice, dialogue_line);
+        send_dialogue_event(world, event);
+        send_dialogue_event(world, EventDialogueUpdated);
+        if let Some(active) = world.get_resource::<ActiveDialogueRunner>() {
+            let key = active.0;
+            send_dialogue_event(world, EventKeyedDialogueUpdated(key));
+        }
+    }
+}
+
+// *****************************************************************************************
+// Keyed Runners
+// *****************************************************************************************
+/// Additional runners that advance independently of the primary [`DialogueRunner`] resource,
+/// each with its own VM, state and queue, for parallel conversations.
+///
+/// Keyed runners are driven by swapping them into the [`DialogueRunner`] and [`DialogueQueue`]
+/// resources while they step, so every command, function and run condition works on them
+/// unchanged. Waits, auto-advance, option timeouts, language switches and hot reloading apply
+/// to every keyed runner too. Use [`RunOnDialogueRunnerCommand`] to target one with any
+/// command. Events sent while a keyed runner steps are the same events the primary runner
+/// sends; check [`ActiveDialogueRunner`] from commands and functions, or listen for
+/// [`EventKeyedDialogueUpdated`], to tell them apart.
+#[derive(Default, Deref, DerefMut)]
+pub struct DialogueRunners(HashMap<Entity, KeyedDialogueRunner>);
+
+pub struct KeyedDialogueRunner {
+    pub runner: DialogueRunner,
+    pub queue: DialogueQueue,
+}
+
+/// Present while a keyed runner is swapped in, naming it.
+pub struct ActiveDialogueRunner(pub Entity);
+
+/// A keyed runner presented a line or options.
+pub struct EventKeyedDialogueUpdated(pub Entity);
+
+/// Creates an idle keyed runner with its own in-memory variable storage, configured like the
+/// primary runner. Line formatters and the RNG aren't shared; set them on the keyed runner with
+/// [`RunOnDialogueRunnerCommand`].
+pub struct SpawnDialogueRunnerCommand {
+    pub key: Entity,
+}
+
+impl Command for SpawnDialogueRunnerCommand {
+    fn write(self, world: &mut World) {
+        let variables = SharedVariableStorage::new(Box::new(HashMapVariableStorage::default()));
+        let mut runner = DialogueRunner::new(Program::default(), Vec::new(), variables);
+        let primary = world.resource::<DialogueRunner>();
+        runner.hot_reload = primary.hot_reload;
+        runner.cache_programs = primary.cache_programs;
+        runner.default_start_node = primary.default_start_node.clone();
+        runner.option_policy = primary.option_policy;
+        runner.line_advance_mode = primary.line_advance_mode;
+        runner.validate_on_load = primary.validate_on_load;
+        runner.option_timeout = primary.option_timeout;
+        runner.command_separator = primary.command_separator;
+        let (checking, coerce_number_bool) = primary.variables.type_checking();
+        runner.variables.set_type_checking(checking, coerce_number_bool);
+        runner.install_functions(world.resource::<DialogueFunctions>());
+        world.resource_mut::<DialogueRunners>().insert(
+            self.key,
+            KeyedDialogueRunner {
+                runner,
+                queue: DialogueQueue::default(),
+            },
+        );
+    }
+}
+
+pub struct DespawnDialogueRunnerCommand {
+    pub key: Entity,
+}
+
+impl Command for DespawnDialogueRunnerCommand {
+    fn write(self, world: &mut World) {
+        world.resource_mut::<DialogueRunners>().remove(&self.key);
+    }
+}
+
+/// Runs `command` against the keyed runner `key` instead of the primary runner.
+pub struct RunOnDialogueRunnerCommand<C: Command> {
+    pub key: Entity,
+    pub command: C,
+}
+
+impl<C: Command> Command for RunOnDialogueRunnerCommand<C> {
+    fn write(self, world: &mut World) {
+        let command = self.command;
+        if with_keyed_runner(world, self.key, |world| command.write(world)).is_none() {
+            warn!("RunOnDialogueRunnerCommand: no dialogue runner for {:?}!", self.key);
+        }
+    }
+}
+
+/// Swaps the keyed runner `key` into the primary runner's resources while `f` runs.
+fn with_keyed_runner<R>(
+    world: &mut World,
+    key: Entity,
+    f: impl FnOnce(&mut World) -> R,
+) -> Option<R> {
+    let mut keyed = world.resource_mut::<DialogueRunners>().remove(&key)?;
+    std::mem::swap(&mut keyed.runner, &mut *world.resource_mut::<DialogueRunner>());
+    std::mem::swap(&mut keyed.queue, &mut *world.resource_mut::<DialogueQueue>());
+    let previous = world.remove_resource::<ActiveDialogueRunner>();
+    world.insert_resource(ActiveDialogueRunner(key));
+
+    let result = f(world);
+
+    match previous {
+        Some(previous) => world.insert_resource(previous),
+        None => {
+            world.remove_resource::<ActiveDialogueRunner>();
+        }
+    }
+    std::mem::swap(&mut keyed.runner, &mut *world.resource_mut::<DialogueRunner>());
+    std::mem::swap(&mut keyed.queue, &mut *world.resource_mut::<DialogueQueue>());
+    world.resource_mut::<DialogueRunners>().insert(key, keyed);
+    Some(result)
+}
+
+/// Runs `f` against the primary runner, then against each keyed runner swapped in.
+fn for_each_dialogue_runner(world: &mut World, mut f: impl FnMut(&mut World)) {
+    f(world);
+    let keys: Vec<Entity> = world.resource::<DialogueRunners>().keys().copied().collect();
+    for key in keys {
+        with_keyed_runner(world, key, &mut f);
+    }
+}
+
+fn update_keyed_runners(world: &mut World) {
+    let keys: Vec<Entity> = world.resource::<DialogueRunners>().keys().copied().collect();
+    for key in keys {
+        let step = with_keyed_runner(world, key, |world| {
+            try_start_next(world);
+            step_dialogue(world)
+        });
+        if let Some(Some(DialogueStep::Line | DialogueStep::Options)) = step {
+            send_dialogue_event(world, EventKeyedDialogueUpdated(key));
+        }
+    }
+}
+
+// *****************************************************************************************
+// Headless Driver
+// *****************************************************************************************
+/// What [`DialogueDriver::step`] stopped at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    Line { line_id: String, line: DialogueLine },
+    Options(Vec<DialogueOption>),
+    Command { name: String, args: Vec<String> },
+    Complete,
+}
+
+/// Steps a [`DialogueRunner`] synchronously without an `App` or schedule, for asserting script
+/// outcomes in plain tests. It runs the same code the plugin does against a bare [`World`] with
+/// in-memory variable storage, so commands and functions registered on
+/// [`DialogueDriver::world_mut`] behave as they would in a game. Holds, `<<wait>>` and
+/// `<<yield>>` are ignored.
+pub struct DialogueDriver {
+    world: World,
+}
+
+impl DialogueDriver {
+    pub fn new(program: Program, string_table: Vec<LineInfo>) -> Self {
+        let mut world = World::new();
+        init_dialogue_world(&mut world);
+        let variables = SharedVariableStorage::new(Box::new(HashMapVariableStorage::default()));
+        let mut runner = DialogueRunner::new(program, string_table, variables);
+        runner.install_functions(world.resource::<DialogueFunctions>());
+        world.insert_resource(runner);
+        Self { world }
+    }
+
+    /// Starts the dialogue at `node`, or the best available node of the group named `node`
+    /// (see [`DialogueRunner::resolve_node_group`]). Returns whether a node was found.
+    pub fn start(&mut self, node: &str) -> bool {
+        match self.runner().resolve_node_group(node) {
+            Some(node) => {
+                JumpToNodeCommand { node }.write(&mut self.world);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs the dialogue to its next line, set of options, command or completion. While options
+    /// are waiting on [`DialogueDriver::select`] they're returned again.
+    pub fn step(&mut self) -> StepResult {
+        self.world.resource_mut::<DialogueRunner>().paused = false;
+        loop {
+            // There's no clock driving the driver, so waits finish immediately.
+            self.world.resource_mut::<DialogueRunner>().wait = None;
+            let step = step_dialogue(&mut self.world);
+            let runner = self.runner();
+            match (step, &runner.state) {
+                (Some(DialogueStep::NodeChange), _) => continue,
+                (Some(DialogueStep::Command), _) => {
+                    let command = self
+                        .world
+                        .resource_mut::<Events<EventCommandRun>>()
+                        .drain()
+                        .last();
+                    match command {
+                        Some(EventCommandRun { name, args }) => {
+                            return StepResult::Command { name, args };
+                        }
+                        // The interceptor skipped every command in the batch.
+                        None => continue,
+                    }
+                }
+                (_, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(line))) => {
+                    let line_id = runner.current_line.as_ref().map(|line| line.id.clone());
+                    return StepResult::Line {
+                        line_id: line_id.unwrap_or_default(),
+                        line: line.clone(),
+                    };
+                }
+                (
+                    _,
+                    DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(options)),
+                ) => {
+                    return StepResult::Options(options.clone());
+                }
+                (Some(DialogueStep::Complete), _) | (_, DialogueRunnerState::Idle) => {
+                    return StepResult::Complete;
+                }
+                (_, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null)) => continue,
+            }
+        }
+    }
+
+    /// Picks the option with [`DialogueOption::index`] `index`.
+    pub fn select(&mut self, index: usize) {
+        SelectDialogueOptionCommand { index }.write(&mut self.world);
+    }
+
+    pub fn runner(&self) -> &DialogueRunner {
+        self.world.resource::<DialogueRunner>()
+    }
+
+    pub fn runner_mut(&mut self) -> Mut<'_, DialogueRunner> {
+        self.world.resource_mut::<DialogueRunner>()
+    }
+
+    /// The world the runner steps in, for registering commands and functions or reading events.
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+}
+
+// *****************************************************************************************
+// Dialogue Functions
+// *****************************************************************************************
+thread_local! {
+    /// Worlds lent to dialogue functions, innermost last. Functions that step dialogue again
+    /// push another world while they run.
+    static DIALOGUE_WORLDS: RefCell<Vec<World>> = const { RefCell::new(Vec::new()) };
+}
+
+/// VM trampolines for each function name, created once and shared by every runner's library.
+/// yharnam only takes `'static` functions, so each distinct name leaks one small closure for
+/// the life of the process; registering the same name again reuses it.
+static DIALOGUE_TRAMPOLINES: Mutex<Vec<(String, &'static ReturningFunction)>> =
+    Mutex::new(Vec::new());
+
+/// Moves `world` where dialogue functions called by the VM can reach it while `f` runs, and
+/// puts it back afterwards, even if `f` panics.
+fn with_dialogue_world<R>(world: &mut World, f: impl FnOnce() -> R) -> R {
+    struct Restore<'w>(&'w mut World);
+
+    impl Drop for Restore<'_> {
+        fn drop(&mut self) {
+            match DIALOGUE_WORLDS.with(|worlds| worlds.borrow_mut().pop()) {
+                Some(world) => *self.0 = world,
+                None => error!("Dialogue world was not returned"),
+            }
+        }
+    }
+
+    DIALOGUE_WORLDS.with(|worlds| worlds.borrow_mut().push(std::mem::take(world)));
+    let _restore = Restore(world);
+    f()
+}
+
+/// A world borrowed from [`DIALOGUE_WORLDS`], pushed back when dropped so a panicking
+/// function doesn't lose it.
+struct LentDialogueWorld(World);
+
+impl Drop for LentDialogueWorld {
+    fn drop(&mut self) {
+        let world = std::mem::take(&mut self.0);
+        DIALOGUE_WORLDS.with(|worlds| worlds.borrow_mut().push(world));
+    }
+}
+
+fn install_dialogue_function(vm: &mut VirtualMachine, name: String) {
+    let trampoline = {
+        let mut trampolines = DIALOGUE_TRAMPOLINES.lock().unwrap();
+        match trampolines.iter().find(|(existing, _)| *existing == name) {
+            Some((_, trampoline)) => *trampoline,
+            None => {
+                let function_name = name.clone();
+                let trampoline: &'static ReturningFunction =
+                    Box::leak(Box::new(move |args: &[yharnam::YarnValue]| {
+                        let args = args.iter().cloned().map(Into::into).collect();
+                        yharnam::YarnValue::from(call_dialogue_function(&function_name, args))
+                    }));
+                trampolines.push((name.clone(), trampoline));
+                trampoline
+            }
+        }
+    };
+    vm.library.insert(name, FunctionInfo::new_returning(-1, trampoline));
+}
+
+fn call_dialogue_function(name: &str, args: Vec<YarnValue>) -> YarnValue {
+    let mut world = match DIALOGUE_WORLDS.with(|worlds| worlds.borrow_mut().pop()) {
+        Some(world) => LentDialogueWorld(world),
+        None => {
+            warn!("Dialogue function {} called outside of update_runner!", name);
+            return YarnValue::Null;
+        }
+    };
+    let function = world
+        .0
+        .get_resource::<DialogueFunctions>()
+        .and_then(|functions| functions.get(name).cloned());
+    match function {
+        Some(function) => function.call(&mut world.0, args),
+        None => {
+            warn!("Dialogue function {} is not registered!", name);
+            YarnValue::Null
+        }
+    }
+}
+
+/// Small seedable generator backing the random built-ins, so dialogue can be made deterministic.
+#[derive(Debug, Clone)]
+pub struct DialogueRng(u64);
+
+impl Default for DialogueRng {
+    fn default() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+        Self(seed)
+    }
+}
+
+impl DialogueRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        // splitmix64
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn number_arg(args: &[YarnValue], idx: usize) -> f32 {
+    args.get(idx).map_or(0.0, YarnValue::as_number)
+}
+
+fn string_arg(args: &[YarnValue], idx: usize) -> String {
+    args.get(idx).map(ToString::to_string).unwrap_or_default()
+}
+
+fn builtin_visited(world: &mut World, args: Vec<YarnValue>) -> YarnValue {
+    let runner = world.resource::<DialogueRunner>();
+    YarnValue::Bool(runner.has_visited(&string_arg(&args, 0)))
+}
+
+fn builtin_visited_count(world: &mut World, args: Vec<YarnValue>) -> YarnValue {
+    let runner = world.resource::<DialogueRunner>();
+    YarnValue::Number(runner.visit_count(&string_arg(&args, 0)) as f32)
+}
+
+fn builtin_dice(world: &mut World, args: Vec<YarnValue>) -> YarnValue {
+    let sides = number_arg(&args, 0).max(1.0) as u64;
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    YarnValue::Number((runner.rng.next_u64() % sides + 1) as f32)
+}
+
+fn builtin_random(world: &mut World, _args: Vec<YarnValue>) -> YarnValue {
+    YarnValue::Number(world.resource_mut::<DialogueRunner>().rng.next_f32())
+}
+
+fn builtin_random_range(world: &mut World, args: Vec<YarnValue>) -> YarnValue {
+    let (min, max) = (number_arg(&args, 0), number_arg(&args, 1));
+    let mut runner = world.resource_mut::<DialogueRunner>();
+    YarnValue::Number(min + runner.rng.next_f32() * (max - min))
+}
+
+fn builtin_round_places(_world: &mut World, args: Vec<YarnValue>) -> YarnValue {
+    let scale = 10f32.powi(number_arg(&args, 1) as i32);
+    YarnValue::Number((number_arg(&args, 0) * scale).round() / scale)
+}
+
+// *****************************************************************************************
+// Asset Loaders
+// *****************************************************************************************
+#[derive(Debug, Clone, TypeUuid, Deref)]
+#[uuid = "aa134e2e-a11e-4350-ae1e-b5410d0c333c"]
+pub struct YarnStringTable(pub Arc<Vec<LineInfo>>);
+
+#[derive(Default)]
+pub struct YarnStringTableLoader {
+    pub strictness: CsvStrictness,
+    pub dialect: CsvDialect,
+}
+
+/// The CSV format string tables are exported in. Defaults to comma-separated with a header row.
+/// Without headers, columns are read in `LineInfo` field order: id, text, file, node,
+/// lineNumber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    pub has_headers: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            has_headers: true,
+        }
+    }
+}
+
+impl CsvDialect {
+    pub fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.delimiter(self.delimiter).has_headers(self.has_headers);
+        builder
+    }
+}
+
+/// The plugin's string table settings, for tables parsed outside [`YarnStringTableLoader`]
+/// such as by [`AddDialogueBytesToQueueCommand`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StringTableFormat {
+    pub dialect: CsvDialect,
+    pub strictness: CsvStrictness,
+}
+
+/// What [`YarnStringTableLoader`] does with string table rows it can't parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CsvStrictness {
+    /// Fail the whole table, so the load reports as failed.
+    #[default]
+    Strict,
+    /// Log the row and load the rest of the table.
+    SkipMalformedRows,
+}
+
+/// Line text storage the runner can read from instead of an in-memory `Vec<LineInfo>`. See
+/// [`DialogueRunner::string_source`].
+pub trait StringTableSource: Send + Sync {
+    fn text(&self, line_id: &str) -> Option<Cow<'_, str>>;
+}
+
+/// A string table left on disk. Opening it reads the file once to index each line's position,
+/// after which a line's text is read from the file whenever it's presented. Only the IDs and
+/// their offsets stay in memory.
+pub struct StreamingStringTable {
+    positions: HashMap<String, csv::Position>,
+    text_column: usize,
+    reader: Mutex<csv::Reader<fs::File>>,
+}
+
+impl StreamingStringTable {
+    /// Indexes the table at `path`. With headers, the `id` and `text` columns are found by name,
+    /// otherwise they're the first two columns.
+    pub fn open(path: impl AsRef<Path>, dialect: CsvDialect) -> Result<Self, DialogueLoadError> {
+        let path = path.as_ref();
+        let read_error = |source| DialogueLoadError::TableRead {
+            path: path.to_path_buf(),
+            source,
+        };
+        let parse_error = |source| DialogueLoadError::TableParse {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut reader = dialect.reader_builder().from_path(path).map_err(read_error)?;
+        let (id_column, text_column) = match dialect.has_headers {
+            true => {
+                let headers = reader.headers().map_err(parse_error)?;
+                let column = |name| headers.iter().position(|header| header == name);
+                (column("id").unwrap_or(0), column("text").unwrap_or(1))
+            }
+            false => (0, 1),
+        };
+
+        let mut positions = HashMap::default();
+        let mut record = csv::StringRecord::new();
+        loop {
+            let position = reader.position().clone();
+            if !reader.read_record(&mut record).map_err(parse_error)? {
+                break;
+            }
+            if let Some(id) = record.get(id_column) {
+                positions.insert(id.to_string(), position);
+            }
+        }
+        Ok(Self {
+            positions,
+            text_column,
+            reader: Mutex::new(reader),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+impl StringTableSource for StreamingStringTable {
+    fn text(&self, line_id: &str) -> Option<Cow<'_, str>> {
+        let position = self.positions.get(line_id)?;
+        let mut reader = self.reader.lock().unwrap();
+        let mut record = csv::StringRecord::new();
+        if let Err(error) = reader.seek(position.clone()) {
+            warn!("Unable to seek to line {} in the string table: {}", line_id, error);
+            return None;
+        }
+        match reader.read_record(&mut record) {
+            Ok(true) => record.get(self.text_column).map(|text| Cow::Owned(text.to_string())),
+            Ok(false) => None,
+            Err(error) => {
+                warn!("Unable to read line {} from the string table: {}", line_id, error);
+                None
+            }
+        }
+    }
+}
+
+impl AssetLoader for YarnStringTableLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::asset::BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            // Metadata exports share the `.csv` extension, so they're told apart by name.
+            let file_name = load_context
+                .path()
+                .file_name()
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            if file_name.ends_with("-metadata.csv") {
+                let metadata = parse_line_metadata(bytes).map_err(|err| {
+                    anyhow::anyhow!("{}: {}", load_context.path().display(), err)
+                })?;
+                load_context.set_default_asset(LoadedAsset::new(YarnLineMetadata(metadata)));
+                return Ok(());
+            }
+
+            let string_table =
+                parse_string_table(bytes, self.dialect, self.strictness, load_context.path())?;
+            let string_table = YarnStringTable(Arc::new(string_table));
+
+            load_context.set_default_asset(LoadedAsset::new(string_table));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["csv"]
+    }
+}
+
+/// Parses a string table export. csv errors carry the record, line and byte they occurred at.
+fn parse_string_table(
+    bytes: &[u8],
+    dialect: CsvDialect,
+    strictness: CsvStrictness,
+    path: &Path,
+) -> Result<Vec<LineInfo>, anyhow::Error> {
+    let mut string_table = Vec::new();
+    for result in dialect.reader_builder().from_reader(bytes).deserialize() {
+        match (result, strictness) {
+            (Ok(line), _) => string_table.push(line),
+            (Err(err), CsvStrictness::Strict) => {
+                return Err(anyhow::anyhow!("{}: {}", path.display(), err));
+            }
+            (Err(err), CsvStrictness::SkipMalformedRows) => {
+                warn!("{}: skipping malformed row: {}", path.display(), err);
+            }
+        }
+    }
+    Ok(string_table)
+}
+
+/// Metadata tags per line ID, loaded from a `<stem>-Metadata.csv` by [`YarnStringTableLoader`].
+#[derive(Debug, Clone, Default, TypeUuid, Deref)]
+#[uuid = "5b0c2a1e-8f6d-4c1b-9a57-2e3d4f6a7b81"]
+pub struct YarnLineMetadata(pub HashMap<String, Vec<String>>);
+
+#[derive(Deserialize)]
+struct LineMetadataRecord {
+    id: String,
+    #[serde(default)]
+    tags: String,
+}
+
+/// Parses a Yarn metadata export. Each row's `tags` column holds space-separated tags.
+fn parse_line_metadata(bytes: &[u8]) -> Result<HashMap<String, Vec<String>>, csv::Error> {
+    csv::Reader::from_reader(bytes)
+        .deserialize()
+        .map(|record| {
+            record.map(|record: LineMetadataRecord| {
+                let tags = record.tags.split_whitespace().map(str::to_string).collect();
+                (record.id, tags)
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "35d03e10-93b3-436e-8df4-7c7bea467dc0"]
+pub struct YarnProgram {
+    program: Program,
+    initial_values: HashMap<String, YarnValue>,
+}
+
+impl YarnProgram {
+    /// A program without declared variable defaults.
+    pub fn new(program: Program) -> Self {
+        Self {
+            program,
+            initial_values: HashMap::default(),
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// The name the program was compiled with.
+    pub fn name(&self) -> &str {
+        &self.program.name
+    }
+
+    /// Names of the program's nodes, in no particular order.
+    pub fn node_names(&self) -> impl Iterator<Item = &str> {
+        self.program.nodes.keys().map(String::as_str)
+    }
+
+    /// The default value of each variable the script `<<declare>>`s, e.g. `$coins` for
+    /// `<<declare $coins = 10>>`.
+    pub fn initial_values(&self) -> &HashMap<String, YarnValue> {
+        &self.initial_values
+    }
+}
+
+/// The part of a compiled program yharnam's `Program` doesn't decode: field 3 of Yarn Spinner's
+/// `Program` message, holding the declared variables' default values.
+#[derive(Clone, PartialEq, Message)]
+struct ProgramInitialValues {
+    #[prost(map = "string, message", tag = "3")]
+    initial_values: std::collections::HashMap<String, yharnam::yarn_proto::Operand>,
+}
+
+fn operand_value(operand: yharnam::yarn_proto::Operand) -> YarnValue {
+    use yharnam::yarn_proto::operand::Value;
+
+    match operand.value {
+        Some(Value::StringValue(s)) => YarnValue::String(s),
+        Some(Value::BoolValue(b)) => YarnValue::Bool(b),
+        Some(Value::FloatValue(n)) => YarnValue::Number(n),
+        None => YarnValue::Null,
+    }
+}
+
+/// Typed access to an instruction operand's value, for tooling that inspects programs.
+pub trait OperandExt {
+    fn as_string(&self) -> Option<&str>;
+    fn as_bool(&self) -> Option<bool>;
+    fn as_float(&self) -> Option<f32>;
+}
+
+impl OperandExt for yarn_proto::Operand {
+    fn as_string(&self) -> Option<&str> {
+        match &self.value {
+            Some(yarn_proto::operand::Value::StringValue(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self.value {
+            Some(yarn_proto::operand::Value::BoolValue(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_float(&self) -> Option<f32> {
+        match self.value {
+            Some(yarn_proto::operand::Value::FloatValue(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+pub trait InstructionExt {
+    /// The instruction's opcode, or `None` if the raw value isn't one this version knows.
+    fn opcode_enum(&self) -> Option<yarn_proto::instruction::OpCode>;
+}
+
+impl InstructionExt for yarn_proto::Instruction {
+    fn opcode_enum(&self) -> Option<yarn_proto::instruction::OpCode> {
+        yarn_proto::instruction::OpCode::from_i32(self.opcode)
+    }
+}
+
+#[derive(Default)]
+pub struct YarnProgramLoader;
+
+fn decode_program(bytes: &[u8], path: &Path) -> Result<YarnProgram, anyhow::Error> {
+    let error = |err: prost::DecodeError| {
+        anyhow::anyhow!(
+            "{}: unable to decode program ({} bytes): {}",
+            path.display(),
+            bytes.len(),
+            err
+        )
+    };
+    let program = Program::decode(bytes).map_err(error)?;
+    let initial_values = ProgramInitialValues::decode(bytes)
+        .map_err(error)?
+        .initial_values
+        .into_iter()
+        .map(|(name, operand)| (name, operand_value(operand)))
+        .collect();
+    Ok(YarnProgram {
+        program,
+        initial_values,
+    })
+}
+
+impl AssetLoader for YarnProgramLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::asset::BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let program = decode_program(bytes, load_context.path())?;
+            load_context.set_default_asset(LoadedAsset::new(program));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["yarnc"]
+    }
+}
+
+/// Compiles `.yarn` source on load. The program becomes the default asset and its string table
+/// the [`COMPILED_TABLE_LABEL`] sub-asset, so no external compile step or `.csv` is needed.
+#[cfg(feature = "compile")]
+#[derive(Default)]
+pub struct YarnSourceLoader;
+
+#[cfg(feature = "compile")]
+impl AssetLoader for YarnSourceLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::asset::BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let path = load_context.path().to_path_buf();
+            let source = String::from_utf8(bytes.to_vec())?;
+            // Diagnostics surface through the asset server's load failure log.
+            let (program, string_table) = compile_yarn_source(&path, source)?;
+            load_context.set_labeled_asset(
+                COMPILED_TABLE_LABEL,
+                LoadedAsset::new(YarnStringTable(Arc::new(string_table))),
+            );
+            load_context.set_default_asset(LoadedAsset::new(program));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["yarn"]
+    }
+}
+
+/// Diagnostics from a failed `.yarn` compile, one `file:line:column: message` entry each.
+#[cfg(feature = "compile")]
+#[derive(Debug, Clone)]
+pub struct YarnCompileError(pub Vec<String>);
+
+#[cfg(feature = "compile")]
+impl fmt::Display for YarnCompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+#[cfg(feature = "compile")]
+impl std::error::Error for YarnCompileError {}
+
+/// Compiles a `.yarn` source into a program and its default-language string table.
+#[cfg(feature = "compile")]
+pub fn compile_yarn_source(
+    path: &Path,
+    source: String,
+) -> Result<(YarnProgram, Vec<LineInfo>), YarnCompileError> {
+    use prost_compile::Message as _;
+    use yarnspinner_compiler::prelude::{Compiler, File};
+
+    let file_name = path.to_string_lossy().into_owned();
+    let compilation = Compiler::new()
+        .add_file(File {
+            file_name: file_name.clone(),
+            source,
+        })
+        .compile()
+        .map_err(|err| {
+            YarnCompileError(
+                err.0
+                    .iter()
+                    .map(|diagnostic| {
+                        let (line, column) = diagnostic.range.as_ref().map_or((0, 0), |range| {
+                            (range.start.line + 1, range.start.character + 1)
+                        });
+                        let file = diagnostic.file_name.as_deref().unwrap_or(&file_name);
+                        format!("{}:{}:{}: {}", file, line, column, diagnostic.message)
+                    })
+                    .collect(),
+            )
+        })?;
+
+    // The compiler's program shares Yarn Spinner's protobuf schema, so it round-trips through
+    // bytes, declared defaults included.
+    let program_bytes = compilation
+        .program
+        .map(|program| program.encode_to_vec())
+        .unwrap_or_default();
+    let program = decode_program(&program_bytes, path)
+        .map_err(|err| YarnCompileError(vec![err.to_string()]))?;
+
+    let mut string_table: Vec<LineInfo> = compilation
+        .string_table
+        .into_iter()
+        .map(|(id, info)| LineInfo {
+            id: id.0,
+            text: info.text,
+            file: info.file_name,
+            node: info.node_name,
+            line_number: info.line_number as u32,
+        })
+        .collect();
+    string_table.sort_by_key(|line| line.line_number);
+    Ok((program, string_table))
+}
+
+pub struct AddDialogueToQueueCommand {
+    pub path: PathBuf,
+    pub start_node: Option<String>,
+    /// Language to load the string table in, as `<stem>.<language>.csv`. Defaults to the active
+    /// [`DialogueLanguage`]. Lines missing from it, or the whole table if the file is missing,
+    /// fall back to the default language.
+    pub language: Option<String>,
+    /// Commands only registered while this dialogue runs. See
+    /// [`DialogueQueueEntry::extra_commands`].
+    pub extra_commands: Option<DialogueCommands>,
+}
+
+impl Command for AddDialogueToQueueCommand {
+    fn write(self, world: &mut World) {
+        let language = queue_language(world, self.language);
+        let mut entry = load_queue_entry(world, &language, self.path, self.start_node);
+        entry.extra_commands = self.extra_commands;
+        let mut dialogue_queue = world.get_resource_mut::<DialogueQueue>().unwrap();
+        dialogue_queue.push_back(entry)
+    }
+}
+
+/// Queues dialogue that only plays if `condition` still holds when its turn comes, e.g. the
+/// speaker is still nearby. Otherwise it's skipped with [`EventDialogueSkipped`].
+pub struct AddConditionalDialogueCommand {
+    pub path: PathBuf,
+    pub start_node: Option<String>,
+    pub condition: DialogueQueueCondition,
+}
+
+impl Command for AddConditionalDialogueCommand {
+    fn write(self, world: &mut World) {
+        let language = queue_language(world, None);
+        let mut entry = load_queue_entry(world, &language, self.path, self.start_node);
+        entry.condition = Some(self.condition);
+        world.resource_mut::<DialogueQueue>().push_back(entry);
+    }
+}
+
+/// Queues several dialogues back to back in one command, so nothing else can enqueue between
+/// them. Entries are `(path, start_node)` and play in order.
+pub struct AddDialogueSequenceCommand {
+    pub entries: Vec<(PathBuf, Option<String>)>,
+}
+
+impl Command for AddDialogueSequenceCommand {
+    fn write(self, world: &mut World) {
+        let language = queue_language(world, None);
+        let entries: Vec<DialogueQueueEntry> = self
+            .entries
+            .into_iter()
+            .map(|(path, start_node)| load_queue_entry(world, &language, path, start_node))
+            .collect();
+        let mut dialogue_queue = world.resource_mut::<DialogueQueue>();
+        dialogue_queue.extend(entries);
+    }
+}
+
+/// The active [`DialogueLanguage`], switched to `language` when one is given.
+fn queue_language(world: &World, language: Option<String>) -> DialogueLanguage {
+    let mut active = world.resource::<DialogueLanguage>().clone();
+    if let Some(language) = language {
+        active.current = language;
+    }
+    active
+}
+
+/// Loads a program and its string tables in `language`, reusing preloaded or cached handles when
+/// they were loaded in that language.
+fn load_queue_entry(
+    world: &World,
+    language: &DialogueLanguage,
+    path: PathBuf,
+    start_node: Option<String>,
+) -> DialogueQueueEntry {
+    let preloaded = world
+        .get_resource::<DialoguePreloadCache>()
+        .and_then(|cache| cache.entries.get(&path))
+        .or_else(|| world.get_resource::<DialogueProgramCache>()?.entries.get(&path))
+        .filter(|preloaded| preloaded.language == language.current);
+    if let Some(preloaded) = preloaded {
+        let handles = preloaded.handles.clone();
+        return DialogueQueueEntry {
+            path,
+            program: handles.program,
+            table: handles.table,
+            fallback_table: handles.fallback_table,
+            metadata: handles.metadata,
+            start_node,
+            language: Some(language.clone()),
+            load_retries: 0,
+            extra_commands: None,
+            condition: None,
+        };
+    }
+
+    let asset_server = world.get_resource::<AssetServer>().unwrap();
+
+    let program = asset_server.load(path.as_path());
+    let table = asset_server.load(language.table_path(&path));
+    let fallback_table = language
+        .fallback_table_path(&path)
+        .map(|path| asset_server.load(path));
+    let metadata = may_have_line_metadata(asset_server, &path)
+        .then(|| asset_server.load(line_metadata_path(&path)));
+
+    DialogueQueueEntry {
+        path,
+        program,
+        table,
+        fallback_table,
+        metadata,
+        start_node,
+        language: Some(language.clone()),
+        load_retries: 0,
+        extra_commands: None,
+        condition: None,
+    }
+}
+
+/// Whether the line metadata file for `program` might exist, checked by listing the program's
+/// directory so dialogue without metadata doesn't log a failed load. Asset sources that can't
+/// list directories, such as the web and Android, list nothing; there the program itself is
+/// missing from the listing, so the load is attempted and a missing file is tolerated.
+fn may_have_line_metadata(asset_server: &AssetServer, program: &Path) -> bool {
+    let directory = program.parent().unwrap_or_else(|| Path::new(""));
+    let entries: Vec<PathBuf> = match asset_server.asset_io().read_directory(directory) {
+        Ok(entries) => entries.collect(),
+        Err(_) => return true,
+    };
+    let listed = |path: &Path| entries.iter().any(|entry| entry == path);
+    !listed(program) || listed(&line_metadata_path(program))
+}
+
+/// Starts loading a dialogue's program and tables without queuing it, so queuing it later
+/// doesn't wait on the loads. The handles are kept in the [`DialoguePreloadCache`].
+pub struct PreloadDialogueCommand {
+    pub path: PathBuf,
+}
+
+impl Command for PreloadDialogueCommand {
+    fn write(self, world: &mut World) {
+        let language = queue_language(world, None);
+        let entry = load_queue_entry(world, &language, self.path, None);
+        let preloaded = PreloadedDialogue {
+            language: language.current,
+            handles: DialogueAssetHandles {
+                program: entry.program,
+                table: entry.table,
+                fallback_table: entry.fallback_table,
+                metadata: entry.metadata,
+            },
+        };
+        world
+            .get_resource_or_insert_with(DialoguePreloadCache::default)
+            .entries
+            .insert(entry.path, preloaded);
+    }
+}
+
+/// Handles of preloaded dialogue, keeping the assets resident until evicted. Preloaded
+/// dialogue can be queued any number of times.
+#[derive(Default)]
+pub struct DialoguePreloadCache {
+    entries: HashMap<PathBuf, PreloadedDialogue>,
+}
+
+struct PreloadedDialogue {
+    language: String,
+    handles: DialogueAssetHandles,
+}
+
+impl DialoguePreloadCache {
+    pub fn contains(&self, path: &Path) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    /// Releases the preloaded assets for `path`, returning whether it was cached.
+    pub fn evict(&mut self, path: &Path) -> bool {
+        self.entries.remove(path).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Handles of dialogue that has played, kept while [`DialoguePlugin::cache_programs`] is set so
+/// replays start without reloading. With a `capacity`, the least recently played dialogue is
+/// evicted first.
+#[derive(Default)]
+pub struct DialogueProgramCache {
+    entries: HashMap<PathBuf, PreloadedDialogue>,
+    /// Cached paths, least recently played first.
+    order: VecDeque<PathBuf>,
+    pub capacity: Option<usize>,
+}
+
+impl DialogueProgramCache {
+    pub fn contains(&self, path: &Path) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(&mut self, path: PathBuf, language: String, handles: DialogueAssetHandles) {
+        self.order.retain(|cached| *cached != path);
+        self.order.push_back(path.clone());
+        self.entries.insert(path, PreloadedDialogue { language, handles });
+        while self.capacity.is_some_and(|capacity| self.entries.len() > capacity) {
+            match self.order.pop_front() {
+                Some(oldest) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    /// Releases the cached assets for `path`, returning whether it was cached.
+    pub fn evict(&mut self, path: &Path) -> bool {
+        self.order.retain(|cached| cached != path);
+        self.entries.remove(path).is_some()
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.order.clear();
+        self.entries.clear();
+    }
+}
+
+/// Queues dialogue to play next, ahead of everything already queued.
+pub struct AddDialogueToFrontCommand {
+    pub path: PathBuf,
+    pub start_node: Option<String>,
+    /// Stops the running dialogue so this one starts immediately. The interrupted dialogue is
+    /// queued right behind it and restarts from the node it was in.
+    pub interrupt: bool,
+}
+
+impl Command for AddDialogueToFrontCommand {
+    fn write(self, world: &mut World) {
+        let language = queue_language(world, None);
+        let entry = load_queue_entry(world, &language, self.path, self.start_node);
+
+        let runner = world.resource::<DialogueRunner>();
+        if self.interrupt && runner.state != DialogueRunnerState::Idle {
+            if let Some(node) = runner.current_node.clone() {
+                let remainder = queue_entry_from_runner(world, node);
+                world.resource_mut::<DialogueQueue>().push_front(remainder);
+            }
+            StopDialogueCommand { clear_queue: false }.write(world);
+        }
+
+        world.resource_mut::<DialogueQueue>().push_front(entry);
+    }
+}
+
+/// Queues the runner's already loaded program and tables again, starting at `start_node`.
+fn queue_entry_from_runner(world: &mut World, start_node: String) -> DialogueQueueEntry {
+    let runner = world.resource::<DialogueRunner>();
+    let path = runner.program_path.clone().unwrap_or_default();
+    let program = YarnProgram {
+        program: runner.vm.program.clone(),
+        initial_values: runner.initial_values.clone(),
+    };
+    let table = YarnStringTable(runner.table.clone());
+    let fallback_table = YarnStringTable(runner.fallback_table.clone());
+    let metadata = YarnLineMetadata(runner.line_metadata.clone());
+    let extra_commands = runner.scoped_commands.clone();
+
+    let program = world.resource_mut::<Assets<YarnProgram>>().add(program);
+    let mut yarn_tables = world.resource_mut::<Assets<YarnStringTable>>();
+    let table = yarn_tables.add(table);
+    let fallback_table = yarn_tables.add(fallback_table);
+    let metadata = world.resource_mut::<Assets<YarnLineMetadata>>().add(metadata);
+    DialogueQueueEntry {
+        path,
+        program,
+        table,
+        fallback_table: Some(fallback_table),
+        metadata: Some(metadata),
+        start_node: Some(start_node),
+        language: None,
+        load_retries: 0,
+        extra_commands,
+        condition: None,
+    }
+}
+
+/// Enqueues dialogue decoded from memory, for programs that don't live on disk.
+pub struct AddDialogueBytesToQueueCommand {
+    pub program: Vec<u8>,
+    pub table: Vec<u8>,
+    pub start_node: Option<String>,
+}
+
+impl Command for AddDialogueBytesToQueueCommand {
+    fn write(self, world: &mut World) {
+        let path = Path::new("AddDialogueBytesToQueueCommand");
+        let program = match decode_program(&self.program, path) {
+            Ok(program) => program,
+            Err(err) => {
+                error!("{}", err);
+                return;
+            }
+        };
+        let format = world.get_resource::<StringTableFormat>().copied().unwrap_or_default();
+        let string_table =
+            match parse_string_table(&self.table, format.dialect, format.strictness, path) {
+                Ok(string_table) => string_table,
+                Err(err) => {
+                    error!("{}", err);
+                    return;
+                }
+            };
+
+        let program = world
+            .resource_mut::<Assets<YarnProgram>>()
+            .add(program);
+        let table = world
+            .resource_mut::<Assets<YarnStringTable>>()
+            .add(YarnStringTable(Arc::new(string_table)));
+
+        world.resource_mut::<DialogueQueue>().push_back(DialogueQueueEntry {
+            path: PathBuf::new(),
+            program,
+            table,
+            fallback_table: None,
+            metadata: None,
+            start_node: self.start_node,
+            language: None,
+            load_retries: 0,
+            extra_commands: None,
+            condition: None,
+        })
+    }
+}
+
+/// Switches the dialogue language. The running dialogue's tables are reloaded and the current
+/// line is re-presented once they arrive.
+pub struct SetDialogueLanguageCommand {
+    pub language: String,
+}
+
+impl Command for SetDialogueLanguageCommand {
+    fn write(self, world: &mut World) {
+        let mut language = world.resource_mut::<DialogueLanguage>();
+        let previous_language = std::mem::replace(&mut language.current, self.language);
+
+        for_each_dialogue_runner(world, |world| {
+            let program_path = world
+                .resource::<DialogueRunner>()
+                .program_path
+                .clone()
+                .filter(|path| !path.as_os_str().is_empty());
+            if let Some(program_path) = program_path {
+                let asset_server = world.resource::<AssetServer>();
+                let language = world.resource::<DialogueLanguage>();
+                let pending = PendingLanguageTables {
+                    table: asset_server.load(language.table_path(&program_path)),
+                    fallback_table: language
+                        .fallback_table_path(&program_path)
+                        .map(|path| asset_server.load(path)),
+                    previous_language: previous_language.clone(),
+                };
+                world.resource_mut::<DialogueRunner>().pending_tables = Some(pending);
+            }
+        });
+    }
+}
+
+/// Splits command text on `separator` wherever it isn't inside double quotes. Escapes are left
+/// in place for [`split_command_arguments`] to handle.
+pub fn split_command_batch(command_text: &str, separator: char) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    let mut chars = command_text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c == separator && !quoted => {
+                commands.push(std::mem::take(&mut current));
+            }
+            c => current.push(c),
+        }
+    }
+    commands.push(current);
+    commands
+}
+
+/// Splits command text into arguments on whitespace. Double quotes group words into a single
+/// argument, and `\"` or `\\` escape a literal quote or backslash.
+pub fn split_command_arguments(command_text: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut in_argument = false;
+    let mut quoted = false;
+
+    let mut chars = command_text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                in_argument = true;
+                match chars.next() {
+                    Some(escaped @ ('"' | '\\')) => current.push(escaped),
+                    Some(other) => {
+                        current.push('\\');
+                        current.push(other);
+                    }
+                    None => current.push('\\'),
+                }
+            }
+            '"' => {
+                in_argument = true;
+                quoted = !quoted;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_argument {
+                    arguments.push(std::mem::take(&mut current));
+                    in_argument = false;
+                }
+            }
+            c => {
+                in_argument = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_argument {
+        arguments.push(current);
+    }
+    arguments
+}
+
+/// Replaces `{n}` placeholders in `input` with `substitutions[n]`, the way the runner does for
+/// every line. See [`apply_named_substitutions`].
+pub fn apply_substitutions(input: &str, substitutions: &[String]) -> String {
+    apply_named_substitutions(input, substitutions, &HashMap::default())
+}
+
+/// Replaces `{name}` placeholders with `named` values and `{n}` placeholders with
+/// substitutions in a single pass over `input`. Substituted values are inserted literally and
+/// never scanned for placeholders themselves, so a value like `a{b}c` comes through unchanged.
+/// Escapes are copied through untouched, so `\{0\}` stays literal until the text is unescaped.
+pub fn apply_named_substitutions(
+    input: &str,
+    substitutions: &[String],
+    named: &HashMap<String, String>,
+) -> String {
+    let mut return_string = String::new();
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                return_string.push(c);
+                if let Some(escaped) = chars.next() {
+                    return_string.push(escaped);
+                }
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let placeholder = match rest.find('}') {
+                    Some(end) => &rest[..end],
+                    None => {
+                        return_string.push(c);
+                        continue;
+                    }
+                };
+                // Numeric placeholders are positional, so `{1}` refers to the second
+                // substitution no matter where it appears. Anything we can't resolve is left as
+                // written.
+                let key = placeholder.trim();
+                let value = named.get(key).or_else(|| {
+                    key.parse::<usize>()
+                        .ok()
+                        .and_then(|idx| substitutions.get(idx))
+                });
+                match value {
+                    Some(sub) => return_string.push_str(sub),
+                    None => {
+                        return_string.push('{');
+                        return_string.push_str(placeholder);
+                        return_string.push('}');
+                    }
+                }
+                chars = rest[placeholder.len() + 1..].chars();
+            }
+            c => return_string.push(c),
+        }
+    }
+    return_string
+}
+
+/// The character a recognized `\x` escape stands for. `\:` is included because it keeps a
+/// colon from being read as the speaker separator.
+fn unescaped_char(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        '\\' | '[' | ']' | '{' | '}' | '<' | '>' | '#' | '/' | ':' => Some(c),
+        _ => None,
+    }
+}
+
+/// Escapes every character [`unescape_text`] recognizes, so `input` survives markup parsing
+/// and unescaping as written.
+pub fn escape_text(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        if unescaped_char(c) == Some(c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+    output
+}
+
+/// Converts Yarn escapes such as `\n`, `\\` and `\{` in presented text. Unknown escapes are
+/// left as written.
+pub fn unescape_text(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().copied().and_then(unescaped_char)) {
+            ('\\', Some(unescaped)) => {
+                chars.next();
+                output.push(unescaped);
+            }
+            (c, _) => output.push(c),
+        }
+    }
+    output
+}
+
+// *****************************************************************************************
+// Markup
+// *****************************************************************************************
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarkupParseResult {
+    pub clean_text: String,
+    pub attributes: Vec<MarkupAttribute>,
+}
+
+/// A markup span such as `[wave]hello[/wave]`. `start` and `length` count characters in the
+/// clean text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Reflect, FromReflect)]
+pub struct MarkupAttribute {
+    pub name: String,
+    pub start: usize,
+    pub length: usize,
+    pub properties: HashMap<String, String>,
+}
+
+/// A zero-width markup tag such as `[sfx=clang/]` that fires an effect as the reveal reaches
+/// `char_index`, rather than styling text. See [`fire_inline_triggers`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineTrigger {
+    pub name: String,
+    pub properties: HashMap<String, String>,
+    /// The character in the clean text the trigger sits before.
+    pub char_index: usize,
+}
+
+/// Zero-width tags the typewriter interprets itself, which aren't reported as triggers.
+const DISPLAY_MARKUP: &[&str] = &["pause"];
+
+/// The zero-width attributes among `attributes` that are inline triggers, in text order.
+pub fn inline_triggers(attributes: &[MarkupAttribute]) -> Vec<InlineTrigger> {
+    let mut triggers: Vec<InlineTrigger> = attributes
+        .iter()
+        .filter(|attribute| attribute.length == 0)
+        .filter(|attribute| !DISPLAY_MARKUP.contains(&attribute.name.as_str()))
+        .map(|attribute| InlineTrigger {
+            name: attribute.name.clone(),
+            properties: attribute.properties.clone(),
+            char_index: attribute.start,
+        })
+        .collect();
+    triggers.sort_by_key(|trigger| trigger.char_index);
+    triggers
+}
+
+/// Strips markup tags from `input`, recording the spans they covered. Supports
+/// `[name]...[/name]`, `[name=value]`, `[name key=value]`, self-closing `[name/]`, the
+/// close-all `[/]`, and Yarn escapes such as `\[`, `\]` and `\n` (see [`unescape_text`]).
+pub fn parse_markup(input: &str) -> MarkupParseResult {
+    let mut clean_text = String::new();
+    let mut position = 0;
+    let mut attributes: Vec<MarkupAttribute> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().copied().and_then(unescaped_char).is_some() => {
+                let escaped = chars.next().unwrap();
+                clean_text.push(unescaped_char(escaped).unwrap());
+                position += 1;
+            }
+            '[' => {
+                let mut tag = String::new();
+                let mut terminated = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        terminated = true;
+                        break;
+                    }
+                    tag.push(c);
+                }
+                if !terminated {
+                    clean_text.push('[');
+                    clean_text.push_str(&tag);
+                    position += 1 + tag.chars().count();
+                    continue;
+                }
+
+                let tag = tag.trim();
+                if let Some(name) = tag.strip_prefix('/') {
+                    let name = name.trim();
+                    if name.is_empty() {
+                        for idx in open.drain(..) {
+                            attributes[idx].length = position - attributes[idx].start;
+                        }
+                    } else if let Some(stack_idx) =
+                        open.iter().rposition(|idx| attributes[*idx].name == name)
+                    {
+                        let idx = open.remove(stack_idx);
+                        attributes[idx].length = position - attributes[idx].start;
+                    }
+                } else if let Some(body) = tag.strip_suffix('/') {
+                    let (name, properties) = parse_markup_tag(body);
+                    attributes.push(MarkupAttribute {
+                        name,
+                        start: position,
+                        length: 0,
+                        properties,
+                    });
+                } else {
+                    let (name, properties) = parse_markup_tag(tag);
+                    open.push(attributes.len());
+                    attributes.push(MarkupAttribute {
+                        name,
+                        start: position,
+                        length: 0,
+                        properties,
+                    });
+                }
+            }
+            _ => {
+                clean_text.push(c);
+                position += 1;
+            }
+        }
+    }
+
+    // Unclosed tags run to the end of the line.
+    for idx in open {
+        attributes[idx].length = position - attributes[idx].start;
+    }
+
+    MarkupParseResult {
+        clean_text,
+        attributes,
+    }
+}
+
+fn parse_markup_tag(tag: &str) -> (String, HashMap<String, String>) {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in tag.trim().chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    let mut properties = HashMap::default();
+    let mut tokens = tokens.into_iter();
+    let name = match tokens.next() {
+        // `[color=red]` is shorthand for a property named after the tag.
+        Some(first) => match first.split_once('=') {
+            Some((name, value)) => {
+                properties.insert(name.to_string(), value.to_string());
+                name.to_string()
+            }
+            None => first,
+        },
+        None => String::new(),
+    };
+    for token in tokens {
+        match token.split_once('=') {
+            Some((key, value)) => properties.insert(key.to_string(), value.to_string()),
+            None => properties.insert(token, String::new()),
+        };
+    }
+    (name, properties)
+}
+
+// *****************************************************************************************
+// Observers
+// *****************************************************************************************
+/// Instrumentation hooks called as dialogue plays, e.g. for analytics. Every method defaults
+/// to doing nothing, so observers only implement what they record. Register observers with
+/// [`DialogueObservers::add`].
+pub trait DialogueObserver: Send + Sync {
+    /// A line was presented.
+    fn on_line(&mut self, _line_id: &str, _line: &DialogueLine) {}
+
+    fn on_option_selected(&mut self, _option: &SelectedOption) {}
+
+    /// A node started, including the start node of a dialogue.
+    fn on_node(&mut self, _node: &str) {}
+
+    /// The dialogue finished or was stopped. Carries the name of the last node.
+    fn on_complete(&mut self, _last_node: &str) {}
+}
+
+#[derive(Default)]
+pub struct DialogueObservers(Vec<Box<dyn DialogueObserver>>);
+
+impl DialogueObservers {
+    pub fn add(&mut self, observer: Box<dyn DialogueObserver>) {
+        self.0.push(observer);
+    }
+}
+
+/// Logs every hook to stdout, for debugging.
+pub struct PrintlnObserver;
+
+impl DialogueObserver for PrintlnObserver {
+    fn on_line(&mut self, line_id: &str, line: &DialogueLine) {
+        match &line.speaker {
+            Some(speaker) => println!("[dialogue] line {}: {}: {}", line_id, speaker, line.text),
+            None => println!("[dialogue] line {}: {}", line_id, line.text),
+        }
+    }
+
+    fn on_option_selected(&mut self, option: &SelectedOption) {
+        println!(
+            "[dialogue] option {} of {} selected: {}",
+            option.index, option.option_count, option.text
+        );
+    }
+
+    fn on_node(&mut self, node: &str) {
+        println!("[dialogue] node {}", node);
+    }
+
+    fn on_complete(&mut self, last_node: &str) {
+        println!("[dialogue] complete after {}", last_node);
+    }
+}
+
+fn notify_observers(world: &mut World, mut notify: impl FnMut(&mut dyn DialogueObserver)) {
+    if let Some(mut observers) = world.get_resource_mut::<DialogueObservers>() {
+        for observer in observers.0.iter_mut() {
+            notify(observer.as_mut());
+        }
+    }
+}
+
+// *****************************************************************************************
+// Typewriter
+// *****************************************************************************************
+/// How long to dwell before revealing each character of a line, for typewriter-style UIs.
+/// Built from `[pause=ms/]` markup, which waits at its position, and `[speed=factor]` spans,
+/// which scale the reveal rate of the characters they cover.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TypewriterPlan {
+    /// Seconds to wait before revealing each character. The extra last entry is the dwell
+    /// after the final character, from a trailing pause.
+    pub delays: Vec<f32>,
+}
+
+impl TypewriterPlan {
+    pub fn new(text: &str, attributes: &[MarkupAttribute], characters_per_second: f32) -> Self {
+        let char_count = text.chars().count();
+        let base_delay = match characters_per_second > 0.0 {
+            true => 1.0 / characters_per_second,
+            false => 0.0,
+        };
+        let mut delays = vec![base_delay; char_count];
+        delays.push(0.0);
+
+        for attribute in attributes {
+            match attribute.name.as_str() {
+                "speed" => {
+                    let factor = attribute
+                        .properties
+                        .get("speed")
+                        .and_then(|speed| speed.parse::<f32>().ok())
+                        .filter(|factor| *factor > 0.0);
+                    let end = (attribute.start + attribute.length).min(char_count);
+                    if let Some(factor) = factor {
+                        for delay in &mut delays[attribute.start.min(end)..end] {
+                            *delay /= factor;
+                        }
+                    }
+                }
+                "pause" => {
+                    let millis = attribute
+                        .properties
+                        .get("pause")
+                        .and_then(|pause| pause.parse::<f32>().ok())
+                        .unwrap_or_default();
+                    delays[attribute.start.min(char_count)] += millis.max(0.0) / 1000.0;
+                }
+                _ => {}
+            }
+        }
+        Self { delays }
+    }
+
+    /// Seconds until the whole line, including any trailing pause, has been revealed.
+    pub fn total_duration(&self) -> f32 {
+        self.delays.iter().sum()
+    }
+
+    /// How many characters are visible `elapsed` seconds into the reveal.
+    pub fn visible_characters(&self, elapsed: f32) -> usize {
+        let char_count = self.delays.len().saturating_sub(1);
+        let mut time = 0.0;
+        for (idx, delay) in self.delays.iter().take(char_count).enumerate() {
+            time += delay;
+            if time > elapsed {
+                return idx;
+            }
+        }
+        char_count
+    }
+}
+
+/// State for [`typewriter_system`]. Not added by [`DialoguePlugin`]; UIs that want a typewriter
+/// insert it and read `visible_characters`.
+pub struct DialogueTypewriter {
+    pub characters_per_second: f32,
+    pub plan: TypewriterPlan,
+    pub line_id: Option<String>,
+    pub elapsed: f32,
+    pub visible_characters: usize,
+    /// The line's inline triggers, and how many of them have fired.
+    pub triggers: Vec<InlineTrigger>,
+    pub fired_triggers: usize,
+}
+
+impl Default for DialogueTypewriter {
+    fn default() -> Self {
+        Self {
+            characters_per_second: 30.0,
+            plan: TypewriterPlan::default(),
+            line_id: None,
+            elapsed: 0.0,
+            visible_characters: 0,
+            triggers: Vec::new(),
+            fired_triggers: 0,
+        }
+    }
+}
+
+impl DialogueTypewriter {
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.plan.total_duration()
+    }
+
+    /// Reveals the rest of the line at once, e.g. when the player presses a button mid-reveal.
+    pub fn skip(&mut self) {
+        self.elapsed = self.plan.total_duration();
+        self.visible_characters = self.plan.delays.len().saturating_sub(1);
+    }
+}
+
+/// Sample typewriter that reveals the [`CurrentDialogueLine`] following its [`TypewriterPlan`].
+/// The reveal freezes while the dialogue is held, so games can stretch long pauses.
+pub fn typewriter_system(
+    time: Res<Time>,
+    holds: Res<DialogueHolds>,
+    current_line: Res<CurrentDialogueLine>,
+    mut typewriter: ResMut<DialogueTypewriter>,
+) {
+    let line = match &current_line.0 {
+        Some(line) => line,
+        None => {
+            if typewriter.line_id.is_some() {
+                *typewriter = DialogueTypewriter {
+                    characters_per_second: typewriter.characters_per_second,
+                    ..Default::default()
+                };
+            }
+            return;
+        }
+    };
+    if typewriter.line_id.as_ref() != Some(&line.line_id) {
+        typewriter.plan =
+            TypewriterPlan::new(&line.text, &line.markup, typewriter.characters_per_second);
+        typewriter.line_id = Some(line.line_id.clone());
+        typewriter.elapsed = 0.0;
+        typewriter.triggers = inline_triggers(&line.markup);
+        typewriter.fired_triggers = 0;
+    } else if !holds.is_held() && !typewriter.is_finished() {
+        typewriter.elapsed += time.delta_seconds();
+    }
+    typewriter.visible_characters = typewriter.plan.visible_characters(typewriter.elapsed);
+}
+
+/// Runs the registered handler for each inline trigger the [`DialogueTypewriter`] has revealed.
+/// A trigger at index `n` fires once the `n + 1`th character is visible, and triggers after the
+/// last character fire when the reveal finishes. Order it after [`typewriter_system`].
+pub fn fire_inline_triggers(world: &mut World) {
+    let due: Vec<InlineTrigger> = match world.get_resource_mut::<DialogueTypewriter>() {
+        Some(mut typewriter) => {
+            let finished = typewriter.is_finished();
+            let visible = typewriter.visible_characters;
+            let due: Vec<InlineTrigger> = typewriter.triggers[typewriter.fired_triggers..]
+                .iter()
+                .take_while(|trigger| trigger.char_index < visible || finished)
+                .cloned()
+                .collect();
+            typewriter.fired_triggers += due.len();
+            due
+        }
+        None => return,
+    };
+    for trigger in due {
+        let handler = world
+            .get_resource::<InlineTriggerHandlers>()
+            .and_then(|handlers| handlers.get(&trigger.name).copied());
+        match handler {
+            Some(handler) => handler(world, &trigger),
+            None => warn!("No handler for inline trigger {}!", trigger.name),
+        }
+    }
+}
+
+// *****************************************************************************************
+// Run Conditions
+// *****************************************************************************************
+pub fn run_if_dialogue_queue_occupied(queue: Res<DialogueQueue>) -> ShouldRun {
+    match !queue.is_empty() {
+        true => ShouldRun::Yes,
+        false => ShouldRun::No,
+    }
+}
+
+pub fn run_if_no_dialogue_hold(holds: Res<DialogueHolds>) -> ShouldRun {
+    match holds.is_held() {
+        false => ShouldRun::Yes,
+        true => ShouldRun::No,
+    }
+}
+
+pub fn run_if_dialogue_running(runner: Res<DialogueRunner>) -> ShouldRun {
+    match runner.phase() {
+        DialoguePhase::Idle | DialoguePhase::Completed => ShouldRun::No,
+        _ => ShouldRun::Yes,
+    }
+}
+
+pub fn run_if_waiting_on_options(runner: Res<DialogueRunner>) -> ShouldRun {
+    match runner.phase() {
+        DialoguePhase::AwaitingOptionSelection => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
+pub fn run_if_presenting_line(runner: Res<DialogueRunner>) -> ShouldRun {
+    match runner.phase() {
+        DialoguePhase::PresentingLine => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
+/// Runs while a conversation is active in any sense: the runner isn't idle, dialogue is queued,
+/// or the dialogue is held or waiting. Meant for gating player movement and interaction.
+pub fn run_if_dialogue_busy(
+    runner: Res<DialogueRunner>,
+    queue: Res<DialogueQueue>,
+    holds: Res<DialogueHolds>,
+) -> ShouldRun {
+    let busy = runner.state != DialogueRunnerState::Idle
+        || !queue.is_empty()
+        || holds.is_held()
+        || runner.wait.is_some();
+    match busy {
+        true => ShouldRun::Yes,
+        false => ShouldRun::No,
+    }
+}
+
+/// The inverse of [`run_if_dialogue_busy`].
+pub fn run_if_dialogue_free(
+    runner: Res<DialogueRunner>,
+    queue: Res<DialogueQueue>,
+    holds: Res<DialogueHolds>,
+) -> ShouldRun {
+    match run_if_dialogue_busy(runner, queue, holds) {
+        ShouldRun::Yes => ShouldRun::No,
+        _ => ShouldRun::Yes,
+    }
+}
+
+pub struct ExecuteDialogueCommand {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl Command for ExecuteDialogueCommand {
+    fn write(self, world: &mut World) {
+        let action = world
+            .get_resource::<DialogueCommandInterceptor>()
+            .map(|interceptor| (interceptor.0)(&self.command, &self.args))
+            .unwrap_or_default();
+        let (name, args) = match action {
+            CommandAction::Run => (self.command, self.args),
+            CommandAction::Skip => {
+                debug!("Command {} skipped by the interceptor", self.command);
+                return;
+            }
+            CommandAction::Replace { name, args } => (name, args),
+        };
+        send_dialogue_event(world, EventCommandRun {
+            name: name.clone(),
+            args: args.clone(),
+        });
+        let scoped = world
+            .get_resource::<DialogueRunner>()
+            .and_then(|runner| runner.scoped_commands.as_ref()?.get(&name).cloned());
+        let blocking = world
+            .get_resource::<DialogueBlockingCommands>()
+            .and_then(|commands| commands.get(&name).copied());
+        let command = world.resource::<DialogueCommands>().get(&name).cloned();
+        if let Some(com) = scoped {
+            com.call(world, args);
+        } else if let Some(com) = blocking {
+            let token = com(world, args);
+            // Handlers may finish synchronously, in which case there's nothing to wait on.
+            let mut blocking = world.resource_mut::<BlockingDialogueCommands>();
+            if blocking.is_pending(token) {
+                blocking.holding = true;
+                world.resource_mut::<DialogueHolds>().acquire(BLOCKING_COMMAND_HOLD);
+            }
+        } else if let Some(com) = command {
+            com.call(world, args);
+        } else if let Some(fallback) = world.get_resource::<DialogueCommandFallback>() {
+            let fallback = fallback.0;
+            fallback(world, name, args);
+        } else {
+            warn!("Unknown dialogue command {}!", name);
+            send_dialogue_event(world, EventUnknownCommand { name, args });
+        }
+    }
+}
+
+pub struct SelectDialogueOptionCommand {
+    /// The [`DialogueOption::index`] of a presented option, which stays the VM's original index
+    /// even when unavailable options are hidden.
+    pub index: usize,
+}
+
+impl Command for SelectDialogueOptionCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.get_resource_mut::<DialogueRunner>().unwrap();
+        if !matches!(runner.vm.execution_state, ExecutionState::WaitingOnOptionSelection) {
+            warn!("SelectDialogueOptionCommand: dialogue is not waiting on an option selection!");
+            return;
+        }
+        let presented = match &runner.state {
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(options)) => options
+                .iter()
+                .find(|option| option.index == self.index)
+                .map(|option| (option.clone(), options.len())),
+            _ => None,
+        };
+        let (option, option_count) = match presented {
+            Some(presented) => presented,
+            None => {
+                warn!(
+                    "SelectDialogueOptionCommand: option {} was not presented!",
+                    self.index
+                );
+                return;
+            }
+        };
+        if !option.is_available {
+            warn!("SelectDialogueOptionCommand: option {} is unavailable!", self.index);
+            return;
+        }
+        let vm_index = match u32::try_from(self.index) {
+            Ok(vm_index) => vm_index,
+            Err(_) => {
+                warn!("SelectDialogueOptionCommand: option {} is out of range!", self.index);
+                return;
+            }
+        };
+        runner.vm.set_selected_option(vm_index);
+        runner.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null);
+        runner.option_timer = None;
+        let selected = SelectedOption {
+            index: option.index,
+            text: option.text.clone(),
+            line_id: option.line_id,
+            option_count,
+        };
+        runner.last_selected_option = Some(selected.clone());
+        world.resource_mut::<DialogueHistory>().push(HistoryEntry::OptionChosen {
+            text: option.text,
+            index: option.index,
+        });
+        notify_observers(world, |observer| observer.on_option_selected(&selected));
+        send_dialogue_event(world, EventOptionSelected(selected));
+    }
+}
+
+pub struct SetYarnVariableCommand {
+    pub name: String,
+    pub value: YarnValue,
+}
+
+impl Command for SetYarnVariableCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.get_resource_mut::<DialogueRunner>().unwrap();
+        runner.set_variable(self.name, self.value);
+    }
+}
+
+/// Sets several variables in one command, so no runner update can see only some of them.
+/// When `then_queue` is given the dialogue is queued after the variables are set, so its start
+/// node always sees them.
+pub struct SetYarnVariablesCommand {
+    pub values: Vec<(String, YarnValue)>,
+    pub then_queue: Option<AddDialogueToQueueCommand>,
+}
+
+impl Command for SetYarnVariablesCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        for (name, value) in self.values {
+            runner.set_variable(name, value);
+        }
+        if let Some(queue) = self.then_queue {
+            queue.write(world);
+        }
+    }
+}
+
+/// Replaces the runner's variable storage, e.g. with a loaded save for a new chapter. Only
+/// applied while the runner is idle, so a running branch never sees its variables change
+/// underneath it.
+pub struct SetVariableStorageCommand(pub Box<dyn VariableStorage>);
+
+impl Command for SetVariableStorageCommand {
+    fn write(self, world: &mut World) {
+        let runner = world.resource::<DialogueRunner>();
+        if runner.state != DialogueRunnerState::Idle {
+            warn!("SetVariableStorageCommand: dialogue is running, storage not replaced!");
+            return;
+        }
+        runner.variables.replace(self.0);
+    }
+}
+
+/// Resets variables to the defaults the last loaded program declares, clearing every other
+/// variable so it reads as null until assigned again. Only applied while the runner is idle.
+pub struct ResetVariablesCommand;
+
+impl Command for ResetVariablesCommand {
+    fn write(self, world: &mut World) {
+        let runner = world.resource::<DialogueRunner>();
+        if runner.state != DialogueRunnerState::Idle {
+            warn!("ResetVariablesCommand: dialogue is running, variables not reset!");
+            return;
+        }
+        runner.variables.clear();
+        runner.seed_initial_values();
+    }
+}
+
+/// Aborts the running dialogue, optionally cancelling everything still queued behind it.
+pub struct StopDialogueCommand {
+    pub clear_queue: bool,
+}
+
+impl Command for StopDialogueCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        if runner.state == DialogueRunnerState::Idle {
+            return;
+        }
+        runner.stop_vm();
+        runner.state = DialogueRunnerState::Idle;
+        runner.idle_reason = IdleReason::Stopped;
+        runner.paused = false;
+        runner.wait = None;
+        runner.scoped_commands = None;
+        let interrupted_node = runner.current_node.take().unwrap_or_default();
+        if self.clear_queue {
+            world.resource_mut::<DialogueQueue>().clear();
+        }
+        notify_observers(world, |observer| observer.on_complete(&interrupted_node));
+        send_dialogue_event(world, EventDialogueCompleted(interrupted_node, IdleReason::Stopped));
+    }
+}
+
+/// Replays the current dialogue from its start node. When idle, the most recently started
+/// dialogue is queued again instead.
+pub struct RestartDialogueCommand;
+
+impl Command for RestartDialogueCommand {
+    fn write(self, world: &mut World) {
+        let runner = world.resource::<DialogueRunner>();
+        let start_node = match runner.start_node.clone() {
+            Some(start_node) => start_node,
+            None => {
+                warn!("RestartDialogueCommand: no dialogue has been started!");
+                return;
+            }
+        };
+        if runner.state == DialogueRunnerState::Idle {
+            let entry = queue_entry_from_runner(world, start_node);
+            world.resource_mut::<DialogueQueue>().push_back(entry);
+            return;
+        }
+
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        runner.wait = None;
+        runner.last_selected_option = None;
+        JumpToNodeCommand { node: start_node }.write(world);
+    }
+}
+
+/// Moves past the currently presented line so the next `update_runner` tick continues.
+pub struct AdvanceDialogueCommand;
+
+impl Command for AdvanceDialogueCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        if matches!(runner.vm.execution_state, ExecutionState::WaitingOnOptionSelection) {
+            warn!("AdvanceDialogueCommand: dialogue is waiting on an option selection!");
+            return;
+        }
+        if let DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(_)) = runner.state {
+            runner.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null);
+            runner.auto_advance = None;
+        }
+    }
+}
+
+/// Switches [`SkipSeenMode`] between on and off.
+pub struct ToggleSkipSeenCommand;
+
+impl Command for ToggleSkipSeenCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        runner.skip_seen = match runner.skip_seen {
+            SkipSeenMode::Off => SkipSeenMode::On,
+            SkipSeenMode::On => SkipSeenMode::Off,
+        };
+    }
+}
+
+/// Continues dialogue paused by `<<yield>>`.
+pub struct ResumeDialogueCommand;
+
+impl Command for ResumeDialogueCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        if !runner.paused {
+            warn!("ResumeDialogueCommand: dialogue isn't paused!");
+            return;
+        }
+        runner.paused = false;
+    }
+}
+
+/// Runs the dialogue forward until it reaches options, a node boundary, or completion. Commands
+/// passed along the way are still executed.
+pub struct SkipToChoiceCommand;
+
+impl Command for SkipToChoiceCommand {
+    fn write(self, world: &mut World) {
+        let runner = world.resource::<DialogueRunner>();
+        if matches!(runner.vm.execution_state, ExecutionState::WaitingOnOptionSelection) {
+            warn!("SkipToChoiceCommand: dialogue is waiting on an option selection!");
+            return;
+        }
+        loop {
+            // Lines are skipped even when they'd otherwise wait for a manual advance.
+            AdvanceDialogueCommand.write(world);
+            if !matches!(step_dialogue(world), Some(DialogueStep::Line | DialogueStep::Command)) {
+                break;
+            }
+            // A command may have asked for the dialogue to hold.
+            if world.resource::<DialogueHolds>().is_held()
+                || world.resource::<DialogueRunner>().wait.is_some()
+            {
+                break;
+            }
+        }
+    }
+}
+
+/// Holds the dialogue under `key` until a [`ReleaseDialogueHoldCommand`] with the same key.
+pub struct AcquireDialogueHoldCommand {
+    pub key: String,
+}
+
+impl Command for AcquireDialogueHoldCommand {
+    fn write(self, world: &mut World) {
+        world.resource_mut::<DialogueHolds>().acquire(self.key);
+    }
+}
+
+/// Releases the hold acquired under `key`. Holds under other keys are unaffected.
+pub struct ReleaseDialogueHoldCommand {
+    pub key: String,
+}
+
+impl Command for ReleaseDialogueHoldCommand {
+    fn write(self, world: &mut World) {
+        if !world.resource_mut::<DialogueHolds>().release(&self.key) {
+            warn!("ReleaseDialogueHoldCommand: {:?} was not held!", self.key);
+        }
+    }
+}
+
+/// Moves the running dialogue to `node`, starting the runner if it was idle.
+pub struct JumpToNodeCommand {
+    pub node: String,
+}
+
+impl Command for JumpToNodeCommand {
+    fn write(self, world: &mut World) {
+        let mut runner = world.resource_mut::<DialogueRunner>();
+        if !runner.vm.program.nodes.contains_key(&self.node) {
+            warn!("JumpToNodeCommand: node {} does not exist!", self.node);
+            let available = runner.vm.program.nodes.keys().cloned().collect();
+            send_dialogue_event(world, EventMissingNode {
+                requested: self.node,
+                available,
+            });
+            return;
+        }
+        runner.vm.set_node(&self.node);
+        runner.mark_visited(&self.node);
+        runner.current_node = Some(self.node.clone());
+        runner.current_line = None;
+        runner.state = DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null);
+        let tags = runner.current_node_tags().to_vec();
+        world.resource_mut::<DialogueHistory>().push(HistoryEntry::NodeBoundary {
+            name: self.node.clone(),
+        });
+        send_dialogue_event(world, EventNodeStarted(self.node, tags));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yharnam::yarn_proto::{instruction::OpCode, operand::Value, Instruction, Node, Operand};
+
+    fn instruction(opcode: OpCode, operands: Vec<Value>) -> Instruction {
+        Instruction {
+            opcode: opcode as i32,
+            operands: operands.into_iter().map(|value| Operand { value: Some(value) }).collect(),
+        }
+    }
+
+    fn string(value: &str) -> Value {
+        Value::StringValue(value.to_string())
+    }
+
+    fn run_command(text: &str) -> Instruction {
+        instruction(OpCode::RunCommand, vec![string(text)])
+    }
+
+    fn run_line(id: &str, substitutions: usize) -> Instruction {
+        instruction(OpCode::RunLine, vec![string(id), Value::FloatValue(substitutions as f32)])
+    }
+
+    fn line_info(id: &str, text: &str) -> LineInfo {
+        LineInfo {
+            id: id.to_string(),
+            text: text.to_string(),
+            file: String::new(),
+            node: String::new(),
+            line_number: 0,
+        }
+    }
+
+    fn stop() -> Instruction {
+        instruction(OpCode::Stop, Vec::new())
+    }
+
+    fn add_option(line_id: &str, label: &str) -> Instruction {
+        instruction(OpCode::AddOption, vec![string(line_id), string(label)])
+    }
+
+    fn node(name: &str, tags: &[&str], instructions: Vec<Instruction>) -> Node {
+        Node {
+            name: name.to_string(),
+            instructions,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn resolved_entry(
+        program: Program,
+        table: Vec<LineInfo>,
+        start_node: Option<&str>,
+    ) -> ResolvedQueueEntry {
+        ResolvedQueueEntry {
+            path: PathBuf::from("test.yarnc"),
+            program: YarnProgram::new(program),
+            table: YarnStringTable(Arc::new(table)),
+            fallback_table: None,
+            metadata: YarnLineMetadata::default(),
+            start_node: start_node.map(str::to_string),
+            handles: None,
+            language_fallback: None,
+            extra_commands: None,
+        }
+    }
+
+    /// Gives `world` an asset server and the dialogue asset collections, as `AssetPlugin` would.
+    fn add_dialogue_assets(world: &mut World) {
+        use bevy::{
+            asset::{AssetStage, FileAssetIo},
+            tasks::TaskPool,
+        };
+
+        let mut app = App::new();
+        app.add_stage(AssetStage::LoadAssets, SystemStage::parallel())
+            .add_stage(AssetStage::AssetEvents, SystemStage::parallel())
+            .insert_resource(AssetServer::new(FileAssetIo::new("assets", false), TaskPool::new()))
+            .add_asset::<YarnProgram>()
+            .add_asset::<YarnStringTable>()
+            .add_asset::<YarnLineMetadata>();
+        world.insert_resource(app.world.remove_resource::<AssetServer>().unwrap());
+        world.insert_resource(app.world.remove_resource::<Assets<YarnProgram>>().unwrap());
+        world.insert_resource(app.world.remove_resource::<Assets<YarnStringTable>>().unwrap());
+        world.insert_resource(app.world.remove_resource::<Assets<YarnLineMetadata>>().unwrap());
+    }
+
+    /// A queue entry for `program` and `table`, added to `world`'s asset collections as if they
+    /// had finished loading from `path`.
+    fn loaded_entry(
+        world: &mut World,
+        path: &str,
+        program: Program,
+        table: Vec<LineInfo>,
+    ) -> DialogueQueueEntry {
+        let program = world.resource_mut::<Assets<YarnProgram>>().add(YarnProgram::new(program));
+        let table = YarnStringTable(Arc::new(table));
+        let table = world.resource_mut::<Assets<YarnStringTable>>().add(table);
+        DialogueQueueEntry {
+            path: PathBuf::from(path),
+            program,
+            table,
+            fallback_table: None,
+            metadata: None,
+            start_node: None,
+            language: None,
+            load_retries: 0,
+            extra_commands: None,
+            condition: None,
+        }
+    }
+
+    /// `program` encoded as the compiler writes it, with `initial_values` as declared defaults.
+    fn compiled_program(program: Program, initial_values: &[(&str, Value)]) -> Vec<u8> {
+        let initial_values = initial_values
+            .iter()
+            .map(|(name, value)| (name.to_string(), Operand { value: Some(value.clone()) }))
+            .collect();
+        let mut bytes = Vec::new();
+        program.encode(&mut bytes).unwrap();
+        ProgramInitialValues { initial_values }.encode(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// A fresh directory under the system temp dir holding `files`.
+    fn asset_dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!("bevy_yarn_spinner_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (file, contents) in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    /// A headless app loading assets from `root`, with `plugin` added.
+    fn dialogue_app(root: &Path, plugin: DialoguePlugin) -> App {
+        use bevy::asset::{AssetPlugin, AssetServerSettings};
+
+        let mut app = App::new();
+        app.insert_resource(AssetServerSettings {
+            asset_folder: root.to_string_lossy().into_owned(),
+            watch_for_changes: false,
+        })
+        .add_plugins(MinimalPlugins)
+        .add_plugin(AssetPlugin)
+        .add_plugin(plugin);
+        app
+    }
+
+    /// Updates `app` until `done` holds, failing after a few seconds.
+    fn update_until(app: &mut App, done: impl Fn(&World) -> bool) {
+        let started = std::time::Instant::now();
+        while !done(&app.world) {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out updating the app");
+            app.update();
+            std::thread::yield_now();
+        }
+    }
+
+    const TABLE_HEADER: &str = "id,text,file,node,lineNumber\n";
+
+    fn line_id(step: StepResult) -> String {
+        match step {
+            StepResult::Line { line_id, .. } => line_id,
+            step => panic!("expected a line, got {:?}", step),
+        }
+    }
+
+    fn program(nodes: Vec<Node>) -> Program {
+        Program {
+            name: "test".to_string(),
+            nodes: nodes.into_iter().map(|node| (node.name.clone(), node)).collect(),
+        }
+    }
+
+    #[test]
+    fn interceptor_runs_before_command_event() {
+        #[derive(Default)]
+        struct Ran(Vec<String>);
+        fn record(world: &mut World, name: &str, args: Vec<String>) {
+            world.resource_mut::<Ran>().0.push(format!("{} {}", name, args.join(" ")));
+        }
+
+        let start = node(
+            "Start",
+            &[],
+            vec![run_command("secret"), run_command("shake 2"), stop()],
+        );
+        let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+        let world = driver.world_mut();
+        world.init_resource::<Ran>();
+        world.register_dialogue_command("secret", |world, args| record(world, "secret", args));
+        world.register_dialogue_command("shake", |world, args| record(world, "shake", args));
+        world.register_dialogue_command("rumble", |world, args| record(world, "rumble", args));
+        world.set_command_interceptor(Box::new(|name, args| match name {
+            "secret" => CommandAction::Skip,
+            "shake" => CommandAction::Replace {
+                name: "rumble".to_string(),
+                args: args.iter().map(|arg| format!("{}0", arg)).collect(),
+            },
+            _ => CommandAction::Run,
+        }));
+        let command_events = |world: &mut World| -> Vec<(String, Vec<String>)> {
+            world
+                .resource_mut::<Events<EventCommandRun>>()
+                .drain()
+                .map(|EventCommandRun { name, args }| (name, args))
+                .collect()
+        };
+
+        assert!(driver.start("Start"));
+        let world = driver.world_mut();
+        // The vetoed command neither runs nor is reported, not even as unknown.
+        assert_eq!(step_dialogue(world), Some(DialogueStep::Command));
+        assert!(command_events(world).is_empty());
+        assert!(world.resource_mut::<Events<EventUnknownCommand>>().drain().next().is_none());
+        assert!(world.resource::<Ran>().0.is_empty());
+
+        assert_eq!(step_dialogue(world), Some(DialogueStep::Command));
+        let rumble = ("rumble".to_string(), vec!["20".to_string()]);
+        assert_eq!(command_events(world), [rumble]);
+        assert_eq!(world.resource::<Ran>().0, ["rumble 20"]);
+        assert_eq!(step_dialogue(world), Some(DialogueStep::Complete));
+    }
+
+    #[test]
+    fn substituted_values_are_literal() {
+        let substitutions = ["a{b}c".to_string()];
+        assert_eq!(apply_substitutions("{0} done", &substitutions), "a{b}c done");
+
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushString, vec![string("Bob: [b]hi[/b] \\n")]),
+                run_line("line:1", 1),
+                stop(),
+            ],
+        );
+        let table = vec![line_info("line:1", "Guard: {0} done")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+
+        assert!(driver.start("Start"));
+        match driver.step() {
+            StepResult::Line { line, .. } => {
+                assert_eq!(line.speaker.as_deref(), Some("Guard"));
+                assert_eq!(line.text, "Bob: [b]hi[/b] \\n done");
+                assert!(line.attributes.is_empty());
+            }
+            step => panic!("expected a line, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn dialogue_functions_receive_arguments() {
+        fn add(_: &mut World, args: Vec<YarnValue>) -> YarnValue {
+            YarnValue::Number(args.iter().map(YarnValue::as_number).sum())
+        }
+
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(2.0)]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(3.0)]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(2.0)]),
+                instruction(OpCode::CallFunc, vec![string("add")]),
+                instruction(OpCode::StoreVariable, vec![string("$sum")]),
+                instruction(OpCode::Pop, Vec::new()),
+                stop(),
+            ],
+        );
+        let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+        driver.world_mut().register_dialogue_function("add", add);
+
+        assert!(driver.start("Start"));
+        assert_eq!(driver.step(), StepResult::Complete);
+        assert_eq!(driver.runner().get_variable("$sum"), Some(YarnValue::Number(5.0)));
+    }
+
+    #[test]
+    fn save_state_round_trips_pending_options() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(7.0)]),
+                instruction(OpCode::StoreVariable, vec![string("$gold")]),
+                instruction(OpCode::Pop, Vec::new()),
+                add_option("opt:a", "A"),
+                add_option("opt:b", "B"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:a", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:b", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("A".to_string(), 7), ("B".to_string(), 10)].into_iter().collect();
+        let table = || {
+            vec![
+                line_info("opt:a", "Go left"),
+                line_info("opt:b", "Go right"),
+                line_info("line:a", "Left it is."),
+                line_info("line:b", "Right it is."),
+            ]
+        };
+        let mut driver = DialogueDriver::new(program(vec![start.clone()]), table());
+        assert!(driver.start("Start"));
+        let options = match driver.step() {
+            StepResult::Options(options) => options,
+            step => panic!("expected options, got {:?}", step),
+        };
+        let save = driver.runner().save_state();
+        assert!(save.waiting_on_options);
+        assert_eq!(save.current_options.len(), 2);
+
+        let mut restored = DialogueDriver::new(program(vec![start]), table());
+        restored.runner_mut().restore_state(save);
+        assert_eq!(restored.runner().get_variable("$gold"), Some(YarnValue::Number(7.0)));
+        assert_eq!(restored.step(), StepResult::Options(options));
+
+        restored.select(1);
+        match restored.step() {
+            StepResult::Line { line_id, line } => {
+                assert_eq!(line_id, "line:b");
+                assert_eq!(line.text, "Right it is.");
+            }
+            step => panic!("expected a line, got {:?}", step),
+        }
+        assert_eq!(restored.step(), StepResult::Complete);
+    }
+
+    #[test]
+    fn driver_steps_through_a_conversation() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                run_line("line:hello", 0),
+                run_command("fade_out 0.5"),
+                add_option("opt:stay", "Stay"),
+                add_option("opt:leave", "Leave"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:stay", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:leave", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("Stay".to_string(), 6), ("Leave".to_string(), 9)].into_iter().collect();
+        let table = vec![
+            line_info("line:hello", "Guard: Halt!"),
+            line_info("opt:stay", "Stay"),
+            line_info("opt:leave", "Leave"),
+            line_info("line:stay", "Guard: Good."),
+            line_info("line:leave", "Guard: Off you go."),
+        ];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+
+        assert!(!driver.start("Missing"));
+        assert!(driver.start("Start"));
+        match driver.step() {
+            StepResult::Line { line_id, line } => {
+                assert_eq!(line_id, "line:hello");
+                assert_eq!(line.speaker.as_deref(), Some("Guard"));
+                assert_eq!(line.text, "Halt!");
+            }
+            step => panic!("expected a line, got {:?}", step),
+        }
+        assert_eq!(driver.step(), StepResult::Command {
+            name: "fade_out".to_string(),
+            args: vec!["0.5".to_string()],
+        });
+
+        let options = match driver.step() {
+            StepResult::Options(options) => options,
+            step => panic!("expected options, got {:?}", step),
+        };
+        let texts: Vec<_> = options.iter().map(|option| option.text.as_str()).collect();
+        assert_eq!(texts, ["Stay", "Leave"]);
+        // Options are returned again until one is picked.
+        assert_eq!(driver.step(), StepResult::Options(options.clone()));
+
+        driver.select(options[1].index);
+        match driver.step() {
+            StepResult::Line { line_id, line } => {
+                assert_eq!(line_id, "line:leave");
+                assert_eq!(line.text, "Off you go.");
+            }
+            step => panic!("expected a line, got {:?}", step),
+        }
+        assert_eq!(driver.step(), StepResult::Complete);
+    }
+
+    #[test]
+    fn node_groups_pick_by_condition_and_priority() {
+        let nodes = vec![
+            node("Greet_default", &["group:Greet"], vec![stop()]),
+            node("Greet_angry", &["group:Greet", "when:$angry", "priority:1"], vec![stop()]),
+            node("Greet_calm", &["group:Greet", "when:!$angry"], vec![stop()]),
+            node("Greet_rich", &["group:Greet", "when:$gold=100"], vec![stop()]),
+            node("Secret", &["group:Hidden", "when:$found"], vec![stop()]),
+        ];
+        let mut driver = DialogueDriver::new(program(nodes), Vec::new());
+        let resolve = |driver: &DialogueDriver, name| driver.runner().resolve_node_group(name);
+
+        // Both conditionless and `!$angry` hold; the node with more conditions wins.
+        assert_eq!(resolve(&driver, "Greet").as_deref(), Some("Greet_calm"));
+
+        driver.runner_mut().set_variable("$angry", YarnValue::Bool(true));
+        assert_eq!(resolve(&driver, "Greet").as_deref(), Some("Greet_angry"));
+
+        // Equal priority and condition counts fall back to the first name.
+        driver.runner_mut().set_variable("$angry", YarnValue::Bool(false));
+        driver.runner_mut().set_variable("$gold", YarnValue::Number(100.0));
+        assert_eq!(resolve(&driver, "Greet").as_deref(), Some("Greet_calm"));
+
+        assert_eq!(resolve(&driver, "Hidden"), None);
+        assert_eq!(resolve(&driver, "Greet_rich").as_deref(), Some("Greet_rich"));
+        assert_eq!(resolve(&driver, "Missing"), None);
+    }
+
+    #[test]
+    fn substitutions_are_positional() {
+        let substitutions = ["tea".to_string(), "cake".to_string()];
+        assert_eq!(apply_substitutions("{1} and {0}", &substitutions), "cake and tea");
+        assert_eq!(apply_substitutions("{0} {0}", &substitutions), "tea tea");
+        assert_eq!(apply_substitutions("{0} or {2}", &substitutions), "tea or {2}");
+    }
+
+    #[test]
+    fn command_arguments_respect_quotes_and_escapes() {
+        let split = |text| split_command_arguments(text);
+        assert_eq!(split(r#"spawn "Big Goblin" 3"#), ["spawn", "Big Goblin", "3"]);
+        assert_eq!(split("walk  north   "), ["walk", "north"]);
+        assert_eq!(split(r#"say "He said \"hi\"""#), ["say", r#"He said "hi""#]);
+        assert_eq!(split(r#"path C:\\games \n"#), ["path", r"C:\games", r"\n"]);
+        assert_eq!(split(r#"name """#), ["name", ""]);
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn missing_lines_present_a_placeholder() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                run_line("line:gone", 0),
+                add_option("opt:gone", "End"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                stop(),
+            ],
+        );
+        start.labels = [("End".to_string(), 4)].into_iter().collect();
+        let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+
+        assert!(driver.start("Start"));
+        match driver.step() {
+            StepResult::Line { line, .. } => assert_eq!(line.text, "[missing: line:gone]"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+        match driver.step() {
+            StepResult::Options(options) => assert_eq!(options[0].text, "[missing: opt:gone]"),
+            step => panic!("expected options, got {:?}", step),
+        }
+        let missing: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventMissingLine>>()
+            .drain()
+            .map(|event| event.line_id)
+            .collect();
+        assert_eq!(missing, ["line:gone", "opt:gone"]);
+
+        driver.select(0);
+        assert_eq!(driver.step(), StepResult::Complete);
+    }
+
+    #[test]
+    fn jump_to_node_resumes_in_target() {
+        let nodes = vec![
+            node("Hub", &[], vec![run_line("line:hub", 0), run_line("line:hub2", 0), stop()]),
+            node("Shop", &[], vec![run_line("line:shop", 0), stop()]),
+        ];
+        let table = vec![
+            line_info("line:hub", "Welcome back."),
+            line_info("line:hub2", "Anything else?"),
+            line_info("line:shop", "What'll it be?"),
+        ];
+        let mut driver = DialogueDriver::new(program(nodes), table);
+
+        assert!(driver.start("Hub"));
+        assert_eq!(line_id(driver.step()), "line:hub");
+
+        JumpToNodeCommand { node: "Shop".to_string() }.write(driver.world_mut());
+        assert_eq!(driver.runner().current_node().as_deref(), Some("Shop"));
+        assert_eq!(line_id(driver.step()), "line:shop");
+
+        JumpToNodeCommand { node: "Nowhere".to_string() }.write(driver.world_mut());
+        let missing: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventMissingNode>>()
+            .drain()
+            .map(|event| event.requested)
+            .collect();
+        assert_eq!(missing, ["Nowhere"]);
+        assert_eq!(driver.runner().current_node().as_deref(), Some("Shop"));
+        assert_eq!(driver.step(), StepResult::Complete);
+    }
+
+    #[test]
+    fn configured_default_start_node_is_used() {
+        let nodes = || {
+            program(vec![
+                node("Begin", &[], vec![run_line("line:begin", 0), stop()]),
+                node("Other", &[], vec![stop()]),
+            ])
+        };
+        let table = || vec![line_info("line:begin", "Once upon a time.")];
+        let plugin = DialoguePluginBuilder::default()
+            .default_start_node("Begin")
+            .build()
+            .unwrap();
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+
+        let mut runner = driver.runner_mut();
+        assert!(runner.setup(resolved_entry(nodes(), table(), None)).is_err());
+        runner.default_start_node = plugin.default_start_node;
+        assert!(runner.setup(resolved_entry(nodes(), table(), None)).is_ok());
+        assert_eq!(runner.current_node().as_deref(), Some("Begin"));
+        match driver.step() {
+            StepResult::Line { line_id, .. } => assert_eq!(line_id, "line:begin"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+
+        // An entry's own start node still wins.
+        let mut runner = driver.runner_mut();
+        assert!(runner.setup(resolved_entry(nodes(), table(), Some("Other"))).is_ok());
+        assert_eq!(runner.current_node().as_deref(), Some("Other"));
+    }
+
+    #[test]
+    fn runner_states_compare_their_entries() {
+        let presenting = |text: &str| {
+            let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+            let table = vec![line_info("line:1", text)];
+            let mut driver = DialogueDriver::new(program(vec![start]), table);
+            assert!(driver.start("Start"));
+            driver.step();
+            driver.runner().state.clone()
+        };
+        let greeting = presenting("Hello.");
+        assert_eq!(greeting, presenting("Hello."));
+        assert_ne!(greeting, presenting("Goodbye."));
+        assert_ne!(greeting, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null));
+        assert_ne!(
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Options(Vec::new())),
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null),
+        );
+        assert_eq!(DialogueRunnerState::Idle, DialogueRunnerState::Idle);
+    }
+
+    #[test]
+    fn queued_entry_waits_for_late_assets() {
+        let first = node("Start", &[], vec![run_line("line:first", 0), stop()]);
+        let table = vec![line_info("line:first", "First.")];
+        let mut driver = DialogueDriver::new(program(vec![first]), table);
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+
+        let id = HandleId::random::<YarnProgram>();
+        let late_program = world.resource::<Assets<YarnProgram>>().get_handle(id);
+        let table = YarnStringTable(Arc::new(vec![line_info("line:second", "Second.")]));
+        let table = world.resource_mut::<Assets<YarnStringTable>>().add(table);
+        world.resource_mut::<DialogueQueue>().push_back(DialogueQueueEntry {
+            path: PathBuf::from("second.yarnc"),
+            program: late_program.clone(),
+            table,
+            fallback_table: None,
+            metadata: None,
+            start_node: None,
+            language: None,
+            load_retries: 0,
+            extra_commands: None,
+            condition: None,
+        });
+
+        assert!(driver.start("Start"));
+        assert!(matches!(driver.step(), StepResult::Line { .. }));
+        // Completing while the next program is still loading leaves it queued.
+        assert_eq!(driver.step(), StepResult::Complete);
+        assert_eq!(driver.world_mut().resource::<DialogueQueue>().len(), 1);
+        check_queue(driver.world_mut());
+        assert_eq!(driver.runner().state, DialogueRunnerState::Idle);
+
+        let second = node("Start", &[], vec![run_line("line:second", 0), stop()]);
+        driver
+            .world_mut()
+            .resource_mut::<Assets<YarnProgram>>()
+            .set_untracked(late_program, YarnProgram::new(program(vec![second])));
+        check_queue(driver.world_mut());
+        assert!(driver.world_mut().resource::<DialogueQueue>().is_empty());
+        match driver.step() {
+            StepResult::Line { line_id, .. } => assert_eq!(line_id, "line:second"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn escapes_are_unescaped_after_substitution() {
+        let escapes = [
+            (r"\n", "\n"),
+            (r"\\", "\\"),
+            (r"\[", "["),
+            (r"\]", "]"),
+            (r"\{", "{"),
+            (r"\}", "}"),
+            (r"\<", "<"),
+            (r"\>", ">"),
+            (r"\#", "#"),
+            (r"\/", "/"),
+            (r"\:", ":"),
+            (r"\q", r"\q"),
+        ];
+        for (escaped, unescaped) in escapes {
+            assert_eq!(unescape_text(escaped), unescaped, "unescaping {}", escaped);
+        }
+
+        let substitutions = ["ten".to_string()];
+        let text = apply_substitutions(r"\{0\} is {0}", &substitutions);
+        assert_eq!(text, r"\{0\} is ten");
+        assert_eq!(unescape_text(&text), "{0} is ten");
+
+        assert_eq!(parse_markup(r"\[b\]bold\[/b\]").clean_text, "[b]bold[/b]");
+        let line = DialogueLine::parse(r"Time\: noon");
+        assert_eq!(line.speaker, None);
+        assert_eq!(line.text, "Time: noon");
+    }
+
+    #[test]
+    fn manual_advance_holds_lines_until_advanced() {
+        let start = node(
+            "Start",
+            &[],
+            vec![run_line("line:1", 0), run_line("line:2", 0), stop()],
+        );
+        let table = vec![line_info("line:1", "One."), line_info("line:2", "Two.")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        driver.runner_mut().line_advance_mode = LineAdvanceMode::Manual;
+
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:1");
+        let presented = driver.runner().state.clone();
+        for _ in 0..3 {
+            assert!(step_dialogue(driver.world_mut()).is_none());
+            assert_eq!(driver.runner().state, presented);
+        }
+
+        AdvanceDialogueCommand.write(driver.world_mut());
+        assert_eq!(line_id(driver.step()), "line:2");
+    }
+
+    #[test]
+    fn line_formatters_chain_in_order() {
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let table = vec![line_info("line:1", r"Guard: [b]halt[/b] \{now\}")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        driver.runner_mut().add_line_formatter(Box::new(|text| text.to_uppercase()));
+        driver.runner_mut().add_line_formatter(Box::new(|text| format!("{}!", text)));
+
+        assert!(driver.start("Start"));
+        match driver.step() {
+            StepResult::Line { line, .. } => assert_eq!(line.text, "HALT {NOW}!"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+        let presented: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventLinePresented>>()
+            .drain()
+            .map(|event| (event.0, event.5))
+            .collect();
+        let (source, line) = &presented[0];
+        assert_eq!(presented.len(), 1);
+        // The source keeps its markup and escapes; the parsed line is what gets displayed.
+        assert_eq!(source, r"GUARD: [B]HALT[/B] \{NOW\}!");
+        assert_eq!(line.speaker.as_deref(), Some("GUARD"));
+        assert_eq!(line.text, "HALT {NOW}!");
+        assert_eq!(line.attributes[0].name, "B");
+    }
+
+    #[test]
+    fn named_substitutions_come_from_game_state() {
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(12.0)]),
+                run_line("line:1", 1),
+                stop(),
+            ],
+        );
+        let table = vec![line_info("line:1", "{player_name} owes {0} gold to {lender}.")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        let mut provider = driver.world_mut().resource_mut::<SubstitutionProvider>();
+        provider.insert("player_name".to_string(), "Ash".to_string());
+
+        assert!(driver.start("Start"));
+        match driver.step() {
+            StepResult::Line { line, .. } => {
+                assert_eq!(line.text, "Ash owes 12 gold to {lender}.");
+            }
+            step => panic!("expected a line, got {:?}", step),
+        }
+
+        let named = [("0".to_string(), "named".to_string())].into_iter().collect();
+        let substitutions = ["numeric".to_string()];
+        assert_eq!(apply_named_substitutions("{0}", &substitutions, &named), "named");
+    }
+
+    #[test]
+    fn corrupt_assets_fail_to_load() {
+        let path = Path::new("dialogue/intro.yarnc");
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let mut bytes = Vec::new();
+        program(vec![start]).encode(&mut bytes).unwrap();
+        assert!(decode_program(&bytes, path).is_ok());
+        let err = decode_program(&bytes[..bytes.len() / 2], path).unwrap_err();
+        assert!(err.to_string().starts_with("dialogue/intro.yarnc: unable to decode program"));
+
+        let path = Path::new("dialogue/intro.csv");
+        let csv = "id,text,file,node,lineNumber\n\
+                   line:1,Hello,intro,Start,1\n\
+                   line:2,Broken,intro,Start,not a number\n\
+                   line:3,Bye,intro,Start,3\n";
+        let dialect = CsvDialect::default();
+        let err = parse_string_table(csv.as_bytes(), dialect, CsvStrictness::Strict, path)
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("dialogue/intro.csv: "), "{}", message);
+        assert!(message.contains("line: 3"), "{}", message);
+        let table =
+            parse_string_table(csv.as_bytes(), dialect, CsvStrictness::SkipMalformedRows, path)
+                .unwrap();
+        let ids: Vec<_> = table.iter().map(|line| line.id.as_str()).collect();
+        assert_eq!(ids, ["line:1", "line:3"]);
+    }
+
+    #[test]
+    fn streaming_table_matches_in_memory_table() {
+        let csv = "id,text,file,node,lineNumber\n\
+                   line:1,Hello,intro,Start,1\n\
+                   line:2,\"Well, \"\"quoted\"\"\nand wrapped\",intro,Start,2\n\
+                   line:3,Bye,intro,Start,3\n";
+        let path = std::env::temp_dir()
+            .join(format!("bevy_yarn_spinner_streaming_{}.csv", std::process::id()));
+        fs::write(&path, csv).unwrap();
+        let dialect = CsvDialect::default();
+        let streaming = StreamingStringTable::open(&path, dialect).unwrap();
+
+        let table =
+            parse_string_table(csv.as_bytes(), dialect, CsvStrictness::Strict, &path).unwrap();
+        assert_eq!(streaming.len(), table.len());
+        for line in &table {
+            assert_eq!(streaming.text(&line.id).as_deref(), Some(line.text.as_str()));
+        }
+        assert_eq!(streaming.text("line:missing"), None);
+        drop(streaming);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn apply_substitutions_edge_cases() {
+        let substitutions = ["a".to_string(), "b".to_string()];
+        let cases = [
+            ("", ""),
+            ("no placeholders", "no placeholders"),
+            ("{0} then more", "a then more"),
+            ("{0}{1}", "ab"),
+            ("{ 1 }", "b"),
+            ("{}", "{}"),
+            ("{name}", "{name}"),
+            ("{-1}", "{-1}"),
+            ("unclosed {0", "unclosed {0"),
+            ("stray } brace", "stray } brace"),
+            ("{{0}}", "{{0}}"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(apply_substitutions(input, &substitutions), expected, "input {:?}", input);
+        }
+        assert_eq!(apply_substitutions("{0}", &[]), "{0}");
+    }
+
+    #[test]
+    fn typed_commands_receive_parsed_values() {
+        struct Typed(Vec<YarnValue>);
+        struct Raw(Vec<String>);
+
+        let start = node(
+            "Start",
+            &[],
+            vec![run_command("typed 5 true hello"), run_command("raw 5 true"), stop()],
+        );
+        let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+        driver
+            .world_mut()
+            .register_typed_dialogue_command("typed", |world, args| {
+                world.insert_resource(Typed(args))
+            })
+            .register_dialogue_command("raw", |world, args| world.insert_resource(Raw(args)));
+
+        assert!(driver.start("Start"));
+        while driver.step() != StepResult::Complete {}
+        assert_eq!(driver.world_mut().resource::<Typed>().0, [
+            YarnValue::Number(5.0),
+            YarnValue::Bool(true),
+            YarnValue::String("hello".to_string()),
+        ]);
+        assert_eq!(driver.world_mut().resource::<Raw>().0, ["5", "true"]);
+    }
+
+    #[test]
+    fn options_presented_fires_once_per_set() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                add_option("opt:yes", "Yes"),
+                add_option("opt:no", "No"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:done", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("Yes".to_string(), 4), ("No".to_string(), 4)].into_iter().collect();
+        let table = vec![
+            line_info("opt:yes", "Yes"),
+            line_info("opt:no", "No"),
+            line_info("line:done", "Done."),
+        ];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        let presented = |driver: &mut DialogueDriver| -> Vec<Vec<DialogueOption>> {
+            let mut events = driver.world_mut().resource_mut::<Events<EventOptionsPresented>>();
+            events.drain().map(|event| event.options).collect()
+        };
+
+        assert!(driver.start("Start"));
+        let options = match driver.step() {
+            StepResult::Options(options) => options,
+            step => panic!("expected options, got {:?}", step),
+        };
+        // Deliberating across frames doesn't present the set again.
+        driver.step();
+        step_dialogue(driver.world_mut());
+        let sets = presented(&mut driver);
+        assert_eq!(sets, [options]);
+        assert_eq!(sets[0][1].line_id, "opt:no");
+        assert_eq!(sets[0][1].index, 1);
+
+        driver.select(1);
+        assert_eq!(line_id(driver.step()), "line:done");
+        assert!(presented(&mut driver).is_empty());
+    }
+
+    #[test]
+    fn unsupported_program_is_skipped_without_completing() {
+        let mut start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        start.instructions.insert(0, Instruction {
+            opcode: 99,
+            operands: Vec::new(),
+        });
+        let next = node("Start", &[], vec![run_line("line:next", 0), stop()]);
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        let unsupported = loaded_entry(world, "new.yarnc", program(vec![start]), Vec::new());
+        let next = loaded_entry(world, "next.yarnc", program(vec![next]), Vec::new());
+        world.resource_mut::<DialogueQueue>().extend([unsupported, next]);
+
+        check_queue(world);
+        let events: Vec<_> = world
+            .resource_mut::<Events<EventUnsupportedProgram>>()
+            .drain()
+            .map(|event| (event.path, event.opcodes))
+            .collect();
+        assert_eq!(events, [(Some(PathBuf::from("new.yarnc")), vec![99])]);
+        assert!(world.resource_mut::<Events<EventDialogueCompleted>>().drain().next().is_none());
+        let path = world.resource::<DialogueRunner>().program_path.clone();
+        assert_eq!(path, Some(PathBuf::from("next.yarnc")));
+        assert_eq!(line_id(driver.step()), "line:next");
+    }
+
+    #[test]
+    fn panicking_function_returns_the_world() {
+        fn boom(_: &mut World, _: Vec<YarnValue>) -> YarnValue {
+            panic!("boom");
+        }
+
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(0.0)]),
+                instruction(OpCode::CallFunc, vec![string("boom")]),
+                stop(),
+            ],
+        );
+        let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+        driver.world_mut().register_dialogue_function("boom", boom);
+        assert!(driver.runner().function_names.contains(&"boom".to_string()));
+
+        assert!(driver.start("Start"));
+        let stepped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| driver.step()));
+        assert!(stepped.is_err());
+        assert!(driver.world_mut().contains_resource::<DialogueRunner>());
+        assert!(DIALOGUE_WORLDS.with(|worlds| worlds.borrow().is_empty()));
+    }
+
+    #[test]
+    fn dialogue_bytes_use_the_configured_csv_dialect() {
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let mut program_bytes = Vec::new();
+        program(vec![start]).encode(&mut program_bytes).unwrap();
+        let table = "id;text;file;node;lineNumber\nline:1;Hello, there;intro;Start;1\n";
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        world.insert_resource(StringTableFormat {
+            dialect: CsvDialect {
+                delimiter: b';',
+                has_headers: true,
+            },
+            strictness: CsvStrictness::Strict,
+        });
+
+        AddDialogueBytesToQueueCommand {
+            program: program_bytes,
+            table: table.as_bytes().to_vec(),
+            start_node: None,
+        }
+        .write(world);
+        check_queue(world);
+        match driver.step() {
+            StepResult::Line { line, .. } => assert_eq!(line.text, "Hello, there"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn blocking_command_holds_until_completed() {
+        struct Door(DialogueCommandToken);
+
+        fn open_door(world: &mut World, _: Vec<String>) -> DialogueCommandToken {
+            let token = world.resource_mut::<BlockingDialogueCommands>().begin();
+            world.insert_resource(Door(token));
+            token
+        }
+
+        let start = node(
+            "Start",
+            &[],
+            vec![run_command("door"), run_line("line:after", 0), stop()],
+        );
+        let table = vec![line_info("line:after", "It creaks open.")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        driver
+            .world_mut()
+            .register_dialogue_command("door", |_, _| panic!("replaced by the blocking command"))
+            .register_blocking_dialogue_command("door", open_door);
+        let mut frame = SystemStage::single_threaded()
+            .with_system(
+                update_runner.exclusive_system().with_run_criteria(run_if_no_dialogue_hold),
+            )
+            .with_system(release_blocking_command_hold);
+
+        let presenting = |world: &World| {
+            let state = &world.resource::<DialogueRunner>().state;
+            matches!(state, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(_)))
+        };
+
+        assert!(driver.start("Start"));
+        for _ in 0..3 {
+            frame.run(driver.world_mut());
+            let world = driver.world_mut();
+            assert!(world.resource::<DialogueHolds>().contains(BLOCKING_COMMAND_HOLD));
+            assert!(!presenting(world));
+        }
+
+        let world = driver.world_mut();
+        let token = world.resource::<Door>().0;
+        world.resource_mut::<BlockingDialogueCommands>().complete_command(token);
+        frame.run(world);
+        assert!(!world.resource::<DialogueHolds>().is_held());
+        frame.run(world);
+        assert!(presenting(world));
+    }
+
+    #[test]
+    fn line_metadata_is_only_loaded_when_present() {
+        use bevy::{asset::FileAssetIo, tasks::TaskPool};
+
+        let root = std::env::temp_dir()
+            .join(format!("bevy_yarn_spinner_metadata_{}", std::process::id()));
+        fs::create_dir_all(root.join("dialogue")).unwrap();
+        for file in ["plain.yarnc", "tagged.yarnc", "tagged-Metadata.csv"] {
+            fs::write(root.join("dialogue").join(file), "").unwrap();
+        }
+        let asset_server = AssetServer::new(FileAssetIo::new(&root, false), TaskPool::new());
+
+        assert!(!may_have_line_metadata(&asset_server, Path::new("dialogue/plain.yarnc")));
+        assert!(may_have_line_metadata(&asset_server, Path::new("dialogue/tagged.yarnc")));
+        // A program the source can't list is given the benefit of the doubt.
+        assert!(may_have_line_metadata(&asset_server, Path::new("dialogue/unlisted.yarnc")));
+        assert!(may_have_line_metadata(&asset_server, Path::new("missing/intro.yarnc")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_language_table_falls_back_to_default() {
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let table = vec![line_info("line:1", "Hello.")];
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+        add_dialogue_assets(driver.world_mut());
+        let entry = resolved_entry(program(vec![start]), table, None);
+        assert!(driver.runner_mut().setup(entry).is_ok());
+        assert_eq!(line_id(driver.step()), "line:1");
+
+        let world = driver.world_mut();
+        SetDialogueLanguageCommand {
+            language: "de".to_string(),
+        }
+        .write(world);
+        // Nothing is on disk: the `de` table fails to load, and `en` arrives from memory.
+        let pending = world.resource::<DialogueRunner>().pending_tables.as_ref().unwrap();
+        let (de, en) = (pending.table.clone(), pending.fallback_table.clone().unwrap());
+        let english = YarnStringTable(Arc::new(vec![line_info("line:1", "Guard: Hello.")]));
+        world.resource_mut::<Assets<YarnStringTable>>().set_untracked(en, english);
+        let started = std::time::Instant::now();
+        while world.resource::<AssetServer>().get_load_state(&de) != LoadState::Failed {
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::yield_now();
+        }
+
+        apply_language_tables(world);
+        assert!(world.resource::<DialogueRunner>().pending_tables.is_none());
+        let fallbacks: Vec<_> = world
+            .resource_mut::<Events<EventLanguageFallback>>()
+            .drain()
+            .map(|event| (event.requested, event.used))
+            .collect();
+        assert_eq!(fallbacks, [("de".to_string(), "en".to_string())]);
+        let presented: Vec<_> = world
+            .resource_mut::<Events<EventLinePresented>>()
+            .drain()
+            .map(|event| event.5.speaker)
+            .collect();
+        assert_eq!(presented.last(), Some(&Some("Guard".to_string())));
+    }
+
+    #[test]
+    fn keyed_runners_advance_independently() {
+        let conversation = |prefix: &str| {
+            let ids = [format!("{}:1", prefix), format!("{}:2", prefix)];
+            let lines = vec![run_line(&ids[0], 0), run_line(&ids[1], 0), stop()];
+            let start = node("Start", &[], lines);
+            let table = ids.iter().map(|id| line_info(id, "...")).collect();
+            (program(vec![start]), table)
+        };
+        let (primary_program, primary_table) = conversation("primary");
+        let mut driver = DialogueDriver::new(primary_program, primary_table);
+        driver.runner_mut().line_advance_mode = LineAdvanceMode::Manual;
+        driver.runner_mut().command_separator = Some(';');
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        let key = world.spawn().id();
+        SpawnDialogueRunnerCommand { key }.write(world);
+        let keyed = &world.resource::<DialogueRunners>()[&key].runner;
+        assert_eq!(keyed.line_advance_mode, LineAdvanceMode::Manual);
+        assert_eq!(keyed.command_separator, Some(';'));
+
+        let (keyed_program, keyed_table) = conversation("keyed");
+        let entry = loaded_entry(world, "keyed.yarnc", keyed_program, keyed_table);
+        world.resource_mut::<DialogueRunners>().get_mut(&key).unwrap().queue.push_back(entry);
+        let keyed_line = |world: &World| {
+            let runner = &world.resource::<DialogueRunners>()[&key].runner;
+            runner.current_line.as_ref().map(|line| line.id.clone())
+        };
+
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "primary:1");
+        let world = driver.world_mut();
+        for _ in 0..3 {
+            update_keyed_runners(world);
+        }
+        assert_eq!(keyed_line(world).as_deref(), Some("keyed:1"));
+
+        RunOnDialogueRunnerCommand {
+            key,
+            command: AdvanceDialogueCommand,
+        }
+        .write(world);
+        update_keyed_runners(world);
+        assert_eq!(keyed_line(world).as_deref(), Some("keyed:2"));
+        let primary = world.resource::<DialogueRunner>().current_line.as_ref().unwrap();
+        assert_eq!(primary.id, "primary:1");
+    }
+
+    #[test]
+    fn try_new_only_checks_the_path() {
+        let err = DialoguePlugin::try_new("").err().unwrap();
+        assert!(matches!(err, DialogueLoadError::InvalidConfiguration(_)));
+        let plugin = DialoguePlugin::try_new("dialogue/missing.yarnc").unwrap();
+        assert_eq!(plugin.startup_program, Some(PathBuf::from("dialogue/missing.yarnc")));
+    }
+
+    #[test]
+    fn declared_defaults_seed_variables() {
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushVariable, vec![string("$coins")]),
+                run_line("line:1", 1),
+                stop(),
+            ],
+        );
+        let bytes = compiled_program(program(vec![start]), &[("$coins", Value::FloatValue(10.0))]);
+        let decoded = decode_program(&bytes, Path::new("coins.yarnc")).unwrap();
+        assert_eq!(decoded.initial_values()["$coins"], YarnValue::Number(10.0));
+
+        let coins_line = |saved: Option<f32>| {
+            let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+            if let Some(saved) = saved {
+                driver.runner_mut().set_variable("$coins", YarnValue::Number(saved));
+            }
+            let world = driver.world_mut();
+            add_dialogue_assets(world);
+            AddDialogueBytesToQueueCommand {
+                program: bytes.clone(),
+                table: b"id,text,file,node,lineNumber\nline:1,{0} coins,a,Start,1\n".to_vec(),
+                start_node: None,
+            }
+            .write(world);
+            check_queue(world);
+            let before = driver.runner().get_variable("$coins");
+            match driver.step() {
+                StepResult::Line { line, .. } => (before, line.text),
+                step => panic!("expected a line, got {:?}", step),
+            }
+        };
+
+        let (before, text) = coins_line(None);
+        assert_eq!(before, Some(YarnValue::Number(10.0)));
+        assert_eq!(text, "10 coins");
+        // A value restored from a save wins over the declared default.
+        let (before, text) = coins_line(Some(3.0));
+        assert_eq!(before, Some(YarnValue::Number(3.0)));
+        assert_eq!(text, "3 coins");
+    }
+
+    #[test]
+    fn storage_swaps_and_resets_between_dialogues() {
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushVariable, vec![string("$coins")]),
+                run_line("line:1", 1),
+                stop(),
+            ],
+        );
+        let bytes = compiled_program(program(vec![start]), &[("$coins", Value::FloatValue(10.0))]);
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+        add_dialogue_assets(driver.world_mut());
+        let play = |driver: &mut DialogueDriver| {
+            let world = driver.world_mut();
+            AddDialogueBytesToQueueCommand {
+                program: bytes.clone(),
+                table: b"id,text,file,node,lineNumber\nline:1,{0} coins,a,Start,1\n".to_vec(),
+                start_node: None,
+            }
+            .write(world);
+            check_queue(world);
+            let text = match driver.step() {
+                StepResult::Line { line, .. } => line.text,
+                step => panic!("expected a line, got {:?}", step),
+            };
+            assert_eq!(driver.step(), StepResult::Complete);
+            text
+        };
+        assert_eq!(play(&mut driver), "10 coins");
+
+        let mut saved = HashMapVariableStorage::default();
+        saved.set("$coins", YarnValue::Number(42.0));
+        SetVariableStorageCommand(Box::new(saved)).write(driver.world_mut());
+        assert_eq!(play(&mut driver), "42 coins");
+
+        driver.runner_mut().set_variable("$gold", YarnValue::Number(1.0));
+        ResetVariablesCommand.write(driver.world_mut());
+        assert_eq!(driver.runner().get_variable("$coins"), Some(YarnValue::Number(10.0)));
+        assert_eq!(driver.runner().get_variable("$gold"), None);
+        assert_eq!(play(&mut driver), "10 coins");
+    }
+
+    #[test]
+    fn mismatched_assignments_follow_the_checking_mode() {
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushString, vec![string("lots")]),
+                instruction(OpCode::StoreVariable, vec![string("$hp")]),
+                instruction(OpCode::Pop, Vec::new()),
+                stop(),
+            ],
+        );
+        let bytes = compiled_program(program(vec![start]), &[("$hp", Value::FloatValue(10.0))]);
+        let assign = |checking: VariableTypeChecking| {
+            let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+            driver.runner_mut().variables.set_type_checking(checking, false);
+            let world = driver.world_mut();
+            add_dialogue_assets(world);
+            AddDialogueBytesToQueueCommand {
+                program: bytes.clone(),
+                table: b"id,text,file,node,lineNumber\n".to_vec(),
+                start_node: None,
+            }
+            .write(world);
+            check_queue(world);
+            assert_eq!(driver.step(), StepResult::Complete);
+            let mismatches: Vec<_> = driver
+                .world_mut()
+                .resource_mut::<Events<EventTypeMismatch>>()
+                .drain()
+                .map(|event| (event.variable, event.expected, event.got))
+                .collect();
+            (driver.runner().get_variable("$hp"), mismatches)
+        };
+        let mismatch = [("$hp".to_string(), YarnValueType::Number, YarnValueType::String)];
+
+        let (hp, mismatches) = assign(VariableTypeChecking::Lenient);
+        assert_eq!(hp, Some(YarnValue::String("lots".to_string())));
+        assert_eq!(mismatches, mismatch);
+        let (hp, mismatches) = assign(VariableTypeChecking::Strict);
+        assert_eq!(hp, Some(YarnValue::Number(10.0)));
+        assert_eq!(mismatches, mismatch);
+        let (hp, mismatches) = assign(VariableTypeChecking::Off);
+        assert_eq!(hp, Some(YarnValue::String("lots".to_string())));
+        assert!(mismatches.is_empty());
+    }
+
+    /// Three options: `opt:a` without a condition, `opt:b` whose condition fails and `opt:c`
+    /// whose condition passes and which takes a substitution.
+    fn conditional_options() -> (Program, Vec<LineInfo>) {
+        let conditional = |line_id: &str, label: &str, substitutions: f32| {
+            let operands = vec![
+                string(line_id),
+                string(label),
+                Value::FloatValue(substitutions),
+                Value::BoolValue(true),
+            ];
+            instruction(OpCode::AddOption, operands)
+        };
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                add_option("opt:a", "A"),
+                instruction(OpCode::PushBool, vec![Value::BoolValue(false)]),
+                conditional("opt:b", "B", 0.0),
+                instruction(OpCode::PushBool, vec![Value::BoolValue(true)]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(3.0)]),
+                conditional("opt:c", "C", 1.0),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:a", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:b", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:c", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("A".to_string(), 8), ("B".to_string(), 11), ("C".to_string(), 14)]
+            .into_iter()
+            .collect();
+        let table = vec![
+            line_info("opt:a", "Leave"),
+            line_info("opt:b", "Bribe"),
+            line_info("opt:c", "Pay {0} gold"),
+            line_info("line:a", "Bye."),
+            line_info("line:b", "Thanks."),
+            line_info("line:c", "Paid."),
+        ];
+        (program(vec![start]), table)
+    }
+
+    #[test]
+    fn failed_option_conditions_are_shown_disabled() {
+        let (program, table) = conditional_options();
+        let mut driver = DialogueDriver::new(program, table);
+        assert_eq!(driver.runner().option_policy, OptionPresentationPolicy::ShowDisabled);
+        assert!(driver.start("Start"));
+        let options = match driver.step() {
+            StepResult::Options(options) => options,
+            step => panic!("expected options, got {:?}", step),
+        };
+        let presented: Vec<_> = options
+            .iter()
+            .map(|option| (option.index, option.text.as_str(), option.is_available))
+            .collect();
+        assert_eq!(presented, [(0, "Leave", true), (1, "Bribe", false), (2, "Pay 3 gold", true)]);
+        assert!(driver.runner().vm.state.stack.is_empty());
+
+        driver.select(1);
+        assert_eq!(driver.step(), StepResult::Options(options));
+        driver.select(2);
+        match driver.step() {
+            StepResult::Line { line_id, .. } => assert_eq!(line_id, "line:c"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+        assert!(driver.runner().vm.state.stack.is_empty());
+    }
+
+    #[test]
+    fn failed_option_conditions_are_hidden_keeping_vm_indices() {
+        let (program, table) = conditional_options();
+        let mut driver = DialogueDriver::new(program, table);
+        driver.runner_mut().option_policy = OptionPresentationPolicy::HideUnavailable;
+        assert!(driver.start("Start"));
+        let options = match driver.step() {
+            StepResult::Options(options) => options,
+            step => panic!("expected options, got {:?}", step),
+        };
+        let presented: Vec<_> = options.iter().map(|option| option.index).collect();
+        assert_eq!(presented, [0, 2]);
+
+        let save = driver.runner().save_state();
+        let available: Vec<_> = save.current_options.iter().map(|o| o.is_available).collect();
+        assert_eq!(available, [true, false, true]);
+        let (program, table) = conditional_options();
+        let mut restored = DialogueDriver::new(program, table);
+        restored.runner_mut().option_policy = OptionPresentationPolicy::HideUnavailable;
+        restored.runner_mut().restore_state(save);
+        assert_eq!(restored.step(), StepResult::Options(options));
+
+        restored.select(1);
+        assert!(restored.runner().last_selected_option.is_none());
+        restored.select(2);
+        match restored.step() {
+            StepResult::Line { line_id, .. } => assert_eq!(line_id, "line:c"),
+            step => panic!("expected a line, got {:?}", step),
+        }
+    }
+
+    #[cfg(feature = "compile")]
+    #[test]
+    fn yarn_source_compiles_to_a_program_and_table() {
+        let source = "title: Start\n---\n<<declare $coins = 10>>\nGuard: You have {$coins} coins.\n\
+                      -> Pay\n    Guard: Thanks.\n-> Leave\n===\n";
+        let (compiled, table) =
+            compile_yarn_source(Path::new("guard.yarn"), source.to_string()).unwrap();
+        assert_eq!(compiled.initial_values()["$coins"], YarnValue::Number(10.0));
+        let texts: Vec<_> = table.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["Guard: You have {0} coins.", "Pay", "Guard: Thanks.", "Leave"]);
+        let table_path = DialogueLanguage::default().table_path(Path::new("guard.yarn"));
+        assert_eq!(table_path.path(), Path::new("guard.yarn"));
+        assert_eq!(table_path.label(), Some(COMPILED_TABLE_LABEL));
+
+        let mut driver = DialogueDriver::new(compiled.program().clone(), table);
+        driver.runner_mut().set_variable("$coins", YarnValue::Number(10.0));
+        assert!(driver.start("Start"));
+        match driver.step() {
+            StepResult::Line { line, .. } => assert_eq!(line.text, "You have 10 coins."),
+            step => panic!("expected a line, got {:?}", step),
+        }
+        match driver.step() {
+            StepResult::Options(options) => {
+                let texts: Vec<_> = options.iter().map(|option| option.text.as_str()).collect();
+                assert_eq!(texts, ["Pay", "Leave"]);
+            }
+            step => panic!("expected options, got {:?}", step),
+        }
+    }
+
+    #[cfg(feature = "compile")]
+    #[test]
+    fn yarn_compile_errors_carry_line_and_column() {
+        let source = "title: Start\n---\n<<if $x>>\nUnclosed\n===\n";
+        let err = compile_yarn_source(Path::new("broken.yarn"), source.to_string()).unwrap_err();
+        assert!(!err.0.is_empty());
+        assert!(err.0.iter().all(|diagnostic| diagnostic.starts_with("broken.yarn:5:1: ")));
+        assert!(err.to_string().contains("<<endif>>"));
+    }
+
+    #[test]
+    fn select_option_command_validates_and_selects() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                add_option("opt:a", "A"),
+                add_option("opt:b", "B"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:a", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:b", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("A".to_string(), 4), ("B".to_string(), 7)].into_iter().collect();
+        let table = vec![
+            line_info("opt:a", "Left"),
+            line_info("opt:b", "Right"),
+            line_info("line:a", "Left it is."),
+            line_info("line:b", "Right it is."),
+        ];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+
+        // Nothing is waiting on a selection yet.
+        SelectDialogueOptionCommand { index: 0 }.write(driver.world_mut());
+        assert_eq!(driver.runner().state, DialogueRunnerState::Idle);
+
+        assert!(driver.start("Start"));
+        assert!(matches!(driver.step(), StepResult::Options(_)));
+        SelectDialogueOptionCommand { index: 5 }.write(driver.world_mut());
+        let waiting = &driver.runner().vm.execution_state;
+        assert!(matches!(waiting, ExecutionState::WaitingOnOptionSelection));
+
+        SelectDialogueOptionCommand { index: 0 }.write(driver.world_mut());
+        let selected: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventOptionSelected>>()
+            .drain()
+            .map(|EventOptionSelected(option)| (option.index, option.text, option.option_count))
+            .collect();
+        assert_eq!(selected, [(0, "Left".to_string(), 2)]);
+        let state = &driver.runner().state;
+        assert_eq!(*state, DialogueRunnerState::Running(DialogueRunningCurrentEntry::Null));
+        assert_eq!(line_id(driver.step()), "line:a");
+    }
+
+    #[test]
+    fn set_variable_command_picks_the_if_branch() {
+        // <<if $seen_intro>> Welcome back. <<else>> Hello, stranger. <<endif>>
+        let start = || {
+            let mut start = node(
+                "Start",
+                &[],
+                vec![
+                    instruction(OpCode::PushVariable, vec![string("$seen_intro")]),
+                    instruction(OpCode::JumpIfFalse, vec![string("Intro")]),
+                    instruction(OpCode::Pop, Vec::new()),
+                    run_line("line:back", 0),
+                    stop(),
+                    instruction(OpCode::Pop, Vec::new()),
+                    run_line("line:intro", 0),
+                    stop(),
+                ],
+            );
+            start.labels = [("Intro".to_string(), 5)].into_iter().collect();
+            program(vec![start])
+        };
+        let table = || {
+            vec![
+                line_info("line:back", "Welcome back."),
+                line_info("line:intro", "Hello, stranger."),
+            ]
+        };
+
+        let mut driver = DialogueDriver::new(start(), table());
+        assert_eq!(driver.runner().get_variable("$seen_intro"), None);
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:intro");
+
+        let mut driver = DialogueDriver::new(start(), table());
+        SetYarnVariableCommand {
+            name: "$seen_intro".to_string(),
+            value: YarnValue::Bool(true),
+        }
+        .write(driver.world_mut());
+        assert_eq!(driver.runner().get_variable("$seen_intro"), Some(YarnValue::Bool(true)));
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:back");
+    }
+
+    #[test]
+    fn seeded_dice_is_in_range_and_reproducible() {
+        let rolls = |seed: u64| {
+            // Roll: {dice(6)}, forever.
+            let mut start = node(
+                "Start",
+                &[],
+                vec![
+                    instruction(OpCode::PushFloat, vec![Value::FloatValue(6.0)]),
+                    instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                    instruction(OpCode::CallFunc, vec![string("dice")]),
+                    run_line("line:roll", 1),
+                    instruction(OpCode::JumpTo, vec![string("Roll")]),
+                ],
+            );
+            start.labels = [("Roll".to_string(), 0)].into_iter().collect();
+            let table = vec![line_info("line:roll", "{0}")];
+            let mut driver = DialogueDriver::new(program(vec![start]), table);
+            driver.runner_mut().rng = DialogueRng::seeded(seed);
+            assert!(driver.start("Start"));
+            (0..100)
+                .map(|_| match driver.step() {
+                    StepResult::Line { line, .. } => line.text.parse::<u32>().unwrap(),
+                    step => panic!("expected a line, got {:?}", step),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let first = rolls(42);
+        assert!(first.iter().all(|roll| (1..=6).contains(roll)));
+        assert!((1..=6).all(|face| first.contains(&face)));
+        assert_eq!(first, rolls(42));
+        assert_ne!(first, rolls(7));
+    }
+
+    #[test]
+    fn stop_interrupts_dialogue_and_optionally_clears_the_queue() {
+        let start = || {
+            let lines = vec![run_line("line:1", 0), run_line("line:2", 0), stop()];
+            program(vec![node("Start", &[], lines)])
+        };
+        let table = || vec![line_info("line:1", "One."), line_info("line:2", "Two.")];
+
+        for clear_queue in [false, true] {
+            let mut driver = DialogueDriver::new(start(), table());
+            let world = driver.world_mut();
+            add_dialogue_assets(world);
+            let entry = loaded_entry(world, "next.yarnc", start(), table());
+            world.resource_mut::<DialogueQueue>().push_back(entry);
+            assert!(driver.start("Start"));
+            assert_eq!(line_id(driver.step()), "line:1");
+
+            StopDialogueCommand { clear_queue }.write(driver.world_mut());
+            let runner = driver.runner();
+            assert_eq!(runner.state, DialogueRunnerState::Idle);
+            assert_eq!(runner.idle_reason, IdleReason::Stopped);
+            assert!(runner.current_node.is_none());
+            assert!(!matches!(runner.vm.execution_state, ExecutionState::Running));
+            let completed: Vec<_> = driver
+                .world_mut()
+                .resource_mut::<Events<EventDialogueCompleted>>()
+                .drain()
+                .map(|EventDialogueCompleted(node, reason)| (node, reason))
+                .collect();
+            assert_eq!(completed, [("Start".to_string(), IdleReason::Stopped)]);
+            let pending = driver.world_mut().resource::<DialogueQueue>().pending_count();
+            assert_eq!(pending, if clear_queue { 0 } else { 1 });
+            assert_eq!(driver.step(), StepResult::Complete);
+        }
+    }
+
+    #[test]
+    fn speaker_splits_on_the_first_unescaped_colon() {
+        let split = |line: &str| {
+            let line = DialogueLine::parse(line);
+            (line.speaker, line.text)
+        };
+        let speaker = |name: &str, text: &str| (Some(name.to_string()), text.to_string());
+
+        assert_eq!(split("Alice: Hello there"), speaker("Alice", "Hello there"));
+        assert_eq!(split("Alice: It's 5:30: late"), speaker("Alice", "It's 5:30: late"));
+        assert_eq!(split("Bob:    spaced out"), speaker("Bob", "spaced out"));
+        assert_eq!(split("  Bob  :trimmed"), speaker("Bob", "trimmed"));
+        assert_eq!(split("No speaker at all"), (None, "No speaker at all".to_string()));
+        assert_eq!(split(": no name"), (None, ": no name".to_string()));
+        assert_eq!(split("Note\\: not a speaker"), (None, "Note: not a speaker".to_string()));
+        assert_eq!(split("A\\:B: text"), speaker("A:B", "text"));
+    }
+
+    #[test]
+    fn markup_spans_properties_and_escapes() {
+        let spans = |input: &str| {
+            let markup = parse_markup(input);
+            let spans: Vec<_> = markup
+                .attributes
+                .iter()
+                .map(|attribute| (attribute.name.clone(), attribute.start, attribute.length))
+                .collect();
+            (markup.clean_text, spans)
+        };
+        let span = |name: &str, start, length| (name.to_string(), start, length);
+
+        let markup = parse_markup("[wave]hello[/wave] [color=red]danger[/color]");
+        assert_eq!(markup.clean_text, "hello danger");
+        assert_eq!(markup.attributes[1].properties.get("color").map(String::as_str), Some("red"));
+        assert_eq!(
+            spans("[wave]hello[/wave] [color=red]danger[/color]").1,
+            [span("wave", 0, 5), span("color", 6, 6)]
+        );
+        assert_eq!(spans("a[pause/]b"), ("ab".to_string(), vec![span("pause", 1, 0)]));
+        assert_eq!(
+            spans("[b][i]x[/]y"),
+            ("xy".to_string(), vec![span("b", 0, 1), span("i", 0, 1)])
+        );
+        assert_eq!(spans("[b]to the end"), ("to the end".to_string(), vec![span("b", 0, 10)]));
+        assert_eq!(spans("\\[not\\] markup"), ("[not] markup".to_string(), vec![]));
+        assert_eq!(spans("dangling [tag"), ("dangling [tag".to_string(), vec![]));
+
+        let markup = parse_markup("[sfx name=\"big boom\" volume=2/]");
+        let properties = &markup.attributes[0].properties;
+        assert_eq!(properties.get("name").map(String::as_str), Some("big boom"));
+        assert_eq!(properties.get("volume").map(String::as_str), Some("2"));
+    }
+
+    #[test]
+    fn unregistered_command_sends_unknown_command() {
+        let start = node("Start", &[], vec![run_command("foo bar"), stop()]);
+        let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+        assert!(driver.start("Start"));
+        assert_eq!(driver.step(), StepResult::Command {
+            name: "foo".to_string(),
+            args: vec!["bar".to_string()],
+        });
+        let unknown: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventUnknownCommand>>()
+            .drain()
+            .map(|EventUnknownCommand { name, args }| (name, args))
+            .collect();
+        assert_eq!(unknown, [("foo".to_string(), vec!["bar".to_string()])]);
+        assert_eq!(driver.step(), StepResult::Complete);
+    }
+
+    #[test]
+    fn preloaded_dialogue_starts_on_the_frame_it_is_queued() {
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let table = format!("{}line:1,Hello.,intro,Start,1\n", TABLE_HEADER);
+        let root = asset_dir(
+            "preload",
+            &[
+                ("intro.yarnc", &compiled_program(program(vec![start]), &[])),
+                ("intro.csv", table.as_bytes()),
+            ],
+        );
+        let mut app = dialogue_app(&root, DialoguePlugin::default());
+        let path = PathBuf::from("intro.yarnc");
+        PreloadDialogueCommand { path: path.clone() }.write(&mut app.world);
+        assert!(app.world.resource::<DialoguePreloadCache>().contains(&path));
+        update_until(&mut app, |world| {
+            !world.resource::<Assets<YarnProgram>>().is_empty()
+                && !world.resource::<Assets<YarnStringTable>>().is_empty()
+        });
+
+        AddDialogueToQueueCommand {
+            path: path.clone(),
+            start_node: None,
+            language: None,
+            extra_commands: None,
+        }
+        .write(&mut app.world);
+        app.update();
+        assert!(app.world.resource::<DialogueQueue>().is_empty());
+        assert_ne!(app.world.resource::<DialogueRunner>().state, DialogueRunnerState::Idle);
+        app.update();
+        let line = app.world.resource::<CurrentDialogueLine>().0.clone();
+        assert_eq!(line.map(|line| line.text), Some("Hello.".to_string()));
+
+        let mut cache = app.world.resource_mut::<DialoguePreloadCache>();
+        assert!(cache.evict(&path));
+        assert!(!cache.contains(&path));
+        assert!(!cache.evict(&path));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn node_transition_hook_redirects_jumps() {
+        fn closed_shop(world: &mut World, _from: &str, to: &str) -> Option<String> {
+            let open = world.resource::<DialogueRunner>().get_variable("$shop_open");
+            (to == "Shop" && open != Some(YarnValue::Bool(true))).then(|| "ShopClosed".to_string())
+        }
+
+        let nodes = || {
+            // <<jump Shop>>
+            let jump = vec![
+                instruction(OpCode::PushString, vec![string("Shop")]),
+                instruction(OpCode::RunNode, Vec::new()),
+            ];
+            let start = node("Start", &[], jump);
+            let shop = node("Shop", &[], vec![run_line("line:open", 0), stop()]);
+            let closed = node("ShopClosed", &[], vec![run_line("line:closed", 0), stop()]);
+            program(vec![start, shop, closed])
+        };
+        let table = || vec![line_info("line:open", "Come in!"), line_info("line:closed", "Shut.")];
+
+        for (open, expected) in [(false, "line:closed"), (true, "line:open")] {
+            let mut driver = DialogueDriver::new(nodes(), table());
+            driver.world_mut().set_node_transition_hook(closed_shop);
+            driver.runner_mut().set_variable("$shop_open", YarnValue::Bool(open));
+            assert!(driver.start("Start"));
+            assert_eq!(line_id(driver.step()), expected);
+            let started: Vec<_> = driver
+                .world_mut()
+                .resource_mut::<Events<EventNodeStarted>>()
+                .drain()
+                .map(|EventNodeStarted(node, _)| node)
+                .collect();
+            let destination = if open { "Shop" } else { "ShopClosed" };
+            assert_eq!(started.last().map(String::as_str), Some(destination));
+            assert_eq!(driver.runner().current_node.as_deref(), Some(destination));
+        }
+    }
+
+    #[test]
+    fn lines_auto_advance_after_their_delay() {
+        let lines = vec![run_line("line:1", 0), run_line("line:2", 0), stop()];
+        let table = vec![line_info("line:1", "One."), line_info("line:2", "Two.")];
+        let mut driver = DialogueDriver::new(program(vec![node("Start", &[], lines)]), table);
+        let delay = Duration::from_secs(1);
+        driver.runner_mut().line_advance_mode = LineAdvanceMode::AutoAdvance { delay };
+        // The hashtag overrides the global delay.
+        let tags = vec!["#autoadvance:0.25".to_string()];
+        driver.runner_mut().line_metadata.insert("line:2".to_string(), tags);
+
+        let tick = |driver: &mut DialogueDriver, seconds: f32| {
+            advance_line_if_due(driver.world_mut(), Duration::from_secs_f32(seconds));
+        };
+
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:1");
+        tick(&mut driver, 0.6);
+        assert_eq!(line_id(driver.step()), "line:1");
+        tick(&mut driver, 0.6);
+        assert_eq!(line_id(driver.step()), "line:2");
+        tick(&mut driver, 0.3);
+        assert_eq!(driver.step(), StepResult::Complete);
+    }
+
+    #[test]
+    fn dialogue_sequence_plays_in_order() {
+        use bevy::ecs::event::ManualEventReader;
+
+        let file = |name: &str, node_name: &str| {
+            let start = node(node_name, &[], vec![run_line(name, 0), stop()]);
+            let row = format!("{},Hi from {},{},{},1\n", name, name, name, node_name);
+            let table = format!("{}{}", TABLE_HEADER, row);
+            (compiled_program(program(vec![start]), &[]), table)
+        };
+        let one = file("one", "Start");
+        let two = file("two", "Middle");
+        let three = file("three", "Start");
+        let root = asset_dir(
+            "sequence",
+            &[
+                ("one.yarnc", &one.0),
+                ("one.csv", one.1.as_bytes()),
+                ("two.yarnc", &two.0),
+                ("two.csv", two.1.as_bytes()),
+                ("three.yarnc", &three.0),
+                ("three.csv", three.1.as_bytes()),
+            ],
+        );
+        let mut app = dialogue_app(&root, DialoguePlugin::default());
+        AddDialogueSequenceCommand {
+            entries: vec![
+                (PathBuf::from("one.yarnc"), None),
+                (PathBuf::from("two.yarnc"), Some("Middle".to_string())),
+                (PathBuf::from("three.yarnc"), None),
+            ],
+        }
+        .write(&mut app.world);
+        assert_eq!(app.world.resource::<DialogueQueue>().pending_count(), 3);
+
+        let mut reader = ManualEventReader::<EventLinePresented>::default();
+        let mut presented = Vec::new();
+        let started = std::time::Instant::now();
+        while presented.len() < 3 {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out playing the sequence");
+            app.update();
+            let events = app.world.resource::<Events<EventLinePresented>>();
+            presented.extend(reader.iter(events).map(|event| event.1.clone()));
+            std::thread::yield_now();
+        }
+        assert_eq!(presented, ["one", "two", "three"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn variable_batch_is_set_before_the_queued_node() {
+        // <<if $has_key>> <<if $guard_asleep>> sneak <<endif>> <<endif>>, otherwise locked.
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushVariable, vec![string("$has_key")]),
+                instruction(OpCode::JumpIfFalse, vec![string("Locked")]),
+                instruction(OpCode::Pop, Vec::new()),
+                instruction(OpCode::PushVariable, vec![string("$guard_asleep")]),
+                instruction(OpCode::JumpIfFalse, vec![string("Locked")]),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:sneak", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:locked", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("Locked".to_string(), 8)].into_iter().collect();
+        let table = format!(
+            "{}line:sneak,You slip past.,vault,Start,1\nline:locked,It's locked.,vault,Start,2\n",
+            TABLE_HEADER
+        );
+        let root = asset_dir(
+            "variable_batch",
+            &[
+                ("vault.yarnc", &compiled_program(program(vec![start]), &[])),
+                ("vault.csv", table.as_bytes()),
+            ],
+        );
+
+        let play = |values: Vec<(&str, bool)>| {
+            let mut app = dialogue_app(&root, DialoguePlugin::default());
+            SetYarnVariablesCommand {
+                values: values
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), YarnValue::Bool(value)))
+                    .collect(),
+                then_queue: Some(AddDialogueToQueueCommand {
+                    path: PathBuf::from("vault.yarnc"),
+                    start_node: None,
+                    language: None,
+                    extra_commands: None,
+                }),
+            }
+            .write(&mut app.world);
+            update_until(&mut app, |world| world.resource::<CurrentDialogueLine>().0.is_some());
+            app.world.resource::<CurrentDialogueLine>().0.clone().unwrap().text
+        };
+
+        assert_eq!(play(vec![("$has_key", true)]), "It's locked.");
+        assert_eq!(play(vec![("$has_key", true), ("$guard_asleep", false)]), "It's locked.");
+        assert_eq!(play(vec![("$has_key", true), ("$guard_asleep", true)]), "You slip past.");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn option_timeout_selects_the_default_option() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                add_option("opt:a", "A"),
+                add_option("opt:b", "B"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:a", 0),
+                stop(),
+                instruction(OpCode::Pop, Vec::new()),
+                run_line("line:b", 0),
+                stop(),
+            ],
+        );
+        start.labels = [("A".to_string(), 4), ("B".to_string(), 7)].into_iter().collect();
+        let table = vec![
+            line_info("opt:a", "Fight"),
+            line_info("opt:b", "Flee"),
+            line_info("line:a", "You draw your sword."),
+            line_info("line:b", "You hesitate, then run."),
+        ];
+        let timeout = OptionTimeout { duration: Duration::from_millis(50), default_index: 1 };
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        driver.runner_mut().option_timeout = Some(timeout);
+        driver.world_mut().insert_resource(Time::default());
+
+        assert!(driver.start("Start"));
+        assert!(matches!(driver.step(), StepResult::Options(_)));
+        let presented: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventOptionsPresented>>()
+            .drain()
+            .map(|event| event.timeout)
+            .collect();
+        assert_eq!(presented, [Some(timeout)]);
+
+        // The first update only starts the clock, so nothing has elapsed yet.
+        driver.world_mut().resource_mut::<Time>().update();
+        tick_option_timeout(driver.world_mut());
+        let waiting = &driver.runner().vm.execution_state;
+        assert!(matches!(waiting, ExecutionState::WaitingOnOptionSelection));
+
+        std::thread::sleep(Duration::from_millis(60));
+        driver.world_mut().resource_mut::<Time>().update();
+        tick_option_timeout(driver.world_mut());
+        let selected: Vec<_> = driver
+            .world_mut()
+            .resource_mut::<Events<EventOptionSelected>>()
+            .drain()
+            .map(|EventOptionSelected(option)| (option.index, option.text))
+            .collect();
+        assert_eq!(selected, [(1, "Flee".to_string())]);
+        assert_eq!(line_id(driver.step()), "line:b");
+    }
+
+    #[test]
+    fn inline_trigger_fires_as_its_character_is_revealed() {
+        #[derive(Default)]
+        struct Fired(Vec<(String, usize)>);
+        fn clang(world: &mut World, trigger: &InlineTrigger) {
+            let visible = world.resource::<DialogueTypewriter>().visible_characters;
+            world.resource_mut::<Fired>().0.push((trigger.name.clone(), visible));
+        }
+
+        let markup = parse_markup("Hello[sfx=clang/] there, [wave]friend[/wave].");
+        assert_eq!(markup.clean_text, "Hello there, friend.");
+        let triggers = inline_triggers(&markup.attributes);
+        let expected = InlineTrigger {
+            name: "sfx".to_string(),
+            properties: [("sfx".to_string(), "clang".to_string())].into_iter().collect(),
+            char_index: 5,
+        };
+        // The wave span is display markup, not a trigger.
+        assert_eq!(triggers, [expected]);
+
+        let mut world = World::new();
+        world.init_resource::<Fired>();
+        world.register_inline_trigger("sfx", clang);
+        world.insert_resource(DialogueTypewriter {
+            characters_per_second: 10.0,
+            plan: TypewriterPlan::new(&markup.clean_text, &markup.attributes, 10.0),
+            triggers,
+            ..Default::default()
+        });
+
+        for step in 0..markup.clean_text.chars().count() {
+            let mut typewriter = world.resource_mut::<DialogueTypewriter>();
+            typewriter.elapsed = (step as f32 + 0.5) / typewriter.characters_per_second;
+            typewriter.visible_characters = typewriter.plan.visible_characters(typewriter.elapsed);
+            fire_inline_triggers(&mut world);
+        }
+        assert_eq!(world.resource::<Fired>().0, [("sfx".to_string(), 6)]);
+    }
+
+    #[test]
+    fn idle_reason_tells_completion_from_interruption() {
+        let nodes = || {
+            // Start: <<jump Outro>> after its line, so the dialogue ends in another node.
+            let start = vec![
+                run_line("line:hello", 0),
+                instruction(OpCode::PushString, vec![string("Outro")]),
+                instruction(OpCode::RunNode, Vec::new()),
+            ];
+            let outro = vec![run_line("line:bye", 0), stop()];
+            program(vec![node("Start", &[], start), node("Outro", &[], outro)])
+        };
+        let table = || vec![line_info("line:hello", "Hello."), line_info("line:bye", "Bye.")];
+        let completed = |driver: &mut DialogueDriver| -> Vec<(String, IdleReason)> {
+            driver
+                .world_mut()
+                .resource_mut::<Events<EventDialogueCompleted>>()
+                .drain()
+                .map(|EventDialogueCompleted(node, reason)| (node, reason))
+                .collect()
+        };
+
+        let mut driver = DialogueDriver::new(nodes(), table());
+        assert_eq!(*driver.runner().idle_reason(), IdleReason::QueueEmpty);
+        assert_eq!(driver.runner().phase(), DialoguePhase::Idle);
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:hello");
+        assert_eq!(line_id(driver.step()), "line:bye");
+        assert_eq!(driver.step(), StepResult::Complete);
+        let finished = IdleReason::Completed { last_node: "Outro".to_string() };
+        assert_eq!(*driver.runner().idle_reason(), finished);
+        assert_eq!(driver.runner().phase(), DialoguePhase::Completed);
+        assert_eq!(completed(&mut driver), [("Outro".to_string(), finished.clone())]);
+        // Stopping an idle runner keeps the reason it went idle for.
+        StopDialogueCommand { clear_queue: false }.write(driver.world_mut());
+        assert_eq!(*driver.runner().idle_reason(), finished);
+        assert!(completed(&mut driver).is_empty());
+
+        let mut driver = DialogueDriver::new(nodes(), table());
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:hello");
+        StopDialogueCommand { clear_queue: false }.write(driver.world_mut());
+        assert_eq!(*driver.runner().idle_reason(), IdleReason::Stopped);
+        assert_eq!(completed(&mut driver), [("Start".to_string(), IdleReason::Stopped)]);
+    }
+
+    #[test]
+    fn command_batch_dispatches_each_command_in_order() {
+        #[derive(Default)]
+        struct Ran(Vec<String>);
+        fn shake(world: &mut World, args: Vec<String>) {
+            world.resource_mut::<Ran>().0.push(format!("shake {}", args.join(" ")));
+        }
+        fn say(world: &mut World, args: Vec<String>) {
+            world.resource_mut::<Ran>().0.push(format!("say {}", args.join(" ")));
+        }
+
+        for separator in [None, Some(';')] {
+            let commands = vec![run_command("shake 2; say \"wait; what\""), stop()];
+            let start = node("Start", &[], commands);
+            let mut driver = DialogueDriver::new(program(vec![start]), Vec::new());
+            driver.world_mut().init_resource::<Ran>();
+            driver.world_mut().register_dialogue_command("shake", shake);
+            driver.world_mut().register_dialogue_command("say", say);
+            driver.runner_mut().command_separator = separator;
+            assert!(driver.start("Start"));
+            assert!(matches!(driver.step(), StepResult::Command { .. }));
+            let ran = std::mem::take(&mut driver.world_mut().resource_mut::<Ran>().0);
+            match separator {
+                // Off by default, so the whole text is one command with extra arguments.
+                None => assert_eq!(ran, ["shake 2; say wait; what"]),
+                Some(_) => assert_eq!(ran, ["shake 2", "say wait; what"]),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_dialogue_is_dropped_after_its_retries() {
+        use bevy::ecs::event::ManualEventReader;
+
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let table = format!("{}line:1,Still here.,after,Start,1\n", TABLE_HEADER);
+        let root = asset_dir(
+            "load_failed",
+            &[
+                ("after.yarnc", &compiled_program(program(vec![start]), &[])),
+                ("after.csv", table.as_bytes()),
+            ],
+        );
+        let mut app = dialogue_app(&root, DialoguePlugin::default());
+        for path in ["missing.yarnc", "after.yarnc"] {
+            AddDialogueToQueueCommand {
+                path: PathBuf::from(path),
+                start_node: None,
+                language: None,
+                extra_commands: None,
+            }
+            .write(&mut app.world);
+        }
+
+        let mut reader = ManualEventReader::<EventDialogueLoadFailed>::default();
+        let mut failed = Vec::new();
+        let started = std::time::Instant::now();
+        while app.world.resource::<CurrentDialogueLine>().0.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5), "the queue stalled");
+            app.update();
+            let events = app.world.resource::<Events<EventDialogueLoadFailed>>();
+            failed.extend(reader.iter(events).map(|event| event.path.clone()));
+            std::thread::yield_now();
+        }
+        assert_eq!(failed, [PathBuf::from("missing.yarnc")]);
+        let line = app.world.resource::<CurrentDialogueLine>().0.clone().unwrap();
+        assert_eq!(line.text, "Still here.");
+        assert!(app.world.resource::<DialogueQueue>().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn peek_next_leaves_the_runner_where_it_was() {
+        fn wave(world: &mut World, _args: Vec<String>) {
+            let mut runner = world.resource_mut::<DialogueRunner>();
+            runner.set_variable("$waved", YarnValue::Bool(true));
+        }
+
+        let start = node(
+            "Start",
+            &[],
+            vec![
+                run_line("line:hello", 0),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(7.0)]),
+                instruction(OpCode::StoreVariable, vec![string("$gold")]),
+                instruction(OpCode::Pop, Vec::new()),
+                run_command("wave"),
+                run_line("line:bye", 0),
+                stop(),
+            ],
+        );
+        let table = vec![line_info("line:hello", "Hello."), line_info("line:bye", "Bye.")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        driver.world_mut().register_dialogue_command("wave", wave);
+        assert_eq!(driver.runner().peek_next(), None);
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:hello");
+
+        let before = driver.runner().debug_snapshot();
+        let peeked = PeekResult::Command { name: "wave".to_string(), args: Vec::new() };
+        assert_eq!(driver.runner().peek_next(), Some(peeked.clone()));
+        // Peeking again gives the same answer, since the first peek moved nothing.
+        assert_eq!(driver.runner().peek_next(), Some(peeked));
+        assert_eq!(driver.runner().debug_snapshot(), before);
+        assert_eq!(driver.runner().get_variable("$gold"), None);
+        assert_eq!(driver.runner().get_variable("$waved"), None);
+
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        assert_eq!(driver.runner().get_variable("$gold"), Some(YarnValue::Number(7.0)));
+        assert_eq!(driver.runner().get_variable("$waved"), Some(YarnValue::Bool(true)));
+        let peeked = driver.runner().peek_next();
+        assert!(matches!(peeked, Some(PeekResult::Line { line_id, .. }) if line_id == "line:bye"));
+        assert_eq!(line_id(driver.step()), "line:bye");
+        assert_eq!(driver.runner().peek_next(), Some(PeekResult::Complete));
+    }
+
+    #[test]
+    fn yield_pauses_until_resumed() {
+        let start = node(
+            "Start",
+            &[],
+            vec![run_line("line:1", 0), run_command("yield"), run_line("line:2", 0), stop()],
+        );
+        let table = vec![line_info("line:1", "Wait here."), line_info("line:2", "Onwards.")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+
+        // Resuming dialogue that isn't paused does nothing.
+        ResumeDialogueCommand.write(driver.world_mut());
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:1");
+        assert!(matches!(driver.step(), StepResult::Command { name, .. } if name == "yield"));
+        assert_eq!(driver.runner().phase(), DialoguePhase::Paused);
+        let position = driver.runner().debug_snapshot();
+        for _ in 0..3 {
+            AdvanceDialogueCommand.write(driver.world_mut());
+            assert_eq!(step_dialogue(driver.world_mut()), None);
+        }
+        assert_eq!(driver.runner().debug_snapshot(), position);
+        assert_eq!(driver.runner().phase(), DialoguePhase::Paused);
+
+        ResumeDialogueCommand.write(driver.world_mut());
+        assert!(step_dialogue(driver.world_mut()).is_some());
+        let state = &driver.runner().state;
+        let line = match state {
+            DialogueRunnerState::Running(DialogueRunningCurrentEntry::Text(line)) => line,
+            _ => panic!("expected a line after resuming, got {:?}", state),
+        };
+        assert_eq!(line.text, "Onwards.");
+    }
+
+    #[test]
+    fn line_overrides_replace_and_revert_table_text() {
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(3.0)]),
+                run_line("line:debt", 1),
+                add_option("opt:pay", "Pay"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                stop(),
+            ],
+        );
+        start.labels = [("Pay".to_string(), 5)].into_iter().collect();
+        let table = vec![
+            line_info("line:debt", "Merchant: You owe {0} gold."),
+            line_info("opt:pay", "Pay"),
+        ];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        let play = |driver: &mut DialogueDriver| {
+            assert!(driver.start("Start"));
+            let line = match driver.step() {
+                StepResult::Line { line, .. } => line,
+                step => panic!("expected a line, got {:?}", step),
+            };
+            let options = match driver.step() {
+                StepResult::Options(options) => options,
+                step => panic!("expected options, got {:?}", step),
+            };
+            driver.select(0);
+            assert_eq!(driver.step(), StepResult::Complete);
+            (line, options[0].text.clone())
+        };
+
+        let mut runner = driver.runner_mut();
+        runner.override_line("line:debt", "Trader: [b]{0}[/b] coins, friend.");
+        runner.override_line("opt:pay", "Hand them over");
+        let (line, option) = play(&mut driver);
+        assert_eq!(line.speaker.as_deref(), Some("Trader"));
+        assert_eq!(line.text, "3 coins, friend.");
+        let spans: Vec<_> =
+            line.attributes.iter().map(|span| (span.name.as_str(), span.start)).collect();
+        assert_eq!(spans, [("b", 0)]);
+        assert_eq!(option, "Hand them over");
+
+        let mut runner = driver.runner_mut();
+        runner.clear_override("line:debt");
+        runner.clear_override("opt:pay");
+        let (line, option) = play(&mut driver);
+        assert_eq!(line.speaker.as_deref(), Some("Merchant"));
+        assert_eq!(line.text, "You owe 3 gold.");
+        assert_eq!(option, "Pay");
+    }
+
+    #[test]
+    fn missing_start_node_is_reported_and_skipped() {
+        let nodes = vec![
+            node("Intro", &[], vec![run_line("line:intro", 0), stop()]),
+            node("Outro", &[], vec![stop()]),
+        ];
+        let next = node("Start", &[], vec![run_line("line:next", 0), stop()]);
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        let mut typo = loaded_entry(world, "typo.yarnc", program(nodes), Vec::new());
+        typo.start_node = Some("Intor".to_string());
+        let next = loaded_entry(world, "next.yarnc", program(vec![next]), Vec::new());
+        world.resource_mut::<DialogueQueue>().extend([typo, next]);
+
+        check_queue(world);
+        let missing: Vec<_> = world
+            .resource_mut::<Events<EventMissingNode>>()
+            .drain()
+            .map(|EventMissingNode { requested, available }| (requested, available))
+            .collect();
+        let available = vec!["Intro".to_string(), "Outro".to_string()];
+        assert_eq!(missing, [("Intor".to_string(), available)]);
+        let started: Vec<_> = world
+            .resource_mut::<Events<EventDialogueStarted>>()
+            .drain()
+            .map(|event| (event.path, event.start_node))
+            .collect();
+        assert_eq!(started, [(PathBuf::from("next.yarnc"), "Start".to_string())]);
+        assert!(world.resource::<DialogueQueue>().is_empty());
+        assert_eq!(line_id(driver.step()), "line:next");
+    }
+
+    #[test]
+    fn voiced_lines_resolve_to_their_clip_handle() {
+        use bevy::asset::HandleId;
+
+        let start = node("Start", &[], vec![run_line("line:1", 0), run_line("line:2", 0), stop()]);
+        let table = vec![line_info("line:1", "Hello."), line_info("line:2", "...")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        let clips = [("line:1".to_string(), "voice/hello.ogg".to_string())];
+        world.insert_resource(VoiceOverTable {
+            clips: clips.into_iter().collect(),
+            convention: None,
+        });
+        let voices = |driver: &mut DialogueDriver| -> Vec<(String, Option<HandleId>)> {
+            driver
+                .world_mut()
+                .resource_mut::<Events<EventLinePresented>>()
+                .drain()
+                .map(|event| (event.1, event.4.map(|voice| voice.id)))
+                .collect()
+        };
+
+        let expected = HandleId::from("voice/hello.ogg");
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:1");
+        assert_eq!(voices(&mut driver), [("line:1".to_string(), Some(expected))]);
+        let current = driver.runner().current_voice.as_ref().map(|voice| voice.id);
+        assert_eq!(current, Some(expected));
+
+        // A line without a clip is still presented, just silently.
+        assert_eq!(line_id(driver.step()), "line:2");
+        assert_eq!(voices(&mut driver), [("line:2".to_string(), None)]);
+        assert!(driver.runner().current_voice.is_none());
+
+        let convention = VoiceOverTable::by_convention("voice/{id}.ogg");
+        assert_eq!(convention.clip_path("line:2").as_deref(), Some("voice/line:2.ogg"));
+    }
+
+    #[test]
+    fn skip_seen_fast_forwards_only_visited_nodes() {
+        #[derive(Default)]
+        struct Chimes(usize);
+        fn chime(world: &mut World, _args: Vec<String>) {
+            world.resource_mut::<Chimes>().0 += 1;
+        }
+
+        // <<if $unlocked>> <<jump Secret>> <<endif>>, after a line and a command.
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                run_line("line:hello", 0),
+                run_command("chime"),
+                instruction(OpCode::PushVariable, vec![string("$unlocked")]),
+                instruction(OpCode::JumpIfFalse, vec![string("Done")]),
+                instruction(OpCode::Pop, Vec::new()),
+                instruction(OpCode::PushString, vec![string("Secret")]),
+                instruction(OpCode::RunNode, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                stop(),
+            ],
+        );
+        start.labels = [("Done".to_string(), 7)].into_iter().collect();
+        let secret = node("Secret", &[], vec![run_line("line:secret", 0), stop()]);
+        let table = vec![line_info("line:hello", "Hello."), line_info("line:secret", "Psst.")];
+        let mut driver = DialogueDriver::new(program(vec![start, secret]), table);
+        driver.world_mut().init_resource::<Chimes>();
+        driver.world_mut().register_dialogue_command("chime", chime);
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:hello");
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        assert_eq!(driver.step(), StepResult::Complete);
+        driver.world_mut().resource_mut::<Events<EventLinePresented>>().clear();
+
+        // Second playthrough, reading-paced: Start has been read, Secret is newly reachable.
+        ToggleSkipSeenCommand.write(driver.world_mut());
+        driver.runner_mut().line_advance_mode = LineAdvanceMode::Manual;
+        driver.runner_mut().set_variable("$unlocked", YarnValue::Bool(true));
+        assert!(driver.start("Start"));
+        let world = driver.world_mut();
+        for _ in 0..10 {
+            if step_dialogue(world).is_none() {
+                break;
+            }
+        }
+        let presented: Vec<_> = world
+            .resource_mut::<Events<EventLinePresented>>()
+            .drain()
+            .map(|event| event.1)
+            .collect();
+        assert_eq!(presented, ["line:hello", "line:secret"]);
+        assert_eq!(world.resource::<Chimes>().0, 2);
+        let runner = world.resource::<DialogueRunner>();
+        assert_eq!(runner.current_node().as_deref(), Some("Secret"));
+        assert!(!runner.is_skipping_seen());
+        assert_eq!(runner.phase(), DialoguePhase::PresentingLine);
+    }
+
+    #[test]
+    fn scoped_commands_shadow_globals_while_their_entry_runs() {
+        #[derive(Default)]
+        struct Rolls(Vec<&'static str>);
+        fn global_roll(world: &mut World, _args: Vec<String>) {
+            world.resource_mut::<Rolls>().0.push("global roll");
+        }
+        fn minigame_roll(world: &mut World, _args: Vec<String>) {
+            world.resource_mut::<Rolls>().0.push("minigame roll");
+        }
+        fn minigame_score(world: &mut World, _args: Vec<String>) {
+            world.resource_mut::<Rolls>().0.push("minigame score");
+        }
+
+        let start = || {
+            let commands = vec![run_command("roll"), run_command("score"), stop()];
+            program(vec![node("Start", &[], commands)])
+        };
+        let mut driver = DialogueDriver::new(Program::default(), Vec::new());
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        world.init_resource::<Rolls>();
+        world.register_dialogue_command("roll", global_roll);
+        let mut minigame = loaded_entry(world, "minigame.yarnc", start(), Vec::new());
+        let mut scoped = DialogueCommands::default();
+        scoped.insert("roll".to_string(), DialogueCommandHandler::Fn(minigame_roll));
+        scoped.insert("score".to_string(), DialogueCommandHandler::Fn(minigame_score));
+        minigame.extra_commands = Some(scoped);
+        let tavern = loaded_entry(world, "tavern.yarnc", start(), Vec::new());
+        world.resource_mut::<DialogueQueue>().extend([minigame, tavern]);
+
+        check_queue(world);
+        assert!(driver.runner().scoped_commands.is_some());
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        assert_eq!(driver.step(), StepResult::Complete);
+
+        // The next entry starts straight away, with only the global registry.
+        let path = driver.runner().program_path.clone();
+        assert_eq!(path, Some(PathBuf::from("tavern.yarnc")));
+        assert!(driver.runner().scoped_commands.is_none());
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        let world = driver.world_mut();
+        let rolls = &world.resource::<Rolls>().0;
+        assert_eq!(*rolls, ["minigame roll", "minigame score", "global roll"]);
+        let unknown: Vec<_> = world
+            .resource_mut::<Events<EventUnknownCommand>>()
+            .drain()
+            .map(|event| event.name)
+            .collect();
+        assert_eq!(unknown, ["score"]);
+        assert!(world.resource::<DialogueCommands>().get("score").is_none());
+    }
+
+    #[test]
+    fn conditional_dialogue_is_skipped_when_its_condition_fails() {
+        use bevy::ecs::event::ManualEventReader;
+
+        struct NpcAlive(bool);
+
+        let file = |name: &str| {
+            let start = node("Start", &[], vec![run_line(name, 0), stop()]);
+            let table = format!("{}{},Line from {}.,{},Start,1\n", TABLE_HEADER, name, name, name);
+            (compiled_program(program(vec![start]), &[]), table)
+        };
+        let (npc, after) = (file("npc"), file("after"));
+        let root = asset_dir(
+            "conditional",
+            &[
+                ("npc.yarnc", &npc.0),
+                ("npc.csv", npc.1.as_bytes()),
+                ("after.yarnc", &after.0),
+                ("after.csv", after.1.as_bytes()),
+            ],
+        );
+
+        for (alive, first_line) in [(false, "Line from after."), (true, "Line from npc.")] {
+            let mut app = dialogue_app(&root, DialoguePlugin::default());
+            app.insert_resource(NpcAlive(alive));
+            AddConditionalDialogueCommand {
+                path: PathBuf::from("npc.yarnc"),
+                start_node: None,
+                condition: Box::new(|world| world.resource::<NpcAlive>().0),
+            }
+            .write(&mut app.world);
+            AddDialogueToQueueCommand {
+                path: PathBuf::from("after.yarnc"),
+                start_node: None,
+                language: None,
+                extra_commands: None,
+            }
+            .write(&mut app.world);
+
+            let mut reader = ManualEventReader::<EventDialogueSkipped>::default();
+            let mut skipped = Vec::new();
+            let started = std::time::Instant::now();
+            while app.world.resource::<CurrentDialogueLine>().0.is_none() {
+                assert!(started.elapsed() < Duration::from_secs(5), "the queue stalled");
+                app.update();
+                let events = app.world.resource::<Events<EventDialogueSkipped>>();
+                skipped.extend(reader.iter(events).map(|event| event.path.clone()));
+                std::thread::yield_now();
+            }
+            let line = app.world.resource::<CurrentDialogueLine>().0.clone().unwrap();
+            assert_eq!(line.text, first_line);
+            match alive {
+                false => {
+                    assert_eq!(skipped, [PathBuf::from("npc.yarnc")]);
+                    assert!(app.world.resource::<DialogueQueue>().is_empty());
+                }
+                true => {
+                    assert!(skipped.is_empty());
+                    assert_eq!(app.world.resource::<DialogueQueue>().pending_count(), 1);
+                }
+            }
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cached_dialogue_replays_without_reloading() {
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let table = format!("{}line:1,Again?,intro,Start,1\n", TABLE_HEADER);
+        let root = asset_dir(
+            "program_cache",
+            &[
+                ("intro.yarnc", &compiled_program(program(vec![start]), &[])),
+                ("intro.csv", table.as_bytes()),
+            ],
+        );
+        let plugin = DialoguePluginBuilder::default().cache_programs(true).build().unwrap();
+        let mut app = dialogue_app(&root, plugin);
+        let path = PathBuf::from("intro.yarnc");
+        let queue = |app: &mut App| {
+            AddDialogueToQueueCommand {
+                path: path.clone(),
+                start_node: None,
+                language: None,
+                extra_commands: None,
+            }
+            .write(&mut app.world)
+        };
+        queue(&mut app);
+        update_until(&mut app, |world| {
+            matches!(world.resource::<DialogueRunner>().idle_reason, IdleReason::Completed { .. })
+        });
+        assert!(app.world.resource::<DialogueProgramCache>().contains(&path));
+
+        // With the files gone, only the cache can start it, and on the frame it's queued.
+        fs::remove_dir_all(&root).unwrap();
+        queue(&mut app);
+        app.update();
+        assert!(app.world.resource::<DialogueQueue>().is_empty());
+        assert_ne!(app.world.resource::<DialogueRunner>().state, DialogueRunnerState::Idle);
+        app.update();
+        let line = app.world.resource::<CurrentDialogueLine>().0.clone();
+        assert_eq!(line.map(|line| line.text), Some("Again?".to_string()));
+
+        let mut cache = app.world.resource_mut::<DialogueProgramCache>();
+        cache.clear_cache();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn dialogue_is_busy_in_every_active_state() {
+        fn busy(world: &mut World) -> bool {
+            let mut busy = IntoSystem::into_system(run_if_dialogue_busy);
+            let mut free = IntoSystem::into_system(run_if_dialogue_free);
+            busy.initialize(world);
+            free.initialize(world);
+            let busy = matches!(busy.run((), world), ShouldRun::Yes);
+            assert_eq!(matches!(free.run((), world), ShouldRun::Yes), !busy);
+            busy
+        }
+
+        let mut start = node(
+            "Start",
+            &[],
+            vec![
+                run_line("line:1", 0),
+                run_command("yield"),
+                add_option("opt:a", "A"),
+                instruction(OpCode::ShowOptions, Vec::new()),
+                instruction(OpCode::Jump, Vec::new()),
+                instruction(OpCode::Pop, Vec::new()),
+                stop(),
+            ],
+        );
+        start.labels = [("A".to_string(), 5)].into_iter().collect();
+        let table = vec![line_info("line:1", "Hi."), line_info("opt:a", "Bye.")];
+        let mut driver = DialogueDriver::new(program(vec![start]), table);
+        let world = driver.world_mut();
+        add_dialogue_assets(world);
+        assert!(!busy(world));
+
+        // Queued but not yet started.
+        let entry = loaded_entry(world, "queued.yarnc", Program::default(), Vec::new());
+        world.resource_mut::<DialogueQueue>().push_back(entry);
+        assert!(busy(world));
+        world.resource_mut::<DialogueQueue>().clear();
+
+        // Held, or waiting out a `<<wait>>`, with nothing running.
+        world.resource_mut::<DialogueHolds>().acquire("cutscene");
+        assert!(busy(world));
+        world.resource_mut::<DialogueHolds>().release("cutscene");
+        world.resource_mut::<DialogueRunner>().wait = Some(Timer::from_seconds(1.0, false));
+        assert!(busy(world));
+        world.resource_mut::<DialogueRunner>().wait = None;
+        assert!(!busy(world));
+
+        // Running a line, paused by `<<yield>>`, then waiting on a selection.
+        assert!(driver.start("Start"));
+        assert_eq!(line_id(driver.step()), "line:1");
+        assert!(busy(driver.world_mut()));
+        assert!(matches!(driver.step(), StepResult::Command { .. }));
+        assert_eq!(driver.runner().phase(), DialoguePhase::Paused);
+        assert!(busy(driver.world_mut()));
+        assert!(matches!(driver.step(), StepResult::Options(_)));
+        assert!(busy(driver.world_mut()));
+        driver.select(0);
+        assert_eq!(driver.step(), StepResult::Complete);
+        assert!(!busy(driver.world_mut()));
+    }
+
+    #[test]
+    fn startup_program_loads_through_the_asset_server() {
+        let start = node("Start", &[], vec![run_line("line:1", 0), stop()]);
+        let table = format!("{}line:1,Welcome.,intro,Start,1\n", TABLE_HEADER);
+        let root = asset_dir(
+            "startup",
+            &[
+                ("dialogue/intro.yarnc", &compiled_program(program(vec![start]), &[])),
+                ("dialogue/intro.csv", table.as_bytes()),
+            ],
+        );
+        // Relative to the asset folder, which isn't the working directory.
+        let path = PathBuf::from("dialogue/intro.yarnc");
+        assert!(!path.exists());
+        let mut app = dialogue_app(&root, DialoguePlugin::try_new(path.clone()).unwrap());
+        let queue = app.world.resource::<DialogueQueue>();
+        assert_eq!(queue.iter().map(|entry| &entry.path).collect::<Vec<_>>(), [&path]);
+
+        update_until(&mut app, |world| world.resource::<CurrentDialogueLine>().0.is_some());
+        let line = app.world.resource::<CurrentDialogueLine>().0.clone().unwrap();
+        assert_eq!(line.text, "Welcome.");
+        let runner = app.world.resource::<DialogueRunner>();
+        assert_eq!(runner.program_path.as_ref(), Some(&path));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
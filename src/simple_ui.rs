@@ -0,0 +1,176 @@
+//! A bare-bones dialogue box drawn with Bevy UI, for prototypes and as a reference for driving
+//! the runner from a UI. It only reads [`CurrentDialogueLine`] and [`CurrentDialogueOptions`]
+//! and answers with [`AdvanceDialogueCommand`] and [`SelectDialogueOptionCommand`].
+//!
+//! Up/Down move the option cursor, Space or Enter advance the line or pick the option. Pair it
+//! with [`LineAdvanceMode::Manual`](crate::LineAdvanceMode::Manual) so lines wait for the player.
+
+use bevy::prelude::*;
+
+use crate::{
+    AdvanceDialogueCommand, CurrentDialogueLine, CurrentDialogueOptions,
+    SelectDialogueOptionCommand,
+};
+
+pub struct SimpleDialogueUiPlugin {
+    /// Asset path of the font the box is drawn with.
+    pub font: String,
+    pub font_size: f32,
+}
+
+impl Default for SimpleDialogueUiPlugin {
+    fn default() -> Self {
+        Self {
+            font: "fonts/FiraSans-Bold.ttf".to_string(),
+            font_size: 24.0,
+        }
+    }
+}
+
+impl Plugin for SimpleDialogueUiPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SimpleDialogueUiSettings {
+            font: self.font.clone(),
+            font_size: self.font_size,
+        })
+        .init_resource::<SimpleDialogueUiState>()
+        .add_startup_system(spawn_dialogue_box)
+        .add_system(handle_dialogue_input)
+        .add_system(update_dialogue_box.after(handle_dialogue_input));
+    }
+}
+
+struct SimpleDialogueUiSettings {
+    font: String,
+    font_size: f32,
+}
+
+#[derive(Default)]
+struct SimpleDialogueUiState {
+    font: Handle<Font>,
+    /// Position of the option cursor in the presented options.
+    selected: usize,
+}
+
+#[derive(Component)]
+struct DialogueBox;
+
+#[derive(Component)]
+struct DialogueBoxText;
+
+/// Both parts of the dialogue box, which are shown and hidden together.
+type DialogueBoxFilter = Or<(With<DialogueBox>, With<DialogueBoxText>)>;
+
+fn spawn_dialogue_box(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    settings: Res<SimpleDialogueUiSettings>,
+    mut state: ResMut<SimpleDialogueUiState>,
+) {
+    state.font = asset_server.load(settings.font.as_str());
+    commands.spawn_bundle(UiCameraBundle::default());
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Px(settings.font_size * 7.0)),
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    left: Val::Px(0.0),
+                    bottom: Val::Px(0.0),
+                    ..Default::default()
+                },
+                padding: Rect::all(Val::Px(16.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                ..Default::default()
+            },
+            color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(DialogueBox)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::default(),
+                    visibility: Visibility { is_visible: false },
+                    ..Default::default()
+                })
+                .insert(DialogueBoxText);
+        });
+}
+
+fn handle_dialogue_input(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    current_line: Res<CurrentDialogueLine>,
+    current_options: Res<CurrentDialogueOptions>,
+    mut state: ResMut<SimpleDialogueUiState>,
+) {
+    let confirm = keys.just_pressed(KeyCode::Space) || keys.just_pressed(KeyCode::Return);
+    if let Some(options) = current_options.0.as_ref().filter(|options| !options.is_empty()) {
+        let last = options.len() - 1;
+        if keys.just_pressed(KeyCode::Up) {
+            state.selected = state.selected.checked_sub(1).unwrap_or(last);
+        }
+        if keys.just_pressed(KeyCode::Down) {
+            state.selected = if state.selected >= last { 0 } else { state.selected + 1 };
+        }
+        state.selected = state.selected.min(last);
+        let option = &options[state.selected];
+        if confirm && option.is_available {
+            commands.add(SelectDialogueOptionCommand { index: option.index });
+            state.selected = 0;
+        }
+    } else if current_line.is_some() && confirm {
+        commands.add(AdvanceDialogueCommand);
+    }
+}
+
+fn update_dialogue_box(
+    current_line: Res<CurrentDialogueLine>,
+    current_options: Res<CurrentDialogueOptions>,
+    settings: Res<SimpleDialogueUiSettings>,
+    state: Res<SimpleDialogueUiState>,
+    mut text: Query<&mut Text, With<DialogueBoxText>>,
+    mut visibility: Query<&mut Visibility, DialogueBoxFilter>,
+) {
+    if !current_line.is_changed() && !current_options.is_changed() && !state.is_changed() {
+        return;
+    }
+    let style = |color: Color| TextStyle {
+        font: state.font.clone(),
+        font_size: settings.font_size,
+        color,
+    };
+    let section = |value: String, color: Color| TextSection {
+        value,
+        style: style(color),
+    };
+
+    let mut sections = Vec::new();
+    if let Some(line) = &current_line.0 {
+        if let Some(speaker) = &line.speaker {
+            sections.push(section(format!("{}\n", speaker), Color::YELLOW));
+        }
+        sections.push(section(line.text.clone(), Color::WHITE));
+    } else if let Some(options) = &current_options.0 {
+        for (position, option) in options.iter().enumerate() {
+            let cursor = if position == state.selected { "> " } else { "  " };
+            let color = match (option.is_available, position == state.selected) {
+                (false, _) => Color::GRAY,
+                (true, true) => Color::YELLOW,
+                (true, false) => Color::WHITE,
+            };
+            sections.push(section(format!("{}{}\n", cursor, option.text), color));
+        }
+    }
+
+    // Visibility doesn't propagate to children, so the box and its text are toggled together.
+    let is_visible = !sections.is_empty();
+    for mut visibility in visibility.iter_mut() {
+        visibility.is_visible = is_visible;
+    }
+    for mut text in text.iter_mut() {
+        text.sections = sections.clone();
+    }
+}